@@ -0,0 +1,240 @@
+#[cfg(feature = "bindgen")]
+extern crate bindgen;
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// The SDK `src/bindings.rs` was generated from.
+const SDK_VERSION: &str = "1.8.0";
+
+const LIB_NAME: &str = "agora-rtc-sdk";
+
+fn main() {
+    println!("cargo:rustc-env=AGORA_SDK_VERSION={}", SDK_VERSION);
+    println!("cargo:rerun-if-env-changed=AGORA_SDK_DIR");
+    println!("cargo:rerun-if-env-changed=AGORA_SDK_SKIP_VERSION_CHECK");
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let sdk_dir = match env::var_os("AGORA_SDK_DIR") {
+        Some(d) => manifest_dir.join(d),
+        None => manifest_dir.join("agora_sdk"),
+    };
+    let link = env::var_os("CARGO_FEATURE_LINK").is_some();
+    let generate = cfg!(feature = "bindgen") && env::var_os("CARGO_FEATURE_PREGENERATED").is_none();
+    if link || generate {
+        check_version(&sdk_dir);
+    }
+    if link {
+        link_sdk(&sdk_dir);
+        if config_has_proxy(&sdk_dir) {
+            // for dependents' build scripts, as DEP_AGORA_RTC_SDK_RTC_PROXY
+            println!("cargo:rtc_proxy=1");
+        }
+    }
+    #[cfg(feature = "bindgen")]
+    if generate {
+        generate_bindings(&sdk_dir);
+    }
+}
+
+/// The header has no version macro, so this goes by `CONFIG_SDK_VERSION`
+/// in the `.config` shipped next to it.
+fn check_version(sdk_dir: &Path) {
+    let config_path = sdk_dir.join(".config");
+    println!("cargo:rerun-if-changed={}", config_path.display());
+    if env::var_os("AGORA_SDK_SKIP_VERSION_CHECK").is_some() {
+        return;
+    }
+    let config = match std::fs::read_to_string(&config_path) {
+        Ok(c) => c,
+        Err(_) => {
+            println!(
+                "cargo:warning={} not found, can't check the SDK is {}",
+                config_path.display(),
+                SDK_VERSION
+            );
+            return;
+        }
+    };
+    let version = config.lines().find_map(|l| {
+        l.trim()
+            .strip_prefix("CONFIG_SDK_VERSION=")
+            .map(|v| v.trim_matches('"').to_owned())
+    });
+    match version {
+        Some(v) if v == SDK_VERSION => {}
+        Some(v) => panic!(
+            "\nthe SDK in {} is {}, these bindings are for {}.\n\
+             Use the matching SDK, or set AGORA_SDK_SKIP_VERSION_CHECK if you know the API didn't change.\n",
+            sdk_dir.display(),
+            v,
+            SDK_VERSION
+        ),
+        None => println!(
+            "cargo:warning=no CONFIG_SDK_VERSION in {}, can't check the SDK is {}",
+            config_path.display(),
+            SDK_VERSION
+        ),
+    }
+}
+
+/// Directory names the SDK packages use for `target_arch`, most specific first.
+/// `$AGORA_SDK_DIR/lib/<TARGET>` is always tried before these.
+fn arch_dirs(arch: &str, target: &str) -> Vec<&'static str> {
+    match arch {
+        "x86_64" => vec!["x86_64", "x86_64-linux-gnu"],
+        "x86" => vec!["x86", "i686", "i386"],
+        "aarch64" => vec!["aarch64", "aarch64-linux-gnu", "arm64"],
+        // Hisilicon himix200 is an armv7 too, pick it by the triple
+        "arm" if target.contains("himix200") => vec!["arm-himix200", "armv7", "arm"],
+        "arm" => vec!["armv7", "arm-linux-gnueabihf", "arm", "arm-himix200"],
+        "mips" => vec!["mips", "mipsel"],
+        "riscv64" => vec!["riscv64"],
+        _ => vec![],
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum LinkKind {
+    Static,
+    Shared,
+}
+
+impl LinkKind {
+    fn file_name(self) -> String {
+        match self {
+            LinkKind::Static => format!("lib{}.a", LIB_NAME),
+            LinkKind::Shared => format!("lib{}.so", LIB_NAME),
+        }
+    }
+}
+
+/// `CONFIG_STATIC=y`/`CONFIG_SHARED=y` in the `.config` shipped with the SDK.
+fn config_link_kind(sdk_dir: &Path) -> Option<LinkKind> {
+    let config = std::fs::read_to_string(sdk_dir.join(".config")).ok()?;
+    config.lines().find_map(|l| match l.trim() {
+        "CONFIG_STATIC=y" => Some(LinkKind::Static),
+        "CONFIG_SHARED=y" => Some(LinkKind::Shared),
+        _ => None,
+    })
+}
+
+/// `CONFIG_RTC_PROXY=y`: the SDK was built with `agora_rtc_set_cloud_proxy`,
+/// which the header only declares then.
+fn config_has_proxy(sdk_dir: &Path) -> bool {
+    std::fs::read_to_string(sdk_dir.join(".config"))
+        .map(|c| c.lines().any(|l| l.trim() == "CONFIG_RTC_PROXY=y"))
+        .unwrap_or(false)
+}
+
+/// Library kinds to look for, in order. Only the one asked for by the
+/// `static`/`shared` feature, otherwise both with `.config`'s choice first.
+fn link_kinds(sdk_dir: &Path) -> Vec<LinkKind> {
+    let is_static = env::var_os("CARGO_FEATURE_STATIC").is_some();
+    let is_shared = env::var_os("CARGO_FEATURE_SHARED").is_some();
+    match (is_static, is_shared) {
+        (true, true) => panic!("features `static` and `shared` are mutually exclusive"),
+        (true, false) => vec![LinkKind::Static],
+        (false, true) => vec![LinkKind::Shared],
+        (false, false) => match config_link_kind(sdk_dir) {
+            Some(LinkKind::Static) => vec![LinkKind::Static, LinkKind::Shared],
+            _ => vec![LinkKind::Shared, LinkKind::Static],
+        },
+    }
+}
+
+fn link_sdk(sdk_dir: &Path) {
+    let target = env::var("TARGET").unwrap();
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let kinds = link_kinds(sdk_dir);
+
+    let lib_root = sdk_dir.join("lib");
+    let mut dirs = vec![lib_root.join(&target)];
+    dirs.extend(arch_dirs(&arch, &target).iter().map(|d| lib_root.join(d)));
+    // some SDK packages are for one arch only and put the library in lib/
+    dirs.push(lib_root.clone());
+
+    let found = dirs.iter().find_map(|d| {
+        kinds
+            .iter()
+            .find(|k| d.join(k.file_name()).is_file())
+            .map(|k| (d, *k))
+    });
+    let (lib_dir, kind) = match found {
+        Some(f) => f,
+        None => {
+            let names: Vec<_> = kinds.iter().map(|k| k.file_name()).collect();
+            let looked: Vec<_> = dirs.iter().map(|d| d.display().to_string()).collect();
+            panic!(
+                "\n{} not found for target `{}`, looked in:\n  {}\n\
+                 Put the SDK library for this target in one of them, point AGORA_SDK_DIR \
+                 at an unpacked SDK, or build without the `link` feature.\n",
+                names.join(" or "),
+                target,
+                looked.join("\n  ")
+            );
+        }
+    };
+
+    // Tell cargo to look for the library in the specified directory
+    println!("cargo:rustc-link-search=native={}", lib_dir.display());
+    println!(
+        "cargo:rerun-if-changed={}",
+        lib_dir.join(kind.file_name()).display()
+    );
+    // Tell cargo to tell rustc to link the library
+    match kind {
+        LinkKind::Static => {
+            println!("cargo:rustc-link-lib=static={}", LIB_NAME);
+            // what the SDK itself links against
+            if env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("linux") {
+                for lib in ["pthread", "m", "dl", "rt"] {
+                    println!("cargo:rustc-link-lib={}", lib);
+                }
+            }
+        }
+        LinkKind::Shared => println!("cargo:rustc-link-lib=dylib={}", LIB_NAME),
+    }
+    // for dependents' build scripts, as DEP_AGORA_RTC_SDK_LIB_DIR
+    println!("cargo:lib_dir={}", lib_dir.display());
+}
+
+// from https://rust-lang.github.io/rust-bindgen/tutorial-3.html
+#[cfg(feature = "bindgen")]
+fn generate_bindings(sdk_dir: &Path) {
+    let header = sdk_dir.join("include/agora_rtc_api.h");
+    if !header.is_file() {
+        panic!(
+            "\n{} not found, is AGORA_SDK_DIR right?\n",
+            header.display()
+        );
+    }
+    // Tell cargo to invalidate the built crate whenever the wrapper changes
+    println!("cargo:rerun-if-changed={}", header.display());
+
+    // The bindgen::Builder is the main entry point
+    // to bindgen, and lets you build up options for
+    // the resulting bindings.
+    let mut builder = bindgen::Builder::default();
+    if config_has_proxy(sdk_dir) {
+        builder = builder.clang_arg("-DCONFIG_RTC_PROXY");
+    }
+    let bindings = builder
+        // The input header we would like to generate
+        // bindings for.
+        .header(header.to_string_lossy())
+        // https://rust-lang.github.io/rust-bindgen/nocopy.html
+        .no_copy("log_config_t")
+        // Tell cargo to invalidate the built crate whenever any of the
+        // included header files changed.
+        .parse_callbacks(Box::new(bindgen::CargoCallbacks))
+        // Finish the builder and generate the bindings.
+        .generate()
+        // Unwrap the Result and panic on failure.
+        .expect("Unable to generate bindings");
+
+    // Write the bindings to the $OUT_DIR/bindings.rs file.
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
+    bindings
+        .write_to_file(out_path.join("bindings.rs"))
+        .expect("Couldn't write bindings!");
+}
@@ -2010,6 +2010,12 @@ extern "C" {
         start_bps: u32,
     ) -> ::std::os::raw::c_int;
 }
+extern "C" {
+    #[doc = " Set cloud proxy"]
+    #[doc = " @param [in] type : reference enum cloud_proxy_type_e"]
+    #[doc = " @return 0: Success, <0: failure"]
+    pub fn agora_rtc_set_cloud_proxy(type_: cloud_proxy_type_e) -> ::std::os::raw::c_int;
+}
 extern "C" {
     #[doc = " Set config params"]
     #[doc = ""]
@@ -0,0 +1,53 @@
+//! Raw bindings to the Agora RTSA C SDK (`agora_rtc_api.h`) and the linking of
+//! `libagora-rtc-sdk`. The safe API is in `agora-rtsa-rs`.
+//!
+//! With the `bindgen` feature the bindings are generated from the header at build time,
+//! otherwise (or with `pregenerated`) `src/bindings.rs` is used, which is the same
+//! output committed for machines without libclang.
+#![allow(non_upper_case_globals)]
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+
+#[cfg(all(feature = "bindgen", not(feature = "pregenerated")))]
+include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+#[cfg(not(all(feature = "bindgen", not(feature = "pregenerated"))))]
+include!("bindings.rs");
+
+/// The SDK version these bindings are for. `build.rs` refuses to link another one.
+pub const SDK_VERSION: &str = env!("AGORA_SDK_VERSION");
+
+impl rtc_channel_options_t {
+    /// I don't need audio by default!
+    pub fn new() -> Self {
+        let codec_opt = audio_codec_option_t {
+            audio_codec_type: audio_codec_type_e_AUDIO_CODEC_DISABLED,
+            // Pcm sample rate. Ignored if audio coded is diabled
+            pcm_sample_rate: 0,
+            // Pcm channel number. Ignored if audio coded is diabled
+            pcm_channel_num: 0,
+        };
+        let audio_opt = rtc_audio_process_options_t {
+            enable_audio_process: false,
+            enable_aec: false,
+            enable_ns: false,
+            ref_data_from_sdk: false,
+            enable_dump_data: false,
+        };
+        rtc_channel_options_t {
+            auto_subscribe_audio: false,
+            auto_subscribe_video: false,
+            subscribe_local_user: false,
+            enable_audio_jitter_buffer: false,
+            enable_audio_mixer: false,
+            audio_codec_opt: codec_opt,
+            audio_process_opt: audio_opt,
+            enable_aut_encryption: false,
+        }
+    }
+}
+
+impl Default for rtc_channel_options_t {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,51 @@
+/*
+ * log_printf for log_config_t.
+ *
+ * Rust can't define variadic functions, so this formats the line and hands
+ * it to the sink registered from Rust (see src/agoraRTC/log_bridge.rs).
+ */
+#include <stdarg.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+typedef void (*agora_rs_log_sink_t)(const char *line, size_t len);
+
+static agora_rs_log_sink_t g_sink = NULL;
+
+void agora_rs_set_log_sink(agora_rs_log_sink_t sink) { g_sink = sink; }
+
+int agora_rs_log_printf(const char *fmt, ...)
+{
+  char buf[512];
+  char *line = buf;
+  va_list ap, ap2;
+  int n;
+
+  va_start(ap, fmt);
+  va_copy(ap2, ap);
+  n = vsnprintf(buf, sizeof(buf), fmt, ap);
+  va_end(ap);
+  if (n < 0) {
+    va_end(ap2);
+    return n;
+  }
+  if ((size_t)n >= sizeof(buf)) {
+    line = malloc((size_t)n + 1);
+    if (line == NULL) {
+      /* still better than nothing */
+      line = buf;
+      n = sizeof(buf) - 1;
+    } else {
+      vsnprintf(line, (size_t)n + 1, fmt, ap2);
+    }
+  }
+  va_end(ap2);
+
+  if (g_sink != NULL) {
+    g_sink(line, (size_t)n);
+  }
+  if (line != buf) {
+    free(line);
+  }
+  return n;
+}
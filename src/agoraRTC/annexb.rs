@@ -0,0 +1,457 @@
+//! H.264/H.265 Annex-B elementary streams cut into access units, one per
+//! `send_video_data`, with `frame_type` set from the NAL units.
+//!
+//! Only the NAL unit headers (and the first bit of the slice header, the ids of the
+//! parameter sets) are looked at. Key frames get the last VPS/SPS/PPS of every id
+//! seen in front of them if they don't carry their own, so a viewer joining late
+//! can decode from any of them.
+use super::{AgoraError, VideoDataType, VideoFrameInfo, VideoFrameType};
+use std::collections::BTreeMap;
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    H264,
+    H265,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Nal {
+    /// a slice, `key` for IDR/IRAP, `first` if it starts a picture
+    Vcl {
+        key: bool,
+        first: bool,
+    },
+    /// VPS, SPS or PPS, by NAL unit type and id, `None` if too short to have one
+    ParamSet(u8, Option<u32>),
+    /// AUD, SEI and the like, only allowed before the first slice of a picture
+    Prefix,
+    Other,
+}
+
+impl Codec {
+    fn classify(self, nal: &[u8]) -> Nal {
+        match self {
+            Codec::H264 => {
+                let first = nal.get(1).is_some_and(|b| b & 0x80 != 0);
+                match nal[0] & 0x1f {
+                    // first_mb_in_slice is ue(v), 0 is a single 1 bit
+                    t @ 1..=5 => Nal::Vcl { key: t == 5, first },
+                    t @ (7 | 8) => Nal::ParamSet(t, self.param_set_id(t, nal)),
+                    6 | 9 | 14..=18 => Nal::Prefix,
+                    _ => Nal::Other,
+                }
+            }
+            Codec::H265 => {
+                let first = nal.get(2).is_some_and(|b| b & 0x80 != 0);
+                match (nal[0] >> 1) & 0x3f {
+                    // first_slice_segment_in_pic_flag
+                    t @ 0..=31 => Nal::Vcl {
+                        key: (16..=23).contains(&t),
+                        first,
+                    },
+                    t @ 32..=34 => Nal::ParamSet(t, self.param_set_id(t, nal)),
+                    35 | 39 | 41..=44 | 48..=55 => Nal::Prefix,
+                    _ => Nal::Other,
+                }
+            }
+        }
+    }
+
+    /// `video/seq/pic_parameter_set_id` of a parameter set of type `t`.
+    fn param_set_id(self, t: u8, nal: &[u8]) -> Option<u32> {
+        let rbsp = unescape(nal);
+        match (self, t) {
+            (Codec::H264, 7) => {
+                let mut b = Bits::new(rbsp.get(1..)?);
+                // profile_idc, constraint flags, level_idc
+                b.skip(24);
+                b.ue()
+            }
+            (Codec::H264, _) => Bits::new(rbsp.get(1..)?).ue(),
+            (Codec::H265, 32) => Bits::new(rbsp.get(2..)?).bits(4),
+            (Codec::H265, 33) => {
+                let mut b = Bits::new(rbsp.get(2..)?);
+                b.skip(4);
+                let max_sub_layers_minus1 = b.bits(3)?;
+                b.skip(1);
+                b.skip_profile_tier_level(max_sub_layers_minus1)?;
+                b.ue()
+            }
+            (Codec::H265, _) => Bits::new(rbsp.get(2..)?).ue(),
+        }
+    }
+}
+
+/// Where the next `00 00 01` starts, at or after `from`.
+fn find_start_code(data: &[u8], from: usize) -> Option<usize> {
+    data.get(from..)?
+        .windows(3)
+        .position(|w| w == [0, 0, 1])
+        .map(|p| from + p)
+}
+
+/// The NAL unit between two start codes, without the zeros of a 4 byte start code
+/// (or `trailing_zero_8bits`) after it.
+fn trim(nal: &[u8]) -> &[u8] {
+    let end = nal.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1);
+    &nal[..end]
+}
+
+/// The NAL units of a whole Annex-B buffer, without their start codes.
+pub fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut start = find_start_code(data, 0);
+    while let Some(s) = start {
+        let next = find_start_code(data, s + 3);
+        let nal = trim(&data[s + 3..next.unwrap_or(data.len())]);
+        if !nal.is_empty() {
+            nals.push(nal);
+        }
+        start = next;
+    }
+    nals
+}
+
+/// RBSP of a NAL unit, without the emulation prevention bytes.
+pub(crate) fn unescape(nal: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &b in nal {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        out.push(b);
+    }
+    out
+}
+
+/// MSB first bit reader over an RBSP.
+pub(crate) struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Bits<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Bits { data, pos: 0 }
+    }
+
+    pub(crate) fn bit(&mut self) -> Option<u32> {
+        let byte = self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Some(bit as u32)
+    }
+
+    pub(crate) fn bits(&mut self, n: u32) -> Option<u32> {
+        (0..n).try_fold(0, |v, _| Some((v << 1) | self.bit()?))
+    }
+
+    pub(crate) fn skip(&mut self, n: usize) {
+        self.pos += n;
+    }
+
+    /// Exp-Golomb
+    pub(crate) fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u32 << zeros) - 1 + self.bits(zeros)?)
+    }
+
+    /// H.265 `profile_tier_level(1, max_sub_layers_minus1)`.
+    pub(crate) fn skip_profile_tier_level(&mut self, max_sub_layers_minus1: u32) -> Option<()> {
+        self.skip(96);
+        let mut present = Vec::new();
+        for _ in 0..max_sub_layers_minus1 {
+            present.push((self.bit()?, self.bit()?));
+        }
+        if max_sub_layers_minus1 > 0 {
+            self.skip(2 * (8 - max_sub_layers_minus1 as usize));
+        }
+        for (profile, level) in present {
+            self.skip(88 * profile as usize + 8 * level as usize);
+        }
+        Some(())
+    }
+
+    pub(crate) fn se(&mut self) -> Option<i32> {
+        let v = self.ue()?;
+        Some(if v % 2 == 1 {
+            v.div_ceil(2) as i32
+        } else {
+            -((v / 2) as i32)
+        })
+    }
+}
+
+/// One picture, ready for `send_video_data`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessUnit {
+    pub data_type: VideoDataType,
+    /// `KEY` if it has an IDR (H.264) or IRAP (H.265) slice, `DELTA` otherwise
+    pub frame_type: VideoFrameType,
+    /// Annex-B, with 4 byte start codes
+    pub data: Vec<u8>,
+}
+
+impl AccessUnit {
+    /// For `send_video_data`, change the rest with the builder if needed.
+    pub fn info(&self) -> VideoFrameInfo {
+        VideoFrameInfo::new(self.data_type).frame_type(self.frame_type)
+    }
+}
+
+/// Cuts an Annex-B stream, fed in chunks of any size, into [`AccessUnit`]s.
+///
+/// ```
+/// use agora_rtsa_rs::agoraRTC::*;
+/// let mut parser = AnnexBParser::new(VideoDataType::H264).unwrap();
+/// let sps = [0, 0, 0, 1, 0x67, 0x42, 0xc0, 0x1f];
+/// let pps = [0, 0, 0, 1, 0x68, 0xce, 0x3c, 0x80];
+/// let idr = [0, 0, 0, 1, 0x65, 0x88, 0x84];
+/// for chunk in [&sps[..], &pps, &idr] {
+///     assert!(parser.push(chunk).is_empty());
+/// }
+/// let au = &parser.flush()[0];
+/// assert_eq!(au.frame_type, VideoFrameType::KEY);
+/// ```
+pub struct AnnexBParser {
+    codec: Codec,
+    data_type: VideoDataType,
+    /// bytes not cut into NAL units yet, from a start code on
+    buf: Vec<u8>,
+    /// NAL units of the access unit being put together
+    nals: Vec<(Nal, Vec<u8>)>,
+    /// last of each parameter set, by NAL unit type and id
+    params: BTreeMap<(u8, Option<u32>), Vec<u8>>,
+}
+
+impl AnnexBParser {
+    /// Only `VideoDataType::H264` and `H265` are Annex-B.
+    pub fn new(data_type: VideoDataType) -> Result<Self, AgoraError> {
+        let codec = match data_type {
+            VideoDataType::H264 => Codec::H264,
+            VideoDataType::H265 => Codec::H265,
+            other => return Err(AgoraError::NotAnnexB(other)),
+        };
+        Ok(AnnexBParser {
+            codec,
+            data_type,
+            buf: Vec::new(),
+            nals: Vec::new(),
+            params: BTreeMap::new(),
+        })
+    }
+
+    /// The access units completed by `data`. The last one is only known to be
+    /// complete once the next one starts, or on `flush`.
+    pub fn push(&mut self, data: &[u8]) -> Vec<AccessUnit> {
+        self.buf.extend_from_slice(data);
+        let mut aus = Vec::new();
+        let mut start = match find_start_code(&self.buf, 0) {
+            Some(s) => s,
+            None => {
+                // keep what may be the beginning of a start code
+                let keep = self.buf.len().min(2);
+                self.buf.drain(..self.buf.len() - keep);
+                return aus;
+            }
+        };
+        while let Some(next) = find_start_code(&self.buf, start + 3) {
+            let nal = trim(&self.buf[start + 3..next]).to_vec();
+            aus.extend(self.add(nal));
+            start = next;
+        }
+        self.buf.drain(..start);
+        aus
+    }
+
+    /// The access units left, at the end of the stream.
+    pub fn flush(&mut self) -> Vec<AccessUnit> {
+        let mut aus = Vec::new();
+        if let Some(start) = find_start_code(&self.buf, 0) {
+            let nal = trim(&self.buf[start + 3..]).to_vec();
+            aus.extend(self.add(nal));
+        }
+        self.buf.clear();
+        aus.extend(self.finish());
+        aus
+    }
+
+    /// Add a NAL unit, returning the access unit it ends if any.
+    fn add(&mut self, nal: Vec<u8>) -> Option<AccessUnit> {
+        if nal.is_empty() {
+            return None;
+        }
+        let kind = self.codec.classify(&nal);
+        let has_vcl = self.nals.iter().any(|(k, _)| matches!(k, Nal::Vcl { .. }));
+        let starts_au = match kind {
+            Nal::Vcl { first, .. } => first,
+            Nal::ParamSet(..) | Nal::Prefix => true,
+            Nal::Other => false,
+        };
+        let done = if has_vcl && starts_au {
+            self.finish()
+        } else {
+            None
+        };
+        if let Nal::ParamSet(t, id) = kind {
+            self.params.insert((t, id), nal.clone());
+        }
+        self.nals.push((kind, nal));
+        done
+    }
+
+    /// The access unit put together so far, `None` if it has no slice.
+    fn finish(&mut self) -> Option<AccessUnit> {
+        let nals = std::mem::take(&mut self.nals);
+        if !nals.iter().any(|(k, _)| matches!(k, Nal::Vcl { .. })) {
+            return None;
+        }
+        let key = nals
+            .iter()
+            .any(|(k, _)| matches!(k, Nal::Vcl { key: true, .. }));
+        let mut out: Vec<&[u8]> = nals.iter().map(|(_, n)| n.as_slice()).collect();
+        if key {
+            let missing: Vec<&[u8]> = self
+                .params
+                .iter()
+                .filter(|((t, id), _)| !nals.iter().any(|(k, _)| *k == Nal::ParamSet(*t, *id)))
+                .map(|(_, n)| n.as_slice())
+                .collect();
+            // after an AUD, which has to come first
+            let at = match nals.first() {
+                Some((Nal::Prefix, n)) if self.is_aud(n) => 1,
+                _ => 0,
+            };
+            out.splice(at..at, missing);
+        }
+        let mut data = Vec::with_capacity(out.iter().map(|n| n.len() + 4).sum());
+        for n in out {
+            data.extend_from_slice(&START_CODE);
+            data.extend_from_slice(n);
+        }
+        Some(AccessUnit {
+            data_type: self.data_type,
+            frame_type: if key {
+                VideoFrameType::KEY
+            } else {
+                VideoFrameType::DELTA
+            },
+            data,
+        })
+    }
+
+    fn is_aud(&self, nal: &[u8]) -> bool {
+        match self.codec {
+            Codec::H264 => nal[0] & 0x1f == 9,
+            Codec::H265 => (nal[0] >> 1) & 0x3f == 35,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn annexb(nals: &[&[u8]]) -> Vec<u8> {
+        nals.iter()
+            .flat_map(|n| START_CODE.iter().chain(n.iter()).copied())
+            .collect()
+    }
+
+    fn parse(data_type: VideoDataType, stream: &[u8], chunk: usize) -> Vec<AccessUnit> {
+        let mut p = AnnexBParser::new(data_type).unwrap();
+        let mut aus: Vec<_> = stream.chunks(chunk).flat_map(|c| p.push(c)).collect();
+        aus.append(&mut p.flush());
+        aus
+    }
+
+    const SPS: &[u8] = &[0x67, 0x42, 0xc0, 0x1f, 0xda];
+    const PPS: &[u8] = &[0x68, 0xce, 0x3c, 0x80];
+    const SEI: &[u8] = &[0x06, 0x05, 0x01, 0x80];
+    // first_mb_in_slice 0, then a second slice of the same picture
+    const IDR_0: &[u8] = &[0x65, 0x88, 0x84, 0x21];
+    const IDR_1: &[u8] = &[0x65, 0x44, 0x21, 0x00, 0x00, 0x03, 0x01];
+    const P: &[u8] = &[0x41, 0x9a, 0x02, 0x10];
+
+    #[test]
+    fn h264_access_units() {
+        let mut stream = annexb(&[SPS, PPS, IDR_0, IDR_1, P, SEI, P]);
+        // 3 byte start code and trailing zeros
+        stream.extend_from_slice(&[0, 0, 1, 0x65, 0x88, 0x99, 0, 0]);
+        let whole = parse(VideoDataType::H264, &stream, stream.len());
+        assert_eq!(whole, parse(VideoDataType::H264, &stream, 1));
+        assert_eq!(whole, parse(VideoDataType::H264, &stream, 5));
+
+        let types: Vec<_> = whole.iter().map(|au| au.frame_type).collect();
+        use VideoFrameType::*;
+        assert_eq!(types, [KEY, DELTA, DELTA, KEY]);
+        assert_eq!(whole[0].data, annexb(&[SPS, PPS, IDR_0, IDR_1]));
+        assert_eq!(whole[1].data, annexb(&[P]));
+        assert_eq!(whole[2].data, annexb(&[SEI, P]));
+        // the cached parameter sets go in front
+        assert_eq!(whole[3].data, annexb(&[SPS, PPS, &[0x65, 0x88, 0x99]]));
+        assert_eq!(whole[3].info().frame_type, KEY);
+        assert_eq!(nal_units(&stream).len(), 8);
+    }
+
+    #[test]
+    fn keeps_every_pps_id() {
+        // pic_parameter_set_id 1, then 1 again with other content
+        let pps1: &[u8] = &[0x68, 0x4f, 0x1e, 0x40];
+        let pps1_new: &[u8] = &[0x68, 0x4e, 0x3c, 0x80];
+        let idr: &[u8] = &[0x65, 0x88, 0x99];
+        let stream = annexb(&[SPS, PPS, pps1, IDR_0, P, idr, pps1_new, idr, idr]);
+        let aus = parse(VideoDataType::H264, &stream, 4);
+        assert_eq!(aus.len(), 5);
+        assert_eq!(aus[0].data, annexb(&[SPS, PPS, pps1, IDR_0]));
+        // both PPS in front, slices may refer to either
+        assert_eq!(aus[2].data, annexb(&[SPS, PPS, pps1, idr]));
+        assert_eq!(aus[3].data, annexb(&[SPS, PPS, pps1_new, idr]));
+        assert_eq!(aus[4].data, annexb(&[SPS, PPS, pps1_new, idr]));
+
+        let mut p = AnnexBParser::new(VideoDataType::H264).unwrap();
+        p.push(&annexb(&[SPS, PPS, pps1, IDR_0]));
+        assert_eq!(
+            p.params.keys().copied().collect::<Vec<_>>(),
+            [(7, Some(0)), (8, Some(0)), (8, Some(1))]
+        );
+    }
+
+    #[test]
+    fn h265_access_units() {
+        let aud: &[u8] = &[0x46, 0x01, 0x50];
+        let vps: &[u8] = &[0x40, 0x01, 0x0c, 0x01];
+        let sps: &[u8] = &[0x42, 0x01, 0x01, 0x01];
+        let pps: &[u8] = &[0x44, 0x01, 0xc1, 0x72];
+        let idr: &[u8] = &[0x26, 0x01, 0xaf, 0x09];
+        let trail: &[u8] = &[0x02, 0x01, 0xd0, 0x0a];
+        let cra: &[u8] = &[0x2a, 0x01, 0xac, 0x0b];
+        let stream = annexb(&[aud, vps, sps, pps, idr, aud, trail, aud, cra]);
+        let aus = parse(VideoDataType::H265, &stream, 3);
+        let types: Vec<_> = aus.iter().map(|au| au.frame_type).collect();
+        use VideoFrameType::*;
+        assert_eq!(types, [KEY, DELTA, KEY]);
+        assert_eq!(aus[0].data, annexb(&[aud, vps, sps, pps, idr]));
+        assert_eq!(aus[1].data, annexb(&[aud, trail]));
+        assert_eq!(aus[2].data, annexb(&[aud, vps, sps, pps, cra]));
+
+        assert!(matches!(
+            AnnexBParser::new(VideoDataType::GENERIC),
+            Err(AgoraError::NotAnnexB(VideoDataType::GENERIC))
+        ));
+        let mut p = AnnexBParser::new(VideoDataType::H265).unwrap();
+        assert!(p.push(&[0xff, 0xfe]).is_empty());
+        assert!(p.flush().is_empty());
+    }
+}
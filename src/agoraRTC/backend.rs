@@ -0,0 +1,386 @@
+//! Every `agora_rtc_*` call goes through an [`RtcBackend`], so the safe API can
+//! run on top of something other than the real SDK, like `FakeBackend` (the `fake` feature).
+//!
+//! The methods take the same arguments as the C functions and return their codes,
+//! `err_2_result` and friends turn those into `AgoraError` as usual.
+use super::super::ffi::*;
+use std::ffi::CStr;
+use std::sync::Arc;
+
+pub trait RtcBackend: Send + Sync {
+    fn get_version(&self) -> String;
+    fn err_2_str(&self, err: i32) -> String;
+    /// `handler` and `option` are kept alive by the caller until `fini`.
+    fn init(
+        &self,
+        app_id: &CStr,
+        handler: &agora_rtc_event_handler_t,
+        option: &mut rtc_service_option_t,
+    ) -> i32;
+    fn fini(&self) -> i32;
+    fn set_log_level(&self, level: rtc_log_level_e) -> i32;
+    fn config_log(&self, size_per_file: i32, max_file_count: i32) -> i32;
+    fn create_connection(&self, conn_id: &mut connection_id_t) -> i32;
+    fn destroy_connection(&self, conn_id: connection_id_t) -> i32;
+    fn get_connection_info(&self, conn_id: connection_id_t, info: &mut connection_info_t) -> i32;
+    fn join_channel(
+        &self,
+        conn_id: connection_id_t,
+        channel_name: &CStr,
+        uid: u32,
+        token: &CStr,
+        options: &mut rtc_channel_options_t,
+    ) -> i32;
+    fn leave_channel(&self, conn_id: connection_id_t) -> i32;
+    fn renew_token(&self, conn_id: connection_id_t, token: &CStr) -> i32;
+    fn notify_network_event(&self, event: network_event_type_e) -> i32;
+    fn mute_local_audio(&self, conn_id: connection_id_t, mute: bool) -> i32;
+    fn mute_local_video(&self, conn_id: connection_id_t, mute: bool) -> i32;
+    fn mute_remote_audio(&self, conn_id: connection_id_t, remote_uid: u32, mute: bool) -> i32;
+    fn mute_remote_video(&self, conn_id: connection_id_t, remote_uid: u32, mute: bool) -> i32;
+    fn request_video_key_frame(
+        &self,
+        conn_id: connection_id_t,
+        remote_uid: u32,
+        stream_type: video_stream_type_e,
+    ) -> i32;
+    fn send_audio_data(
+        &self,
+        conn_id: connection_id_t,
+        data: &[u8],
+        info: &audio_frame_info_t,
+    ) -> i32;
+    fn send_video_data(
+        &self,
+        conn_id: connection_id_t,
+        data: &[u8],
+        info: &video_frame_info_t,
+    ) -> i32;
+    fn set_bwe_param(
+        &self,
+        conn_id: connection_id_t,
+        min_bps: u32,
+        max_bps: u32,
+        start_bps: u32,
+    ) -> i32;
+    fn set_params(&self, params: &CStr) -> i32;
+    /// Only in SDK builds with `CONFIG_RTC_PROXY`, fails on the others.
+    fn set_cloud_proxy(&self, proxy_type: cloud_proxy_type_e) -> i32;
+    /// `handler` is kept alive by the caller until `logout_rtm`.
+    fn login_rtm(&self, rtm_uid: &CStr, token: Option<&CStr>, handler: &agora_rtm_handler_t)
+        -> i32;
+    fn logout_rtm(&self) -> i32;
+    fn send_rtm_data(&self, rtm_uid: &CStr, msg_id: u32, msg: &[u8]) -> i32;
+}
+
+/// The real SDK.
+#[cfg(feature = "ffi")]
+pub struct FfiBackend;
+
+#[cfg(feature = "ffi")]
+impl RtcBackend for FfiBackend {
+    fn get_version(&self) -> String {
+        unsafe {
+            let p = agora_rtc_get_version();
+            if p.is_null() {
+                return String::new();
+            }
+            CStr::from_ptr(p).to_string_lossy().into_owned()
+        }
+    }
+    fn err_2_str(&self, err: i32) -> String {
+        unsafe {
+            let p = agora_rtc_err_2_str(err);
+            if p.is_null() {
+                return String::new();
+            }
+            CStr::from_ptr(p).to_string_lossy().into_owned()
+        }
+    }
+    fn init(
+        &self,
+        app_id: &CStr,
+        handler: &agora_rtc_event_handler_t,
+        option: &mut rtc_service_option_t,
+    ) -> i32 {
+        unsafe { agora_rtc_init(app_id.as_ptr(), handler, option) }
+    }
+    fn fini(&self) -> i32 {
+        unsafe { agora_rtc_fini() }
+    }
+    fn set_log_level(&self, level: rtc_log_level_e) -> i32 {
+        unsafe { agora_rtc_set_log_level(level) }
+    }
+    fn config_log(&self, size_per_file: i32, max_file_count: i32) -> i32 {
+        unsafe { agora_rtc_config_log(size_per_file, max_file_count) }
+    }
+    fn create_connection(&self, conn_id: &mut connection_id_t) -> i32 {
+        unsafe { agora_rtc_create_connection(conn_id) }
+    }
+    fn destroy_connection(&self, conn_id: connection_id_t) -> i32 {
+        unsafe { agora_rtc_destroy_connection(conn_id) }
+    }
+    fn get_connection_info(&self, conn_id: connection_id_t, info: &mut connection_info_t) -> i32 {
+        unsafe { agora_rtc_get_connection_info(conn_id, info) }
+    }
+    fn join_channel(
+        &self,
+        conn_id: connection_id_t,
+        channel_name: &CStr,
+        uid: u32,
+        token: &CStr,
+        options: &mut rtc_channel_options_t,
+    ) -> i32 {
+        unsafe {
+            agora_rtc_join_channel(conn_id, channel_name.as_ptr(), uid, token.as_ptr(), options)
+        }
+    }
+    fn leave_channel(&self, conn_id: connection_id_t) -> i32 {
+        unsafe { agora_rtc_leave_channel(conn_id) }
+    }
+    fn renew_token(&self, conn_id: connection_id_t, token: &CStr) -> i32 {
+        unsafe { agora_rtc_renew_token(conn_id, token.as_ptr()) }
+    }
+    fn notify_network_event(&self, event: network_event_type_e) -> i32 {
+        unsafe { agora_rtc_notify_network_event(event) }
+    }
+    fn mute_local_audio(&self, conn_id: connection_id_t, mute: bool) -> i32 {
+        unsafe { agora_rtc_mute_local_audio(conn_id, mute) }
+    }
+    fn mute_local_video(&self, conn_id: connection_id_t, mute: bool) -> i32 {
+        unsafe { agora_rtc_mute_local_video(conn_id, mute) }
+    }
+    fn mute_remote_audio(&self, conn_id: connection_id_t, remote_uid: u32, mute: bool) -> i32 {
+        unsafe { agora_rtc_mute_remote_audio(conn_id, remote_uid, mute) }
+    }
+    fn mute_remote_video(&self, conn_id: connection_id_t, remote_uid: u32, mute: bool) -> i32 {
+        unsafe { agora_rtc_mute_remote_video(conn_id, remote_uid, mute) }
+    }
+    fn request_video_key_frame(
+        &self,
+        conn_id: connection_id_t,
+        remote_uid: u32,
+        stream_type: video_stream_type_e,
+    ) -> i32 {
+        unsafe { agora_rtc_request_video_key_frame(conn_id, remote_uid, stream_type) }
+    }
+    fn send_audio_data(
+        &self,
+        conn_id: connection_id_t,
+        data: &[u8],
+        info: &audio_frame_info_t,
+    ) -> i32 {
+        // the header wants a *mut, hand it a copy instead of casting
+        let mut info = *info;
+        unsafe {
+            agora_rtc_send_audio_data(
+                conn_id,
+                data.as_ptr() as *const std::ffi::c_void,
+                data.len() as size_t,
+                &mut info,
+            )
+        }
+    }
+    fn send_video_data(
+        &self,
+        conn_id: connection_id_t,
+        data: &[u8],
+        info: &video_frame_info_t,
+    ) -> i32 {
+        let mut info = *info;
+        unsafe {
+            agora_rtc_send_video_data(
+                conn_id,
+                data.as_ptr() as *const std::ffi::c_void,
+                data.len() as size_t,
+                &mut info,
+            )
+        }
+    }
+    fn set_bwe_param(
+        &self,
+        conn_id: connection_id_t,
+        min_bps: u32,
+        max_bps: u32,
+        start_bps: u32,
+    ) -> i32 {
+        unsafe { agora_rtc_set_bwe_param(conn_id, min_bps, max_bps, start_bps) }
+    }
+    fn set_params(&self, params: &CStr) -> i32 {
+        unsafe { agora_rtc_set_params(params.as_ptr()) }
+    }
+    #[cfg(agora_rtc_proxy)]
+    fn set_cloud_proxy(&self, proxy_type: cloud_proxy_type_e) -> i32 {
+        unsafe { agora_rtc_set_cloud_proxy(proxy_type) }
+    }
+    #[cfg(not(agora_rtc_proxy))]
+    fn set_cloud_proxy(&self, _proxy_type: cloud_proxy_type_e) -> i32 {
+        // not in the linked SDK, SdkError::FAILED
+        -1
+    }
+    fn login_rtm(
+        &self,
+        rtm_uid: &CStr,
+        token: Option<&CStr>,
+        handler: &agora_rtm_handler_t,
+    ) -> i32 {
+        let token = token.map_or(std::ptr::null(), |t| t.as_ptr());
+        unsafe { agora_rtc_login_rtm(rtm_uid.as_ptr(), token, handler) }
+    }
+    fn logout_rtm(&self) -> i32 {
+        unsafe { agora_rtc_logout_rtm() }
+    }
+    fn send_rtm_data(&self, rtm_uid: &CStr, msg_id: u32, msg: &[u8]) -> i32 {
+        unsafe {
+            agora_rtc_send_rtm_data(
+                rtm_uid.as_ptr(),
+                msg_id,
+                msg.as_ptr() as *const std::ffi::c_void,
+                msg.len() as size_t,
+            )
+        }
+    }
+}
+
+/// Without the `ffi` feature: no SDK, every call fails with `SdkError::FAILED`.
+#[cfg(not(feature = "ffi"))]
+pub struct NoSdk;
+
+#[cfg(not(feature = "ffi"))]
+const NO_SDK: i32 = -1;
+
+#[cfg(not(feature = "ffi"))]
+impl RtcBackend for NoSdk {
+    fn get_version(&self) -> String {
+        String::new()
+    }
+    fn err_2_str(&self, _err: i32) -> String {
+        "built without the ffi feature".to_owned()
+    }
+    fn init(
+        &self,
+        _app_id: &CStr,
+        _handler: &agora_rtc_event_handler_t,
+        _option: &mut rtc_service_option_t,
+    ) -> i32 {
+        NO_SDK
+    }
+    fn fini(&self) -> i32 {
+        NO_SDK
+    }
+    fn set_log_level(&self, _level: rtc_log_level_e) -> i32 {
+        NO_SDK
+    }
+    fn config_log(&self, _size_per_file: i32, _max_file_count: i32) -> i32 {
+        NO_SDK
+    }
+    fn create_connection(&self, _conn_id: &mut connection_id_t) -> i32 {
+        NO_SDK
+    }
+    fn destroy_connection(&self, _conn_id: connection_id_t) -> i32 {
+        NO_SDK
+    }
+    fn get_connection_info(&self, _conn_id: connection_id_t, _info: &mut connection_info_t) -> i32 {
+        NO_SDK
+    }
+    fn join_channel(
+        &self,
+        _conn_id: connection_id_t,
+        _channel_name: &CStr,
+        _uid: u32,
+        _token: &CStr,
+        _options: &mut rtc_channel_options_t,
+    ) -> i32 {
+        NO_SDK
+    }
+    fn leave_channel(&self, _conn_id: connection_id_t) -> i32 {
+        NO_SDK
+    }
+    fn renew_token(&self, _conn_id: connection_id_t, _token: &CStr) -> i32 {
+        NO_SDK
+    }
+    fn notify_network_event(&self, _event: network_event_type_e) -> i32 {
+        NO_SDK
+    }
+    fn mute_local_audio(&self, _conn_id: connection_id_t, _mute: bool) -> i32 {
+        NO_SDK
+    }
+    fn mute_local_video(&self, _conn_id: connection_id_t, _mute: bool) -> i32 {
+        NO_SDK
+    }
+    fn mute_remote_audio(&self, _conn_id: connection_id_t, _remote_uid: u32, _mute: bool) -> i32 {
+        NO_SDK
+    }
+    fn mute_remote_video(&self, _conn_id: connection_id_t, _remote_uid: u32, _mute: bool) -> i32 {
+        NO_SDK
+    }
+    fn request_video_key_frame(
+        &self,
+        _conn_id: connection_id_t,
+        _remote_uid: u32,
+        _stream_type: video_stream_type_e,
+    ) -> i32 {
+        NO_SDK
+    }
+    fn send_audio_data(
+        &self,
+        _conn_id: connection_id_t,
+        _data: &[u8],
+        _info: &audio_frame_info_t,
+    ) -> i32 {
+        NO_SDK
+    }
+    fn send_video_data(
+        &self,
+        _conn_id: connection_id_t,
+        _data: &[u8],
+        _info: &video_frame_info_t,
+    ) -> i32 {
+        NO_SDK
+    }
+    fn set_bwe_param(
+        &self,
+        _conn_id: connection_id_t,
+        _min_bps: u32,
+        _max_bps: u32,
+        _start_bps: u32,
+    ) -> i32 {
+        NO_SDK
+    }
+    fn set_params(&self, _params: &CStr) -> i32 {
+        NO_SDK
+    }
+    fn set_cloud_proxy(&self, _proxy_type: cloud_proxy_type_e) -> i32 {
+        NO_SDK
+    }
+    fn login_rtm(
+        &self,
+        _rtm_uid: &CStr,
+        _token: Option<&CStr>,
+        _handler: &agora_rtm_handler_t,
+    ) -> i32 {
+        NO_SDK
+    }
+    fn logout_rtm(&self) -> i32 {
+        NO_SDK
+    }
+    fn send_rtm_data(&self, _rtm_uid: &CStr, _msg_id: u32, _msg: &[u8]) -> i32 {
+        NO_SDK
+    }
+}
+
+/// What `RtcService::init` and the free functions like `get_version` use:
+/// the real SDK with the `ffi` feature, [`NoSdk`] without.
+pub fn default_backend() -> Arc<dyn RtcBackend> {
+    #[cfg(feature = "ffi")]
+    {
+        lazy_static::lazy_static! {
+            static ref FFI: Arc<dyn RtcBackend> = Arc::new(FfiBackend);
+        }
+        FFI.clone()
+    }
+    #[cfg(not(feature = "ffi"))]
+    {
+        Arc::new(NoSdk)
+    }
+}
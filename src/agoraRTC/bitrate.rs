@@ -0,0 +1,267 @@
+//! Bandwidth estimation settings, and handing `on_target_bitrate_changed` to the encoder.
+use super::handler::Observer;
+use super::AgoraError;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// `agora_rtc_set_bwe_param` wants `0 < min_bps <= start_bps <= max_bps`.
+pub(crate) fn check_bwe(min_bps: u32, max_bps: u32, start_bps: u32) -> Result<(), AgoraError> {
+    if min_bps == 0 || min_bps > start_bps || start_bps > max_bps {
+        return Err(AgoraError::InvalidBweRange {
+            min_bps,
+            max_bps,
+            start_bps,
+        });
+    }
+    Ok(())
+}
+
+/// Gets the target bitrate of a connection, to retune the encoder.
+///
+/// Called from the SDK thread, hence `Send + Sync`. Don't block in it.
+pub trait EncoderRateController: Send + Sync {
+    /// From `on_target_bitrate_changed`.
+    fn on_target_bitrate(&self, conn_id: u32, target_bps: u32);
+}
+
+impl<F> EncoderRateController for F
+where
+    F: Fn(u32, u32) + Send + Sync,
+{
+    fn on_target_bitrate(&self, conn_id: u32, target_bps: u32) {
+        self(conn_id, target_bps)
+    }
+}
+
+/// Observer of a connection feeding its [`EncoderRateController`].
+pub(crate) struct RateForwarder(pub(crate) Arc<dyn EncoderRateController>);
+
+impl Observer for RateForwarder {
+    fn on_target_bitrate_changed(&self, conn_id: u32, target_bps: u32) {
+        self.0.on_target_bitrate(conn_id, target_bps)
+    }
+}
+
+/// How [`RateSmoother`] follows the targets.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SmoothingConfig {
+    /// Weight of a new target above the smoothed rate, in (0, 1]. Low to rise slowly.
+    pub rise_alpha: f64,
+    /// Weight of a new target below the smoothed rate, in (0, 1]. High to back off fast.
+    pub fall_alpha: f64,
+    /// Only apply once the smoothed rate is this far from the applied one,
+    /// as a fraction of the applied one
+    pub hysteresis: f64,
+    /// What's applied is clamped to this range
+    pub min_bps: u32,
+    pub max_bps: u32,
+}
+
+impl Default for SmoothingConfig {
+    fn default() -> Self {
+        SmoothingConfig {
+            rise_alpha: 0.2,
+            fall_alpha: 0.7,
+            hysteresis: 0.1,
+            min_bps: 0,
+            max_bps: u32::MAX,
+        }
+    }
+}
+
+/// Exponential smoothing with hysteresis, so the encoder isn't retuned on every
+/// wiggle of the estimate. Feed it targets with `update`.
+#[derive(Debug, Clone)]
+pub struct RateSmoother {
+    config: SmoothingConfig,
+    smoothed: Option<f64>,
+    applied: Option<u32>,
+}
+
+impl RateSmoother {
+    pub fn new(config: SmoothingConfig) -> Self {
+        RateSmoother {
+            config,
+            smoothed: None,
+            applied: None,
+        }
+    }
+
+    /// The bitrate to apply now, `None` to keep the current one.
+    /// The first target is applied as is.
+    pub fn update(&mut self, target_bps: u32) -> Option<u32> {
+        let c = &self.config;
+        let target = target_bps as f64;
+        let smoothed = match self.smoothed {
+            None => target,
+            Some(s) => {
+                let alpha = if target > s {
+                    c.rise_alpha
+                } else {
+                    c.fall_alpha
+                };
+                s + alpha.clamp(0.0, 1.0) * (target - s)
+            }
+        };
+        self.smoothed = Some(smoothed);
+        let rate = (smoothed.round() as u32).clamp(c.min_bps, c.max_bps.max(c.min_bps));
+        match self.applied {
+            Some(applied)
+                if (rate as f64 - applied as f64).abs() < c.hysteresis * applied as f64 =>
+            {
+                None
+            }
+            Some(applied) if applied == rate => None,
+            _ => {
+                self.applied = Some(rate);
+                Some(rate)
+            }
+        }
+    }
+
+    /// Last bitrate returned by `update`.
+    pub fn applied(&self) -> Option<u32> {
+        self.applied
+    }
+}
+
+/// Reference [`EncoderRateController`]: smooths the targets of each connection with
+/// a [`RateSmoother`] of its own and calls `apply(conn_id, bps)` when its encoder
+/// should change. Can be set on several connections.
+pub struct SmoothingRateController<F> {
+    config: SmoothingConfig,
+    smoothers: Mutex<HashMap<u32, RateSmoother>>,
+    apply: F,
+}
+
+impl<F: Fn(u32, u32) + Send + Sync> SmoothingRateController<F> {
+    pub fn new(config: SmoothingConfig, apply: F) -> Self {
+        SmoothingRateController {
+            config,
+            smoothers: Mutex::new(HashMap::new()),
+            apply,
+        }
+    }
+
+    fn smoothers(&self) -> MutexGuard<'_, HashMap<u32, RateSmoother>> {
+        self.smoothers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Last bitrate applied to `conn_id`.
+    pub fn applied(&self, conn_id: u32) -> Option<u32> {
+        self.smoothers().get(&conn_id).and_then(|s| s.applied())
+    }
+}
+
+impl<F: Fn(u32, u32) + Send + Sync> EncoderRateController for SmoothingRateController<F> {
+    fn on_target_bitrate(&self, conn_id: u32, target_bps: u32) {
+        // not calling `apply` with the lock held
+        let rate = self
+            .smoothers()
+            .entry(conn_id)
+            .or_insert_with(|| RateSmoother::new(self.config))
+            .update(target_bps);
+        if let Some(bps) = rate {
+            (self.apply)(conn_id, bps)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fake::{fake_service, Call};
+    use super::*;
+
+    fn run(config: SmoothingConfig, trace: &[u32]) -> Vec<Option<u32>> {
+        let mut s = RateSmoother::new(config);
+        trace.iter().map(|t| s.update(*t)).collect()
+    }
+
+    #[test]
+    fn ignores_jitter_backs_off_fast_rises_slowly() {
+        let c = SmoothingConfig::default();
+        // +-5% around 1 Mbps, nothing after the first
+        let jitter = [1_000_000, 1_050_000, 960_000, 1_040_000, 950_000, 1_000_000];
+        let out = run(c, &jitter);
+        assert_eq!(out[0], Some(1_000_000));
+        assert!(out[1..].iter().all(|o| o.is_none()), "{:?}", out);
+
+        // halved: applied on the very next update
+        let mut s = RateSmoother::new(c);
+        s.update(1_000_000);
+        assert_eq!(s.update(500_000), Some(650_000));
+        assert_eq!(s.update(500_000), Some(545_000));
+
+        // doubled again: takes several updates to get close
+        let steps: Vec<u32> = (0..20).filter_map(|_| s.update(1_000_000)).collect();
+        assert!(steps.len() >= 3, "{:?}", steps);
+        assert!(steps.windows(2).all(|w| w[0] < w[1]));
+        assert!(*steps.last().unwrap() > 900_000);
+    }
+
+    #[test]
+    fn clamps_and_forwards() {
+        let c = SmoothingConfig {
+            min_bps: 200_000,
+            max_bps: 2_000_000,
+            ..Default::default()
+        };
+        assert_eq!(run(c, &[5_000_000]), [Some(2_000_000)]);
+        assert_eq!(run(c, &[1_000]), [Some(200_000)]);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let s2 = seen.clone();
+        let ctl = Arc::new(SmoothingRateController::new(c, move |conn, bps| {
+            s2.lock().unwrap().push((conn, bps))
+        }));
+        let fwd = RateForwarder(ctl.clone());
+        fwd.on_target_bitrate_changed(3, 800_000);
+        fwd.on_target_bitrate_changed(3, 810_000);
+        // another connection starts from its own first target
+        fwd.on_target_bitrate_changed(4, 300_000);
+        fwd.on_target_bitrate_changed(3, 820_000);
+        assert_eq!(*seen.lock().unwrap(), [(3, 800_000), (4, 300_000)]);
+        assert_eq!(
+            (ctl.applied(3), ctl.applied(4), ctl.applied(5)),
+            (Some(800_000), Some(300_000), None)
+        );
+
+        assert!(check_bwe(100, 1000, 500).is_ok());
+        assert!(check_bwe(0, 1000, 500).is_err());
+        assert!(check_bwe(600, 1000, 500).is_err());
+        assert!(check_bwe(100, 400, 500).is_err());
+    }
+
+    #[test]
+    fn bwe_and_controller_on_fake() {
+        let (fake, service) = fake_service();
+        let conn = service.create_connection().unwrap();
+        let id = conn.conn_id();
+        fake.take_calls();
+
+        assert!(matches!(
+            conn.set_bwe_params(500_000, 400_000, 450_000),
+            Err(AgoraError::InvalidBweRange { .. })
+        ));
+        conn.set_bwe_params(200_000, 2_000_000, 800_000).unwrap();
+        assert_eq!(
+            fake.take_calls(),
+            [Call::SetBweParam {
+                conn_id: id,
+                min_bps: 200_000,
+                max_bps: 2_000_000,
+                start_bps: 800_000
+            }]
+        );
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let s2 = seen.clone();
+        conn.set_rate_controller(Arc::new(move |conn, bps| {
+            s2.lock().unwrap().push((conn, bps))
+        }));
+        fake.target_bitrate_changed(id, 600_000);
+        conn.clear_rate_controller();
+        fake.target_bitrate_changed(id, 300_000);
+        assert_eq!(*seen.lock().unwrap(), [(id, 600_000)]);
+    }
+}
@@ -0,0 +1,841 @@
+use super::super::ffi::*;
+use super::super::utils::*;
+use super::backend::RtcBackend;
+use super::bitrate::{check_bwe, EncoderRateController, RateForwarder};
+#[cfg(feature = "async")]
+use super::events::{self, EventSink, EventStream};
+use super::handler::{self, DefaultHandler, EventHandler, Observer};
+use super::keyframe::{KeyFrameRequest, KeyFrameStats, KeyFrames};
+use super::recorder::{Recorder, RecorderOptions};
+use super::roster::{RemoteUser, Roster, RosterChange};
+use super::service::RtcService;
+use super::state::{ConnectionState, ReconnectPolicy, Reconnector, StateChange, StateMachine};
+use super::subscription::{SubscribePolicy, Subscriptions};
+use super::token::{TokenProvider, TokenRefresher, TokenRetry};
+use super::{validate_pcm_frame, AgoraError, AudioDataType, VideoFrameInfo, VideoStreamQuality};
+use log::{info, warn};
+use std::ffi::CString;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
+
+fn check_len(buf: &[u8]) -> Result<(), AgoraError> {
+    let _: size_t = buf
+        .len()
+        .try_into()
+        .map_err(|_| AgoraError::BufferTooLarge {
+            len: buf.len(),
+            max: size_t::MAX as usize,
+        })?;
+    Ok(())
+}
+
+// https://stackoverflow.com/questions/53183070/what-is-the-defacto-bytes-type-in-rust
+pub(crate) fn send_video(
+    backend: &dyn RtcBackend,
+    conn_id: u32,
+    buf: &[u8],
+    info: &VideoFrameInfo,
+) -> Result<(), AgoraError> {
+    check_len(buf)?;
+    let info = video_frame_info_t::from(*info);
+    err_2_result(backend.send_video_data(conn_id, buf, &info))
+}
+
+pub(crate) fn send_audio(
+    backend: &dyn RtcBackend,
+    conn_id: u32,
+    buf: &[u8],
+    data_type: AudioDataType,
+) -> Result<(), AgoraError> {
+    check_len(buf)?;
+    let info = audio_frame_info_t {
+        data_type: data_type.into(),
+    };
+    err_2_result(backend.send_audio_data(conn_id, buf, &info))
+}
+
+struct ConnState {
+    uid: u32,
+    channel_name: CString,
+    token: CString,
+    /// `join_channel` succeeded and `leave_channel` wasn't called
+    in_channel: bool,
+    channel_option: Option<rtc_channel_options_t>,
+    /// replayed on the connection the `ReconnectPolicy` creates
+    bwe: Option<(u32, u32, u32)>,
+    mute_audio: bool,
+    mute_video: bool,
+}
+
+/// What is registered for the conn_id. Keeps per connection state up to date
+/// and forwards everything to the internal observers, then to the user's handler.
+pub(crate) struct ConnShared {
+    // changes when the connection is rebuilt
+    conn_id: AtomicU32,
+    state: Mutex<ConnState>,
+    handler: RwLock<Option<Arc<dyn EventHandler>>>,
+    observers: RwLock<Vec<Arc<dyn Observer>>>,
+}
+
+impl ConnShared {
+    fn state(&self) -> MutexGuard<'_, ConnState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn user(&self) -> Arc<dyn EventHandler> {
+        let h = self.handler.read().unwrap_or_else(|e| e.into_inner());
+        match h.as_ref() {
+            Some(h) => h.clone(),
+            None => Arc::new(DefaultHandler),
+        }
+    }
+
+    /// Also called for every event of this connection, before the user's handler.
+    pub(crate) fn add_observer(&self, observer: Arc<dyn Observer>) {
+        let mut o = self.observers.write().unwrap_or_else(|e| e.into_inner());
+        o.push(observer);
+    }
+
+    pub(crate) fn remove_observer(&self, observer: &Arc<dyn Observer>) {
+        let mut o = self.observers.write().unwrap_or_else(|e| e.into_inner());
+        o.retain(|x| !Arc::ptr_eq(x, observer));
+    }
+
+    pub(crate) fn conn_id(&self) -> u32 {
+        self.conn_id.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn uid(&self) -> u32 {
+        self.state().uid
+    }
+
+    pub(crate) fn channel_name(&self) -> String {
+        self.state().channel_name.to_string_lossy().into_owned()
+    }
+
+    pub(crate) fn renew_token(
+        &self,
+        backend: &dyn RtcBackend,
+        token: &str,
+    ) -> Result<(), AgoraError> {
+        let token = token.to_c_string()?;
+        err_2_result(backend.renew_token(self.conn_id(), &token))?;
+        self.state().token = token;
+        Ok(())
+    }
+
+    /// Leave, destroy, create and join again with the same channel, uid, token
+    /// and options, then set the bandwidth range and local mutes again.
+    /// The events of the new connection come here as well. Doesn't join if
+    /// `leave_channel` was called in the meantime.
+    pub(crate) fn rebuild(self: &Arc<Self>, backend: &dyn RtcBackend) -> Result<(), AgoraError> {
+        let old = self.conn_id();
+        if let Err(e) = err_2_result(backend.leave_channel(old)) {
+            warn!("leave_channel failed on rebuild, conn_id: {}, {}", old, e);
+        }
+        if let Err(e) = err_2_result(backend.destroy_connection(old)) {
+            warn!(
+                "destroy_connection failed on rebuild, conn_id: {}, {}",
+                old, e
+            );
+        }
+        handler::unregister_handler(old);
+        let mut conn_id: connection_id_t = 0;
+        err_2_result(backend.create_connection(&mut conn_id))?;
+        handler::register_handler(conn_id, self.clone());
+        self.conn_id.store(conn_id, Ordering::Release);
+        self.observe(|o| o.on_rebuilt(old, conn_id));
+        let mut state = self.state();
+        let state = &mut *state;
+        if !state.in_channel {
+            info!(
+                "left while rebuilding, not joining again, conn_id: {}",
+                conn_id
+            );
+            return Ok(());
+        }
+        let opt = state
+            .channel_option
+            .as_mut()
+            .ok_or(AgoraError::NoConnection)?;
+        err_2_result(backend.join_channel(
+            conn_id,
+            &state.channel_name,
+            state.uid,
+            &state.token,
+            opt,
+        ))?;
+        // a new connection is unmuted
+        let mut replay = Vec::new();
+        if state.mute_audio {
+            replay.push(("mute_local_audio", backend.mute_local_audio(conn_id, true)));
+        }
+        if state.mute_video {
+            replay.push(("mute_local_video", backend.mute_local_video(conn_id, true)));
+        }
+        if let Some((min_bps, max_bps, start_bps)) = state.bwe {
+            let code = backend.set_bwe_param(conn_id, min_bps, max_bps, start_bps);
+            replay.push(("set_bwe_param", code));
+        }
+        for (name, code) in replay {
+            if let Err(e) = err_2_result(code) {
+                warn!("{} failed on rebuild, conn_id: {}, {}", name, conn_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn observe<F: Fn(&dyn Observer)>(&self, f: F) {
+        // cloned so observers may add others
+        let observers = self
+            .observers
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        for o in observers.iter() {
+            f(o.as_ref());
+        }
+    }
+}
+
+impl EventHandler for ConnShared {
+    fn on_join_channel_success(&self, conn_id: u32, uid: u32, elapsed_ms: i32) {
+        // the SDK assigns one if we joined with uid 0
+        self.state().uid = uid;
+        self.observe(|o| o.on_join_channel_success(conn_id, uid, elapsed_ms));
+        self.user()
+            .on_join_channel_success(conn_id, uid, elapsed_ms)
+    }
+    fn on_connection_lost(&self, conn_id: u32) {
+        self.observe(|o| o.on_connection_lost(conn_id));
+        self.user().on_connection_lost(conn_id)
+    }
+    fn on_rejoin_channel_success(&self, conn_id: u32, uid: u32, elapsed_ms: i32) {
+        self.observe(|o| o.on_rejoin_channel_success(conn_id, uid, elapsed_ms));
+        self.user()
+            .on_rejoin_channel_success(conn_id, uid, elapsed_ms)
+    }
+    fn on_error(&self, conn_id: u32, code: i32, msg: &str) {
+        self.observe(|o| o.on_error(conn_id, code, msg));
+        self.user().on_error(conn_id, code, msg)
+    }
+    fn on_user_joined(&self, conn_id: u32, uid: u32, elapsed_ms: i32) {
+        self.observe(|o| o.on_user_joined(conn_id, uid, elapsed_ms));
+        self.user().on_user_joined(conn_id, uid, elapsed_ms)
+    }
+    fn on_user_offline(&self, conn_id: u32, uid: u32, reason: i32) {
+        self.observe(|o| o.on_user_offline(conn_id, uid, reason));
+        self.user().on_user_offline(conn_id, uid, reason)
+    }
+    fn on_user_mute_audio(&self, conn_id: u32, uid: u32, muted: bool) {
+        self.observe(|o| o.on_user_mute_audio(conn_id, uid, muted));
+        self.user().on_user_mute_audio(conn_id, uid, muted)
+    }
+    fn on_user_mute_video(&self, conn_id: u32, uid: u32, muted: bool) {
+        self.observe(|o| o.on_user_mute_video(conn_id, uid, muted));
+        self.user().on_user_mute_video(conn_id, uid, muted)
+    }
+    fn on_audio_data(
+        &self,
+        conn_id: u32,
+        uid: u32,
+        sent_ts: u16,
+        data: &[u8],
+        info: &audio_frame_info_t,
+    ) {
+        self.observe(|o| o.on_audio_data(conn_id, uid, sent_ts, data, info));
+        self.user().on_audio_data(conn_id, uid, sent_ts, data, info)
+    }
+    fn on_mixed_audio_data(&self, conn_id: u32, data: &[u8], info: &audio_frame_info_t) {
+        self.observe(|o| o.on_mixed_audio_data(conn_id, data, info));
+        self.user().on_mixed_audio_data(conn_id, data, info)
+    }
+    fn on_video_data(
+        &self,
+        conn_id: u32,
+        uid: u32,
+        sent_ts: u16,
+        data: &[u8],
+        info: &video_frame_info_t,
+    ) {
+        self.observe(|o| o.on_video_data(conn_id, uid, sent_ts, data, info));
+        self.user().on_video_data(conn_id, uid, sent_ts, data, info)
+    }
+    fn on_target_bitrate_changed(&self, conn_id: u32, target_bps: u32) {
+        self.observe(|o| o.on_target_bitrate_changed(conn_id, target_bps));
+        self.user().on_target_bitrate_changed(conn_id, target_bps)
+    }
+    fn on_key_frame_gen_req(&self, conn_id: u32, uid: u32, stream_type: u32) {
+        self.observe(|o| o.on_key_frame_gen_req(conn_id, uid, stream_type));
+        self.user().on_key_frame_gen_req(conn_id, uid, stream_type)
+    }
+    fn on_token_privilege_will_expire(&self, conn_id: u32, token: &str) {
+        self.observe(|o| o.on_token_privilege_will_expire(conn_id, token));
+        self.user().on_token_privilege_will_expire(conn_id, token)
+    }
+    fn on_license_validation_failure(&self, conn_id: u32, error: i32) {
+        self.observe(|o| o.on_license_validation_failure(conn_id, error));
+        self.user().on_license_validation_failure(conn_id, error)
+    }
+}
+
+/// A connection created by `agora_rtc_create_connection`.
+///
+/// Each connection has its own channel, uid, token, options and [`EventHandler`],
+/// so one process can publish to several channels at once.
+/// Dropping it leaves the channel and destroys only this connection.
+/// It keeps the [`RtcService`] it was created from alive.
+pub struct Connection {
+    shared: Arc<ConnShared>,
+    default_video_info: Option<VideoFrameInfo>,
+    default_audio_type: Option<AudioDataType>,
+    #[cfg(feature = "async")]
+    events: Arc<EventSink>,
+    key_frames: Arc<KeyFrames>,
+    machine: Arc<StateMachine>,
+    rate: Mutex<Option<Arc<dyn Observer>>>,
+    reconnect: Mutex<Option<Reconnector>>,
+    recorder: Mutex<Option<Recorder>>,
+    roster: Arc<Roster>,
+    subscriptions: Arc<Subscriptions>,
+    token: Mutex<Option<TokenRefresher>>,
+    // declared last so it drops after the connection is destroyed
+    service: RtcService,
+}
+
+impl Connection {
+    pub(crate) fn new(service: RtcService) -> Result<Self, AgoraError> {
+        let mut conn_id: connection_id_t = 0;
+        err_2_result(service.backend().create_connection(&mut conn_id))?;
+        let shared = Arc::new(ConnShared {
+            conn_id: AtomicU32::new(conn_id),
+            state: Mutex::new(ConnState {
+                uid: 0,
+                channel_name: CString::default(),
+                token: CString::default(),
+                in_channel: false,
+                channel_option: None,
+                bwe: None,
+                mute_audio: false,
+                mute_video: false,
+            }),
+            handler: RwLock::new(None),
+            observers: RwLock::new(Vec::new()),
+        });
+        #[cfg(feature = "async")]
+        let events = Arc::new(EventSink::default());
+        #[cfg(feature = "async")]
+        shared.add_observer(events.clone());
+        let key_frames = Arc::new(KeyFrames::new(service.backend().clone()));
+        shared.add_observer(key_frames.clone());
+        let machine = Arc::new(StateMachine::default());
+        shared.add_observer(machine.clone());
+        let roster = Arc::new(Roster::default());
+        shared.add_observer(roster.clone());
+        let subscriptions = Arc::new(Subscriptions::new(service.backend().clone()));
+        shared.add_observer(subscriptions.clone());
+        handler::register_handler(conn_id, shared.clone());
+        Ok(Connection {
+            shared,
+            default_video_info: None,
+            default_audio_type: None,
+            #[cfg(feature = "async")]
+            events,
+            key_frames,
+            machine,
+            rate: Mutex::new(None),
+            reconnect: Mutex::new(None),
+            recorder: Mutex::new(None),
+            roster,
+            subscriptions,
+            token: Mutex::new(None),
+            service,
+        })
+    }
+
+    /// Changes when the connection is rebuilt by the [`ReconnectPolicy`].
+    pub fn conn_id(&self) -> u32 {
+        self.shared.conn_id()
+    }
+
+    fn backend(&self) -> &dyn RtcBackend {
+        &**self.service.backend()
+    }
+
+    /// The uid passed to `join_channel`, or the one assigned by the SDK
+    /// once `on_join_channel_success` arrives.
+    pub fn uid(&self) -> u32 {
+        self.shared.uid()
+    }
+
+    pub fn channel_name(&self) -> String {
+        self.shared.channel_name()
+    }
+
+    /// Only once `on_join_channel_success` (or `on_rejoin_channel_success`) arrived.
+    pub fn is_joined(&self) -> bool {
+        self.state() == ConnectionState::JOINED
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.machine.get()
+    }
+
+    /// Every state change from now on.
+    /// Unbounded, drop the receiver once not interested.
+    pub fn watch_state(&self) -> Receiver<StateChange> {
+        self.machine.watch()
+    }
+
+    /// Rebuild the connection (leave, destroy, create, join) when the SDK doesn't
+    /// rejoin by itself in time after `on_connection_lost`, as `policy` says.
+    /// Runs on a thread of its own. Replaces the previous policy.
+    pub fn set_reconnect_policy(&self, policy: ReconnectPolicy) {
+        let mut r = self.reconnect.lock().unwrap_or_else(|e| e.into_inner());
+        // the old one must be stopped first, they share the state machine
+        r.take();
+        *r = Some(Reconnector::start(
+            self.machine.clone(),
+            self.shared.clone(),
+            self.service.clone(),
+            policy,
+        ));
+    }
+
+    pub fn clear_reconnect_policy(&self) {
+        self.reconnect
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+    }
+
+    /// Route the events of this connection to `handler`.
+    /// Without one, events go to [`DefaultHandler`], which only logs.
+    pub fn set_event_handler<H: EventHandler + 'static>(&self, handler: H) {
+        self.set_event_handler_arc(Arc::new(handler));
+    }
+
+    /// Same as `set_event_handler` but lets you keep a reference to the handler.
+    pub fn set_event_handler_arc(&self, handler: Arc<dyn EventHandler>) {
+        let mut h = self
+            .shared
+            .handler
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        *h = Some(handler);
+    }
+
+    pub fn join_channel(
+        &self,
+        channel_name: &str,
+        uid: Option<u32>,
+        token: &str,
+        option: rtc_channel_options_t,
+    ) -> Result<(), AgoraError> {
+        let mut state = self.shared.state();
+        state.channel_name = channel_name.to_c_string()?;
+        state.token = token.to_c_string()?;
+        state.uid = uid.unwrap_or(0);
+        let state = &mut *state;
+        let opt = state.channel_option.insert(option);
+        self.roster.clear();
+        self.subscriptions
+            .joining(opt.auto_subscribe_audio, opt.auto_subscribe_video);
+        let code = self.backend().join_channel(
+            self.shared.conn_id(),
+            &state.channel_name,
+            state.uid,
+            &state.token,
+            opt,
+        );
+        let res = err_2_result(code);
+        if res.is_ok() {
+            state.in_channel = true;
+            self.machine
+                .set(self.shared.conn_id(), ConnectionState::JOINING);
+        }
+        res
+    }
+
+    /// `join_channel` which resolves once `on_join_channel_success` arrives,
+    /// with the uid assigned, or fails on `on_error` or after `timeout`.
+    /// On failure the channel is left, so the SDK doesn't go on joining.
+    /// Needs a tokio runtime with time enabled.
+    ///
+    /// There's no async `leave_channel`, the SDK doesn't report when leaving is done.
+    #[cfg(feature = "async")]
+    pub async fn join_channel_async(
+        &self,
+        channel_name: &str,
+        uid: Option<u32>,
+        token: &str,
+        option: rtc_channel_options_t,
+        timeout: Duration,
+    ) -> Result<u32, AgoraError> {
+        // subscribe first, the SDK may call back before join_channel returns
+        let mut events = self.events();
+        self.join_channel(channel_name, uid, token, option)?;
+        let res = match tokio::time::timeout(timeout, events::join_result(&mut events)).await {
+            Ok(res) => res,
+            Err(_) => Err(AgoraError::Timeout),
+        };
+        if res.is_err() {
+            if let Err(e) = self.leave_channel() {
+                warn!(
+                    "leave_channel failed after join, conn_id: {}, {}",
+                    self.conn_id(),
+                    e
+                );
+            }
+        }
+        res
+    }
+
+    /// All events of this connection from now on.
+    /// They are still delivered to the [`EventHandler`] as well.
+    #[cfg(feature = "async")]
+    pub fn events(&self) -> EventStream {
+        self.events.subscribe()
+    }
+
+    pub fn leave_channel(&self) -> Result<(), AgoraError> {
+        // first, so a rebuild either sees it or is done and leaves here
+        self.shared.state().in_channel = false;
+        let code = self.backend().leave_channel(self.shared.conn_id());
+        self.machine
+            .set(self.shared.conn_id(), ConnectionState::LEFT);
+        err_2_result(code)
+    }
+
+    /// Warns (and counts in `key_frame_stats`) when the first frame after a
+    /// key frame request is not a `VideoFrameType::KEY`.
+    pub fn send_video_data(&self, buf: &[u8], info: &VideoFrameInfo) -> Result<(), AgoraError> {
+        let id = self.shared.conn_id();
+        send_video(self.backend(), id, buf, info)?;
+        self.key_frames.sent(id, info);
+        Ok(())
+    }
+
+    pub fn set_video_info(&mut self, info: VideoFrameInfo) {
+        self.default_video_info = Some(info);
+    }
+
+    pub fn send_video_data_default(&self, buf: &[u8]) -> Result<(), AgoraError> {
+        let i = self.default_video_info.ok_or(AgoraError::NoVideoInfo)?;
+        self.send_video_data(buf, &i)
+    }
+
+    /// Send an audio frame of `data_type`.
+    /// For `AudioDataType::PCM` the frame is checked against the `audio_codec_opt`
+    /// passed to `join_channel`, see `validate_pcm_frame`.
+    pub fn send_audio_data(&self, buf: &[u8], data_type: AudioDataType) -> Result<(), AgoraError> {
+        if data_type == AudioDataType::PCM {
+            let opt = self
+                .shared
+                .state()
+                .channel_option
+                .ok_or(AgoraError::PcmCodecDisabled)?;
+            validate_pcm_frame(buf.len(), &opt.audio_codec_opt)?;
+        }
+        send_audio(self.backend(), self.shared.conn_id(), buf, data_type)
+    }
+
+    pub fn set_audio_type(&mut self, data_type: AudioDataType) {
+        self.default_audio_type = Some(data_type);
+    }
+
+    pub fn send_audio_data_default(&self, buf: &[u8]) -> Result<(), AgoraError> {
+        let t = self.default_audio_type.ok_or(AgoraError::NoAudioType)?;
+        self.send_audio_data(buf, t)
+    }
+
+    pub fn mute_local_audio(&self, is_muted: bool) -> Result<(), AgoraError> {
+        let code = self
+            .backend()
+            .mute_local_audio(self.shared.conn_id(), is_muted);
+        err_2_result(code)?;
+        self.shared.state().mute_audio = is_muted;
+        Ok(())
+    }
+
+    pub fn mute_local_video(&self, is_muted: bool) -> Result<(), AgoraError> {
+        let code = self
+            .backend()
+            .mute_local_video(self.shared.conn_id(), is_muted);
+        err_2_result(code)?;
+        self.shared.state().mute_video = is_muted;
+        Ok(())
+    }
+
+    /// Stop (or start again) receiving the audio of `uid`.
+    /// With `auto_subscribe_audio` off this is how to subscribe, see `set_audio_subscription`.
+    pub fn mute_remote_audio(&self, uid: u32, is_muted: bool) -> Result<(), AgoraError> {
+        self.subscriptions
+            .mute_remote_audio(self.conn_id(), uid, is_muted)
+    }
+
+    /// Stop (or start again) receiving the video of `uid`.
+    pub fn mute_remote_video(&self, uid: u32, is_muted: bool) -> Result<(), AgoraError> {
+        self.subscriptions
+            .mute_remote_video(self.conn_id(), uid, is_muted)
+    }
+
+    /// Remote users seen in the channel since `join_channel`, or since the
+    /// [`ReconnectPolicy`] rebuilt the connection, by uid.
+    /// Those who left stay, with their `offline_reason`, until they join again.
+    pub fn remote_users(&self) -> Vec<RemoteUser> {
+        self.roster.users()
+    }
+
+    /// Every change of `remote_users` from now on.
+    /// Unbounded, drop the receiver once not interested.
+    pub fn watch_users(&self) -> Receiver<RosterChange> {
+        self.roster.watch()
+    }
+
+    /// Whose audio to receive when the channel was joined with `auto_subscribe_audio`
+    /// off. Applied to the users already there and to every one joining later.
+    /// Does nothing while `auto_subscribe_audio` is on.
+    pub fn set_audio_subscription(&self, policy: SubscribePolicy) -> Result<(), AgoraError> {
+        self.subscriptions.set_audio_policy(self.conn_id(), policy)
+    }
+
+    /// Same as `set_audio_subscription`, for `auto_subscribe_video`.
+    pub fn set_video_subscription(&self, policy: SubscribePolicy) -> Result<(), AgoraError> {
+        self.subscriptions.set_video_policy(self.conn_id(), policy)
+    }
+
+    /// Ask `uid` for a key frame of its `quality` stream.
+    /// Repeats within the key frame window are merged, see `set_key_frame_window`.
+    pub fn request_key_frame(
+        &self,
+        uid: u32,
+        quality: VideoStreamQuality,
+    ) -> Result<(), AgoraError> {
+        self.key_frames.request(self.conn_id(), uid, quality)
+    }
+
+    /// Every key frame the remote users ask for from now on (`on_key_frame_gen_req`),
+    /// for the encoder to produce one. Repeats within the key frame window are merged.
+    /// Unbounded, drop the receiver once not interested.
+    pub fn watch_key_frame_requests(&self) -> Receiver<KeyFrameRequest> {
+        self.key_frames.watch()
+    }
+
+    /// [`DEFAULT_KEY_FRAME_WINDOW`](super::keyframe::DEFAULT_KEY_FRAME_WINDOW) if not set.
+    /// Zero merges nothing.
+    pub fn set_key_frame_window(&self, window: Duration) {
+        self.key_frames.set_window(window)
+    }
+
+    pub fn key_frame_stats(&self) -> KeyFrameStats {
+        self.key_frames.stats()
+    }
+
+    /// Bandwidth estimation range of this connection, in bps.
+    /// Needs `0 < min_bps <= start_bps <= max_bps`.
+    pub fn set_bwe_params(
+        &self,
+        min_bps: u32,
+        max_bps: u32,
+        start_bps: u32,
+    ) -> Result<(), AgoraError> {
+        check_bwe(min_bps, max_bps, start_bps)?;
+        let code = self
+            .backend()
+            .set_bwe_param(self.conn_id(), min_bps, max_bps, start_bps);
+        err_2_result(code)?;
+        self.shared.state().bwe = Some((min_bps, max_bps, start_bps));
+        Ok(())
+    }
+
+    /// Hand every `on_target_bitrate_changed` of this connection to `controller`,
+    /// e.g. a [`SmoothingRateController`](super::SmoothingRateController).
+    /// Replaces the previous one.
+    pub fn set_rate_controller(&self, controller: Arc<dyn EncoderRateController>) {
+        let forwarder: Arc<dyn Observer> = Arc::new(RateForwarder(controller));
+        self.shared.add_observer(forwarder.clone());
+        let old = self
+            .rate
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .replace(forwarder);
+        if let Some(old) = old {
+            self.shared.remove_observer(&old);
+        }
+    }
+
+    pub fn clear_rate_controller(&self) {
+        let old = self.rate.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(old) = old {
+            self.shared.remove_observer(&old);
+        }
+    }
+
+    /// Replace the token of the joined channel before it expires.
+    /// See `set_token_provider` to have this done automatically.
+    pub fn renew_token(&self, token: &str) -> Result<(), AgoraError> {
+        self.shared.renew_token(self.backend(), token)
+    }
+
+    /// Renew the token with one from `provider` whenever the SDK says it's about to expire
+    /// (`on_token_privilege_will_expire`) or it has (`on_error` with `TOKEN_EXPIRED` or
+    /// `INVALID_TOKEN`), retrying as `retry` says.
+    /// `provider` is called on a thread of its own and may block. Replaces the previous one.
+    pub fn set_token_provider(&self, provider: Arc<dyn TokenProvider>, retry: TokenRetry) {
+        let refresher =
+            TokenRefresher::start(self.shared.clone(), self.service.clone(), provider, retry);
+        // the old one stops here
+        *self.token.lock().unwrap_or_else(|e| e.into_inner()) = Some(refresher);
+    }
+
+    pub fn clear_token_provider(&self) {
+        self.token.lock().unwrap_or_else(|e| e.into_inner()).take();
+    }
+
+    /// Write what remote users send to `.mkv` files in `options.dir`, one at a time per uid,
+    /// starting a new one when `max_bytes` or `max_duration` is reached or the codec changes.
+    /// H.264/H.265 video, Opus, AAC (with ADTS headers) and G.711 audio are recorded,
+    /// timed by `sent_ts`. Files are written on a thread of their own.
+    /// Replaces the previous recording, which is finished first.
+    pub fn start_recording(&self, options: RecorderOptions) -> Result<(), AgoraError> {
+        let mut recorder = self.recorder.lock().unwrap_or_else(|e| e.into_inner());
+        recorder.take();
+        *recorder = Some(Recorder::start(self.shared.clone(), options)?);
+        Ok(())
+    }
+
+    /// Finish the files being written.
+    pub fn stop_recording(&self) {
+        self.recorder
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.clear_reconnect_policy();
+        self.clear_token_provider();
+        self.stop_recording();
+        let id = self.shared.conn_id();
+        if self.shared.state().in_channel {
+            if let Err(e) = self.leave_channel() {
+                warn!("leave_channel failed on drop, conn_id: {}, {}", id, e);
+            }
+        }
+        let code = self.backend().destroy_connection(id);
+        handler::unregister_handler(id);
+        if let Err(e) = err_2_result(code) {
+            warn!("destroy_connection failed, conn_id: {}, {}", id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fake::{fake_service, Call};
+    use super::*;
+
+    #[derive(Default)]
+    struct Joined(Mutex<Vec<u32>>);
+
+    impl EventHandler for Joined {
+        fn on_user_joined(&self, _conn_id: u32, uid: u32, _elapsed_ms: i32) {
+            self.0.lock().unwrap().push(uid);
+        }
+    }
+
+    #[test]
+    fn join_send_leave_on_fake() {
+        let (fake, service) = fake_service();
+        let conn = service.create_connection().unwrap();
+        let id = conn.conn_id();
+        let joined = Arc::new(Joined::default());
+        conn.set_event_handler_arc(joined.clone());
+
+        fake.fail("join_channel", -2);
+        assert!(conn
+            .join_channel("ch", None, "tok", rtc_channel_options_t::new())
+            .is_err());
+        assert!(!conn.is_joined());
+        fake.clear_failures();
+        conn.join_channel("ch", None, "tok", rtc_channel_options_t::new())
+            .unwrap();
+        fake.join_channel_success(id, 42);
+        fake.user_joined(id, 7);
+        assert_eq!(conn.uid(), 42);
+        assert_eq!(*joined.0.lock().unwrap(), vec![7]);
+
+        conn.send_audio_data(b"opus", AudioDataType::OPUS).unwrap();
+        conn.mute_local_audio(true).unwrap();
+        drop(conn);
+        drop(service);
+        let calls = fake.take_calls();
+        assert_eq!(
+            calls[calls.len() - 5..],
+            [
+                Call::SendAudioData {
+                    conn_id: id,
+                    data: b"opus".to_vec(),
+                    data_type: AudioDataType::OPUS.into()
+                },
+                Call::MuteLocalAudio {
+                    conn_id: id,
+                    mute: true
+                },
+                Call::LeaveChannel(id),
+                Call::DestroyConnection(id),
+                Call::Fini,
+            ]
+        );
+    }
+
+    #[test]
+    fn rebuild_after_leave_does_not_join() {
+        let (fake, service) = fake_service();
+        let conn = service.create_connection().unwrap();
+        conn.join_channel("ch", None, "tok", rtc_channel_options_t::new())
+            .unwrap();
+        // what the reconnect thread may still be doing after this
+        conn.leave_channel().unwrap();
+        fake.take_calls();
+        conn.shared.rebuild(conn.backend()).unwrap();
+        let calls = fake.take_calls();
+        assert!(
+            !calls.iter().any(|c| matches!(c, Call::JoinChannel { .. })),
+            "{:?}",
+            calls
+        );
+        assert_eq!(conn.state(), ConnectionState::LEFT);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn join_async_leaves_on_failure() {
+        let (fake, service) = fake_service();
+        let conn = service.create_connection().unwrap();
+        let id = conn.conn_id();
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        fake.take_calls();
+
+        let join = conn.join_channel_async(
+            "ch",
+            None,
+            "tok",
+            rtc_channel_options_t::new(),
+            Duration::from_millis(10),
+        );
+        assert!(matches!(rt.block_on(join), Err(AgoraError::Timeout)));
+        assert!(!conn.is_joined());
+        assert_eq!(conn.state(), ConnectionState::LEFT);
+        let calls = fake.take_calls();
+        assert!(matches!(calls[..], [Call::JoinChannel { .. }, Call::LeaveChannel(c)] if c == id));
+    }
+}
@@ -0,0 +1,288 @@
+use super::super::utils::err_2_reason;
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use std::ffi::NulError;
+use std::fmt;
+
+/// `agora_err_code_e`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
+#[repr(u32)]
+pub enum SdkError {
+    /// No error.
+    OKAY = 0,
+    /// General error
+    FAILED = 1,
+    /// Network is unavailable
+    NET_DOWN = 14,
+    /// Local user is already in channel and try to join the same channel again.
+    JOIN_CHANNEL_REJECTED = 17,
+    INVALID_APP_ID = 101,
+    INVALID_CHANNEL_NAME = 102,
+    /// Fails to get server resources in the specified region.
+    NO_SERVER_RESOURCES = 103,
+    LOOKUP_CHANNEL_REJECTED = 105,
+    OPEN_CHANNEL_REJECTED = 107,
+    TOKEN_EXPIRED = 109,
+    INVALID_TOKEN = 110,
+    /// Dynamic token has been enabled, but is not provided when joining the channel.
+    DYNAMIC_TOKEN_BUT_USE_STATIC_KEY = 115,
+    SET_CLIENT_ROLE_NOT_AUTHORIZED = 119,
+    /// The user may have used a different encryption password to join the channel.
+    DECRYPTION_FAILED = 120,
+    OPEN_CHANNEL_INVALID_TICKET = 121,
+    OPEN_CHANNEL_TRY_NEXT_VOS = 122,
+    CLIENT_IS_BANNED_BY_SERVER = 123,
+    /// Sending video data too fast and over the bandwidth limit.
+    SEND_VIDEO_OVER_BANDWIDTH_LIMIT = 200,
+    /// SDK built-in audio codec only supports G722 and OPUS.
+    AUDIO_DECODER_NOT_MATCH_AUDIO_FRAME = 201,
+    NO_AUDIO_DECODER_TO_HANDLE_AUDIO_FRAME = 202,
+}
+
+/// `license_err_reason_e`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
+#[repr(u32)]
+pub enum LicenseError {
+    INVALID = 1,
+    EXPIRE = 2,
+    /// Exceed license minutes limit
+    MINUTES_EXCEED = 3,
+    /// License use in limited period
+    LIMITED_PERIOD = 4,
+    /// Same license used in different devices at the same time
+    DIFF_DEVICES = 5,
+    /// SDK internal error
+    INTERNAL = 99,
+}
+
+impl LicenseError {
+    pub fn description(&self) -> &'static str {
+        match self {
+            LicenseError::INVALID => "Invalid license",
+            LicenseError::EXPIRE => "License expired",
+            LicenseError::MINUTES_EXCEED => "Exceed license minutes limit",
+            LicenseError::LIMITED_PERIOD => "License use in limited period",
+            LicenseError::DIFF_DEVICES => "Same license used in different devices at the same time",
+            LicenseError::INTERNAL => "SDK internal error",
+        }
+    }
+}
+
+/// `rtm_err_code_e`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
+#[repr(u32)]
+pub enum RtmError {
+    OK = 0,
+    FAILED = 1,
+    LOGIN_REJECTED = 2,
+    INVALID_RTM_UID = 3,
+    LOGIN_INVALID_TOKEN = 5,
+    LOGIN_NOT_AUTHORIZED = 7,
+    INVALID_APP_ID = 101,
+    LOOKUP_CHANNEL_REJECTED = 105,
+    TOKEN_EXPIRED = 109,
+    INVALID_TOKEN = 110,
+}
+
+impl RtmError {
+    pub fn description(&self) -> &'static str {
+        match self {
+            RtmError::OK => "No error",
+            RtmError::FAILED => "General error",
+            RtmError::LOGIN_REJECTED => "Login is rejected by the server",
+            RtmError::INVALID_RTM_UID => "Invalid rtm uid",
+            RtmError::LOGIN_INVALID_TOKEN => "The token is invalid",
+            RtmError::LOGIN_NOT_AUTHORIZED => "Unauthorized login",
+            RtmError::INVALID_APP_ID => "Invalid app id",
+            RtmError::LOOKUP_CHANNEL_REJECTED => {
+                "The server rejected the request to look up the channel"
+            }
+            RtmError::TOKEN_EXPIRED => "Authorized Timestamp expired",
+            RtmError::INVALID_TOKEN => "Invalid token",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgoraError {
+    /// Returned by `agora_rtc_*` or reported by `on_error`
+    Sdk(SdkError),
+    /// Reported by `on_license_validation_failure`
+    License(LicenseError),
+    /// Reported by the RTM callbacks
+    Rtm(RtmError),
+    /// A code the header doesn't know about
+    Unknown(i32),
+    /// String passed to the SDK contains an interior NUL
+    InvalidString(NulError),
+    /// The operation needs a connection but `create_connection` was not called
+    /// (or the connection was destroyed)
+    NoConnection,
+    /// `len` exceeds what the SDK (or a fixed size C field) accepts
+    BufferTooLarge { len: usize, max: usize },
+    /// Argument `name` is above what the SDK accepts
+    OutOfRange {
+        name: &'static str,
+        value: u64,
+        max: u64,
+    },
+    /// The SDK is not initialized (`init` was not called or failed)
+    NoService,
+    /// The SDK is already initialized with another app id
+    AlreadyInitialized,
+    /// `agora_rtc_init`/`agora_rtc_fini` called from an SDK callback thread
+    Reentrant,
+    /// Only one `RtmClient` can be logged in at a time
+    RtmAlreadyLoggedIn,
+    /// The `RtmClient` logged out before the send result arrived
+    RtmLoggedOut,
+    /// The RTM send queue is full (`OverflowPolicy::ERROR`)
+    RtmQueueFull,
+    /// The message was dropped from the RTM send queue
+    RtmDropped,
+    /// The awaited callback didn't arrive in time
+    Timeout,
+    /// `send_video_data_default` without `set_video_info`
+    NoVideoInfo,
+    /// `send_audio_data_default` without `set_audio_type`
+    NoAudioType,
+    /// PCM was sent but the audio codec of the channel is disabled
+    PcmCodecDisabled,
+    /// PCM frame is not a whole number of 10 ms blocks (`frame_bytes` each)
+    PcmFrameSize { len: usize, frame_bytes: usize },
+    /// An AccessToken2 could not be built or parsed, or its signature is wrong
+    AccessToken(&'static str),
+    /// A media file could not be read, parsed or written
+    MediaFile(String),
+    /// Only H.264 and H.265 streams are Annex-B
+    NotAnnexB(super::VideoDataType),
+    /// `set_bwe_params` needs `0 < min_bps <= start_bps <= max_bps`
+    InvalidBweRange {
+        min_bps: u32,
+        max_bps: u32,
+        start_bps: u32,
+    },
+}
+
+impl AgoraError {
+    /// From a code returned by an `agora_rtc_*` function or passed to `on_error`.
+    /// Functions return the negated `agora_err_code_e`, callbacks the positive one.
+    pub fn from_code(code: i32) -> Self {
+        match SdkError::from_u32(code.unsigned_abs()) {
+            Some(e) => AgoraError::Sdk(e),
+            None => AgoraError::Unknown(code),
+        }
+    }
+
+    /// From the `error` of `on_license_validation_failure`.
+    pub fn from_license_code(code: i32) -> Self {
+        match LicenseError::from_u32(code.unsigned_abs()) {
+            Some(e) => AgoraError::License(e),
+            None => AgoraError::Unknown(code),
+        }
+    }
+
+    /// From a `rtm_err_code_e`.
+    pub fn from_rtm_code(code: u32) -> Self {
+        match RtmError::from_u32(code) {
+            Some(e) => AgoraError::Rtm(e),
+            None => AgoraError::Unknown(code as i32),
+        }
+    }
+}
+
+impl fmt::Display for AgoraError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AgoraError::Sdk(e) => write!(f, "{} ({:?})", err_2_reason(*e as i32), e),
+            AgoraError::License(e) => write!(f, "license: {} ({:?})", e.description(), e),
+            AgoraError::Rtm(e) => write!(f, "rtm: {} ({:?})", e.description(), e),
+            AgoraError::Unknown(code) => write!(f, "{} (code {})", err_2_reason(*code), code),
+            AgoraError::InvalidString(e) => write!(f, "invalid string: {}", e),
+            AgoraError::NoConnection => write!(f, "no connection id"),
+            AgoraError::NoService => write!(f, "SDK not initialized"),
+            AgoraError::AlreadyInitialized => {
+                write!(f, "SDK already initialized with another app id")
+            }
+            AgoraError::Reentrant => write!(f, "SDK init/fini called from an SDK callback"),
+            AgoraError::BufferTooLarge { len, max } => {
+                write!(f, "buffer too large: {} bytes, max {}", len, max)
+            }
+            AgoraError::OutOfRange { name, value, max } => {
+                write!(f, "{} out of range: {}, max {}", name, value, max)
+            }
+            AgoraError::RtmAlreadyLoggedIn => write!(f, "another RtmClient is logged in"),
+            AgoraError::RtmLoggedOut => write!(f, "rtm logged out before the result arrived"),
+            AgoraError::RtmQueueFull => write!(f, "rtm send queue full"),
+            AgoraError::RtmDropped => write!(f, "rtm message dropped from the send queue"),
+            AgoraError::Timeout => write!(f, "timed out"),
+            AgoraError::NoVideoInfo => write!(f, "no default video info"),
+            AgoraError::NoAudioType => write!(f, "no default audio type"),
+            AgoraError::PcmCodecDisabled => {
+                write!(
+                    f,
+                    "PCM needs the audio codec enabled in audio_codec_option_t"
+                )
+            }
+            AgoraError::PcmFrameSize { len, frame_bytes } => write!(
+                f,
+                "PCM frame of {} bytes is not a multiple of {} bytes (10 ms)",
+                len, frame_bytes
+            ),
+            AgoraError::AccessToken(reason) => write!(f, "access token: {}", reason),
+            AgoraError::MediaFile(reason) => write!(f, "media file: {}", reason),
+            AgoraError::NotAnnexB(t) => write!(f, "{:?} is not an Annex-B video type", t),
+            AgoraError::InvalidBweRange {
+                min_bps,
+                max_bps,
+                start_bps,
+            } => write!(
+                f,
+                "invalid BWE range, min {} start {} max {} bps",
+                min_bps, start_bps, max_bps
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AgoraError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AgoraError::InvalidString(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<NulError> for AgoraError {
+    fn from(e: NulError) -> Self {
+        AgoraError::InvalidString(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes() {
+        assert_eq!(
+            AgoraError::from_code(-110),
+            AgoraError::Sdk(SdkError::INVALID_TOKEN)
+        );
+        assert_eq!(
+            AgoraError::from_code(109),
+            AgoraError::Sdk(SdkError::TOKEN_EXPIRED)
+        );
+        assert_eq!(AgoraError::from_code(-42), AgoraError::Unknown(-42));
+        assert_eq!(
+            AgoraError::from_license_code(5),
+            AgoraError::License(LicenseError::DIFF_DEVICES)
+        );
+        assert_eq!(
+            AgoraError::from_rtm_code(3),
+            AgoraError::Rtm(RtmError::INVALID_RTM_UID)
+        );
+        assert_eq!(AgoraError::from_rtm_code(4), AgoraError::Unknown(4));
+    }
+}
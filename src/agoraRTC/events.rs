@@ -0,0 +1,316 @@
+//! Events of a [`Connection`](super::Connection) as a [`Stream`], and the
+//! futures built on them. Enabled by the `async` feature.
+//!
+//! Nothing here needs a particular runtime except the timeouts,
+//! which use `tokio::time` and therefore need a tokio runtime with time enabled.
+use super::super::ffi::*;
+use super::handler::Observer;
+use super::state::fails_join;
+use super::AgoraError;
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures_core::Stream;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+/// One `agora_rtc_event_handler_t` callback, with the data copied.
+#[derive(Debug, Clone)]
+pub enum Event {
+    JoinChannelSuccess {
+        conn_id: u32,
+        uid: u32,
+        elapsed_ms: i32,
+    },
+    ConnectionLost {
+        conn_id: u32,
+    },
+    RejoinChannelSuccess {
+        conn_id: u32,
+        uid: u32,
+        elapsed_ms: i32,
+    },
+    Error {
+        conn_id: u32,
+        code: i32,
+        msg: String,
+    },
+    UserJoined {
+        conn_id: u32,
+        uid: u32,
+        elapsed_ms: i32,
+    },
+    UserOffline {
+        conn_id: u32,
+        uid: u32,
+        reason: i32,
+    },
+    UserMuteAudio {
+        conn_id: u32,
+        uid: u32,
+        muted: bool,
+    },
+    UserMuteVideo {
+        conn_id: u32,
+        uid: u32,
+        muted: bool,
+    },
+    AudioData {
+        conn_id: u32,
+        uid: u32,
+        sent_ts: u16,
+        data: Vec<u8>,
+        info: audio_frame_info_t,
+    },
+    MixedAudioData {
+        conn_id: u32,
+        data: Vec<u8>,
+        info: audio_frame_info_t,
+    },
+    VideoData {
+        conn_id: u32,
+        uid: u32,
+        sent_ts: u16,
+        data: Vec<u8>,
+        info: video_frame_info_t,
+    },
+    TargetBitrateChanged {
+        conn_id: u32,
+        target_bps: u32,
+    },
+    KeyFrameGenReq {
+        conn_id: u32,
+        uid: u32,
+        stream_type: u32,
+    },
+    TokenPrivilegeWillExpire {
+        conn_id: u32,
+        token: String,
+    },
+    LicenseValidationFailure {
+        conn_id: u32,
+        error: i32,
+    },
+}
+
+/// Stream of the events of one connection, see `Connection::events`.
+/// Unbounded, so poll it or drop it.
+pub struct EventStream {
+    rx: UnboundedReceiver<Event>,
+}
+
+impl Stream for EventStream {
+    type Item = Event;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+impl EventStream {
+    /// Next event, `None` once the connection is gone.
+    pub async fn next(&mut self) -> Option<Event> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+/// Observer of a connection feeding its `EventStream`s.
+#[derive(Default)]
+pub(crate) struct EventSink {
+    senders: Mutex<Vec<UnboundedSender<Event>>>,
+}
+
+impl EventSink {
+    pub(crate) fn subscribe(&self) -> EventStream {
+        let (tx, rx) = unbounded();
+        self.senders
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(tx);
+        EventStream { rx }
+    }
+
+    /// `make` is only called if someone listens, frames are not copied for nothing
+    fn send<F: FnOnce() -> Event>(&self, make: F) {
+        let mut senders = self.senders.lock().unwrap_or_else(|e| e.into_inner());
+        if senders.is_empty() {
+            return;
+        }
+        let ev = make();
+        // dropped streams go away here
+        senders.retain(|tx| tx.unbounded_send(ev.clone()).is_ok());
+    }
+}
+
+impl Observer for EventSink {
+    fn on_join_channel_success(&self, conn_id: u32, uid: u32, elapsed_ms: i32) {
+        self.send(|| Event::JoinChannelSuccess {
+            conn_id,
+            uid,
+            elapsed_ms,
+        })
+    }
+    fn on_connection_lost(&self, conn_id: u32) {
+        self.send(|| Event::ConnectionLost { conn_id })
+    }
+    fn on_rejoin_channel_success(&self, conn_id: u32, uid: u32, elapsed_ms: i32) {
+        self.send(|| Event::RejoinChannelSuccess {
+            conn_id,
+            uid,
+            elapsed_ms,
+        })
+    }
+    fn on_error(&self, conn_id: u32, code: i32, msg: &str) {
+        self.send(|| Event::Error {
+            conn_id,
+            code,
+            msg: msg.to_owned(),
+        })
+    }
+    fn on_user_joined(&self, conn_id: u32, uid: u32, elapsed_ms: i32) {
+        self.send(|| Event::UserJoined {
+            conn_id,
+            uid,
+            elapsed_ms,
+        })
+    }
+    fn on_user_offline(&self, conn_id: u32, uid: u32, reason: i32) {
+        self.send(|| Event::UserOffline {
+            conn_id,
+            uid,
+            reason,
+        })
+    }
+    fn on_user_mute_audio(&self, conn_id: u32, uid: u32, muted: bool) {
+        self.send(|| Event::UserMuteAudio {
+            conn_id,
+            uid,
+            muted,
+        })
+    }
+    fn on_user_mute_video(&self, conn_id: u32, uid: u32, muted: bool) {
+        self.send(|| Event::UserMuteVideo {
+            conn_id,
+            uid,
+            muted,
+        })
+    }
+    fn on_audio_data(
+        &self,
+        conn_id: u32,
+        uid: u32,
+        sent_ts: u16,
+        data: &[u8],
+        info: &audio_frame_info_t,
+    ) {
+        self.send(|| Event::AudioData {
+            conn_id,
+            uid,
+            sent_ts,
+            data: data.to_vec(),
+            info: *info,
+        })
+    }
+    fn on_mixed_audio_data(&self, conn_id: u32, data: &[u8], info: &audio_frame_info_t) {
+        self.send(|| Event::MixedAudioData {
+            conn_id,
+            data: data.to_vec(),
+            info: *info,
+        })
+    }
+    fn on_video_data(
+        &self,
+        conn_id: u32,
+        uid: u32,
+        sent_ts: u16,
+        data: &[u8],
+        info: &video_frame_info_t,
+    ) {
+        self.send(|| Event::VideoData {
+            conn_id,
+            uid,
+            sent_ts,
+            data: data.to_vec(),
+            info: *info,
+        })
+    }
+    fn on_target_bitrate_changed(&self, conn_id: u32, target_bps: u32) {
+        self.send(|| Event::TargetBitrateChanged {
+            conn_id,
+            target_bps,
+        })
+    }
+    fn on_key_frame_gen_req(&self, conn_id: u32, uid: u32, stream_type: u32) {
+        self.send(|| Event::KeyFrameGenReq {
+            conn_id,
+            uid,
+            stream_type,
+        })
+    }
+    fn on_token_privilege_will_expire(&self, conn_id: u32, token: &str) {
+        self.send(|| Event::TokenPrivilegeWillExpire {
+            conn_id,
+            token: token.to_owned(),
+        })
+    }
+    fn on_license_validation_failure(&self, conn_id: u32, error: i32) {
+        self.send(|| Event::LicenseValidationFailure { conn_id, error })
+    }
+}
+
+/// Wait on `events` for the outcome of a join: the uid on success, the error
+/// reported by `on_error` if it is one the join can't recover from. Other
+/// errors are warnings as far as the join is concerned.
+pub(crate) async fn join_result(events: &mut EventStream) -> Result<u32, AgoraError> {
+    while let Some(ev) = events.next().await {
+        match ev {
+            Event::JoinChannelSuccess { uid, .. } => return Ok(uid),
+            Event::Error { code, .. } if fails_join(code) => {
+                return Err(AgoraError::from_code(code))
+            }
+            _ => {}
+        }
+    }
+    Err(AgoraError::NoConnection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::SdkError;
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn join_resolves_on_callbacks() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let sink = EventSink::default();
+        let mut events = sink.subscribe();
+        let dropped = sink.subscribe();
+        drop(dropped);
+        sink.on_user_joined(1, 7, 0);
+        sink.on_join_channel_success(1, 42, 10);
+        assert_eq!(rt.block_on(join_result(&mut events)), Ok(42));
+        assert_eq!(sink.senders.lock().unwrap().len(), 1);
+
+        // not fatal, the join goes on
+        sink.on_error(1, 200, "over the bandwidth limit");
+        sink.on_join_channel_success(1, 43, 10);
+        assert_eq!(rt.block_on(join_result(&mut events)), Ok(43));
+
+        sink.on_error(1, 110, "bad token");
+        assert_eq!(
+            rt.block_on(join_result(&mut events)),
+            Err(AgoraError::Sdk(SdkError::INVALID_TOKEN))
+        );
+
+        let res = rt.block_on(async {
+            tokio::time::timeout(Duration::from_millis(10), join_result(&mut events)).await
+        });
+        assert!(res.is_err());
+        drop(events);
+        // nobody listens, nothing is copied or kept
+        sink.on_user_joined(1, 8, 0);
+        assert!(sink.senders.lock().unwrap().is_empty());
+    }
+}
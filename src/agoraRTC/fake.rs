@@ -0,0 +1,636 @@
+//! In-process [`RtcBackend`] for tests. Records every call and lets the test
+//! fire SDK callbacks through the handlers registered with `init`/`login_rtm`,
+//! so events take the same path through `callbacks.rs` as with the real SDK.
+use super::super::ffi::*;
+use super::backend::RtcBackend;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Version the fake claims to be, the one the bindings are for.
+pub const FAKE_SDK_VERSION: &str = SDK_VERSION;
+
+/// One recorded `agora_rtc_*` call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Call {
+    Init {
+        app_id: String,
+    },
+    Fini,
+    SetLogLevel(u32),
+    ConfigLog {
+        size_per_file: i32,
+        max_file_count: i32,
+    },
+    CreateConnection(u32),
+    DestroyConnection(u32),
+    JoinChannel {
+        conn_id: u32,
+        channel_name: String,
+        uid: u32,
+        token: String,
+    },
+    LeaveChannel(u32),
+    RenewToken {
+        conn_id: u32,
+        token: String,
+    },
+    NotifyNetworkEvent(u32),
+    MuteLocalAudio {
+        conn_id: u32,
+        mute: bool,
+    },
+    MuteLocalVideo {
+        conn_id: u32,
+        mute: bool,
+    },
+    MuteRemoteAudio {
+        conn_id: u32,
+        remote_uid: u32,
+        mute: bool,
+    },
+    MuteRemoteVideo {
+        conn_id: u32,
+        remote_uid: u32,
+        mute: bool,
+    },
+    RequestVideoKeyFrame {
+        conn_id: u32,
+        remote_uid: u32,
+        stream_type: u32,
+    },
+    SendAudioData {
+        conn_id: u32,
+        data: Vec<u8>,
+        data_type: u32,
+    },
+    SendVideoData {
+        conn_id: u32,
+        data: Vec<u8>,
+        data_type: u32,
+        stream_type: u32,
+        frame_type: u32,
+        frame_rate: u32,
+    },
+    SetBweParam {
+        conn_id: u32,
+        min_bps: u32,
+        max_bps: u32,
+        start_bps: u32,
+    },
+    SetParams(String),
+    SetCloudProxy(u32),
+    LoginRtm {
+        rtm_uid: String,
+        token: Option<String>,
+    },
+    LogoutRtm,
+    SendRtmData {
+        rtm_uid: String,
+        msg_id: u32,
+        data: Vec<u8>,
+    },
+}
+
+// shared by all fakes so ids never collide in the global handler registry
+static NEXT_CONN_ID: AtomicU32 = AtomicU32::new(1);
+
+#[derive(Default)]
+struct FakeState {
+    calls: Vec<Call>,
+    handler: Option<agora_rtc_event_handler_t>,
+    rtm_handler: Option<agora_rtm_handler_t>,
+    /// conn_id -> (channel_name, uid) once joined
+    joined: HashMap<u32, (String, u32)>,
+    results: HashMap<&'static str, i32>,
+}
+
+// the handlers are plain function pointers
+unsafe impl Send for FakeState {}
+
+#[derive(Default)]
+pub struct FakeBackend {
+    state: Mutex<FakeState>,
+}
+
+fn lossy(s: &CStr) -> String {
+    s.to_string_lossy().into_owned()
+}
+
+impl FakeBackend {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    fn state(&self) -> MutexGuard<'_, FakeState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Record `call` and return what `fail` set for `name`, 0 otherwise.
+    fn record(&self, name: &'static str, call: Call) -> i32 {
+        let mut st = self.state();
+        st.calls.push(call);
+        st.results.get(name).copied().unwrap_or(0)
+    }
+
+    /// Make every call of the method `name` (e.g. `"join_channel"`) return `code`,
+    /// until `clear_failures`. Use negative codes, like the SDK.
+    pub fn fail(&self, name: &'static str, code: i32) {
+        self.state().results.insert(name, code);
+    }
+
+    pub fn clear_failures(&self) {
+        self.state().results.clear();
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.state().calls.clone()
+    }
+
+    /// Calls so far, clearing the record.
+    pub fn take_calls(&self) -> Vec<Call> {
+        std::mem::take(&mut self.state().calls)
+    }
+
+    /// The callbacks passed to `init`, `None` before.
+    pub fn handler(&self) -> Option<agora_rtc_event_handler_t> {
+        self.state().handler
+    }
+
+    /// The callbacks passed to `login_rtm`, `None` before or after logout.
+    pub fn rtm_handler(&self) -> Option<agora_rtm_handler_t> {
+        self.state().rtm_handler
+    }
+
+    /// Run `f` with the `init` callbacks, like the SDK would from its thread.
+    /// Does nothing before `init`.
+    pub fn emit<F: FnOnce(&agora_rtc_event_handler_t)>(&self, f: F) {
+        // not holding the lock, handlers may call back into the backend
+        if let Some(h) = self.handler() {
+            f(&h)
+        }
+    }
+
+    pub fn emit_rtm<F: FnOnce(&agora_rtm_handler_t)>(&self, f: F) {
+        if let Some(h) = self.rtm_handler() {
+            f(&h)
+        }
+    }
+
+    pub fn join_channel_success(&self, conn_id: u32, uid: u32) {
+        self.emit(|h| {
+            if let Some(cb) = h.on_join_channel_success {
+                unsafe { cb(conn_id, uid, 0) }
+            }
+        })
+    }
+
+    pub fn connection_lost(&self, conn_id: u32) {
+        self.emit(|h| {
+            if let Some(cb) = h.on_connection_lost {
+                unsafe { cb(conn_id) }
+            }
+        })
+    }
+
+    pub fn rejoin_channel_success(&self, conn_id: u32, uid: u32) {
+        self.emit(|h| {
+            if let Some(cb) = h.on_rejoin_channel_success {
+                unsafe { cb(conn_id, uid, 0) }
+            }
+        })
+    }
+
+    /// `code` is positive, like `on_error` reports it
+    pub fn error(&self, conn_id: u32, code: i32, msg: &str) {
+        let msg = CString::new(msg).unwrap_or_default();
+        self.emit(|h| {
+            if let Some(cb) = h.on_error {
+                unsafe { cb(conn_id, code, msg.as_ptr()) }
+            }
+        })
+    }
+
+    pub fn user_joined(&self, conn_id: u32, uid: u32) {
+        self.emit(|h| {
+            if let Some(cb) = h.on_user_joined {
+                unsafe { cb(conn_id, uid, 0) }
+            }
+        })
+    }
+
+    pub fn user_offline(&self, conn_id: u32, uid: u32, reason: i32) {
+        self.emit(|h| {
+            if let Some(cb) = h.on_user_offline {
+                unsafe { cb(conn_id, uid, reason) }
+            }
+        })
+    }
+
+    pub fn user_mute_audio(&self, conn_id: u32, uid: u32, muted: bool) {
+        self.emit(|h| {
+            if let Some(cb) = h.on_user_mute_audio {
+                unsafe { cb(conn_id, uid, muted) }
+            }
+        })
+    }
+
+    pub fn user_mute_video(&self, conn_id: u32, uid: u32, muted: bool) {
+        self.emit(|h| {
+            if let Some(cb) = h.on_user_mute_video {
+                unsafe { cb(conn_id, uid, muted) }
+            }
+        })
+    }
+
+    pub fn audio_data(
+        &self,
+        conn_id: u32,
+        uid: u32,
+        sent_ts: u16,
+        data: &[u8],
+        info: &audio_frame_info_t,
+    ) {
+        self.emit(|h| {
+            if let Some(cb) = h.on_audio_data {
+                unsafe {
+                    cb(
+                        conn_id,
+                        uid,
+                        sent_ts,
+                        data.as_ptr() as *const std::ffi::c_void,
+                        data.len() as size_t,
+                        info,
+                    )
+                }
+            }
+        })
+    }
+
+    pub fn mixed_audio_data(&self, conn_id: u32, data: &[u8], info: &audio_frame_info_t) {
+        self.emit(|h| {
+            if let Some(cb) = h.on_mixed_audio_data {
+                unsafe {
+                    cb(
+                        conn_id,
+                        data.as_ptr() as *const std::ffi::c_void,
+                        data.len() as size_t,
+                        info,
+                    )
+                }
+            }
+        })
+    }
+
+    pub fn video_data(
+        &self,
+        conn_id: u32,
+        uid: u32,
+        sent_ts: u16,
+        data: &[u8],
+        info: &video_frame_info_t,
+    ) {
+        self.emit(|h| {
+            if let Some(cb) = h.on_video_data {
+                unsafe {
+                    cb(
+                        conn_id,
+                        uid,
+                        sent_ts,
+                        data.as_ptr() as *const std::ffi::c_void,
+                        data.len() as size_t,
+                        info,
+                    )
+                }
+            }
+        })
+    }
+
+    pub fn target_bitrate_changed(&self, conn_id: u32, target_bps: u32) {
+        self.emit(|h| {
+            if let Some(cb) = h.on_target_bitrate_changed {
+                unsafe { cb(conn_id, target_bps) }
+            }
+        })
+    }
+
+    pub fn key_frame_gen_req(&self, conn_id: u32, uid: u32, stream_type: video_stream_type_e) {
+        self.emit(|h| {
+            if let Some(cb) = h.on_key_frame_gen_req {
+                unsafe { cb(conn_id, uid, stream_type) }
+            }
+        })
+    }
+
+    pub fn token_privilege_will_expire(&self, conn_id: u32, token: &str) {
+        let token = CString::new(token).unwrap_or_default();
+        self.emit(|h| {
+            if let Some(cb) = h.on_token_privilege_will_expire {
+                unsafe { cb(conn_id, token.as_ptr()) }
+            }
+        })
+    }
+
+    pub fn license_validation_failure(&self, conn_id: u32, error: i32) {
+        self.emit(|h| {
+            if let Some(cb) = h.on_license_validation_failure {
+                unsafe { cb(conn_id, error) }
+            }
+        })
+    }
+
+    pub fn rtm_data(&self, rtm_uid: &str, data: &[u8]) {
+        let uid = CString::new(rtm_uid).unwrap_or_default();
+        self.emit_rtm(|h| {
+            if let Some(cb) = h.on_rtm_data {
+                unsafe {
+                    cb(
+                        uid.as_ptr(),
+                        data.as_ptr() as *const std::ffi::c_void,
+                        data.len() as size_t,
+                    )
+                }
+            }
+        })
+    }
+
+    pub fn rtm_event(&self, rtm_uid: &str, event_type: rtm_event_type_e, err_code: rtm_err_code_e) {
+        let uid = CString::new(rtm_uid).unwrap_or_default();
+        self.emit_rtm(|h| {
+            if let Some(cb) = h.on_rtm_event {
+                unsafe { cb(uid.as_ptr(), event_type, err_code) }
+            }
+        })
+    }
+
+    pub fn send_rtm_data_result(&self, msg_id: u32, err_code: rtm_err_code_e) {
+        self.emit_rtm(|h| {
+            if let Some(cb) = h.on_send_rtm_data_result {
+                unsafe { cb(msg_id, err_code) }
+            }
+        })
+    }
+}
+
+impl RtcBackend for FakeBackend {
+    fn get_version(&self) -> String {
+        FAKE_SDK_VERSION.to_owned()
+    }
+    fn err_2_str(&self, err: i32) -> String {
+        format!("fake error {}", err)
+    }
+    fn init(
+        &self,
+        app_id: &CStr,
+        handler: &agora_rtc_event_handler_t,
+        _option: &mut rtc_service_option_t,
+    ) -> i32 {
+        let code = self.record(
+            "init",
+            Call::Init {
+                app_id: lossy(app_id),
+            },
+        );
+        if code == 0 {
+            self.state().handler = Some(*handler);
+        }
+        code
+    }
+    fn fini(&self) -> i32 {
+        let code = self.record("fini", Call::Fini);
+        if code == 0 {
+            let mut st = self.state();
+            st.handler = None;
+            st.joined.clear();
+        }
+        code
+    }
+    fn set_log_level(&self, level: rtc_log_level_e) -> i32 {
+        self.record("set_log_level", Call::SetLogLevel(level))
+    }
+    fn config_log(&self, size_per_file: i32, max_file_count: i32) -> i32 {
+        self.record(
+            "config_log",
+            Call::ConfigLog {
+                size_per_file,
+                max_file_count,
+            },
+        )
+    }
+    fn create_connection(&self, conn_id: &mut connection_id_t) -> i32 {
+        let id = NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed);
+        let code = self.record("create_connection", Call::CreateConnection(id));
+        if code == 0 {
+            *conn_id = id;
+        }
+        code
+    }
+    fn destroy_connection(&self, conn_id: connection_id_t) -> i32 {
+        self.state().joined.remove(&conn_id);
+        self.record("destroy_connection", Call::DestroyConnection(conn_id))
+    }
+    fn get_connection_info(&self, conn_id: connection_id_t, info: &mut connection_info_t) -> i32 {
+        let code = self.state().results.get("get_connection_info").copied();
+        if let Some(code) = code {
+            return code;
+        }
+        let st = self.state();
+        let (name, uid) = match st.joined.get(&conn_id) {
+            Some(j) => j,
+            None => return -(agora_err_code_e_ERR_FAILED as i32),
+        };
+        info.conn_id = conn_id;
+        info.uid = *uid;
+        info.channel_name = [0; 65];
+        for (dst, src) in info.channel_name.iter_mut().zip(name.bytes().take(64)) {
+            *dst = src as std::os::raw::c_char;
+        }
+        0
+    }
+    fn join_channel(
+        &self,
+        conn_id: connection_id_t,
+        channel_name: &CStr,
+        uid: u32,
+        token: &CStr,
+        _options: &mut rtc_channel_options_t,
+    ) -> i32 {
+        let code = self.record(
+            "join_channel",
+            Call::JoinChannel {
+                conn_id,
+                channel_name: lossy(channel_name),
+                uid,
+                token: lossy(token),
+            },
+        );
+        if code == 0 {
+            self.state()
+                .joined
+                .insert(conn_id, (lossy(channel_name), uid));
+        }
+        code
+    }
+    fn leave_channel(&self, conn_id: connection_id_t) -> i32 {
+        self.state().joined.remove(&conn_id);
+        self.record("leave_channel", Call::LeaveChannel(conn_id))
+    }
+    fn renew_token(&self, conn_id: connection_id_t, token: &CStr) -> i32 {
+        self.record(
+            "renew_token",
+            Call::RenewToken {
+                conn_id,
+                token: lossy(token),
+            },
+        )
+    }
+    fn notify_network_event(&self, event: network_event_type_e) -> i32 {
+        self.record("notify_network_event", Call::NotifyNetworkEvent(event))
+    }
+    fn mute_local_audio(&self, conn_id: connection_id_t, mute: bool) -> i32 {
+        self.record("mute_local_audio", Call::MuteLocalAudio { conn_id, mute })
+    }
+    fn mute_local_video(&self, conn_id: connection_id_t, mute: bool) -> i32 {
+        self.record("mute_local_video", Call::MuteLocalVideo { conn_id, mute })
+    }
+    fn mute_remote_audio(&self, conn_id: connection_id_t, remote_uid: u32, mute: bool) -> i32 {
+        self.record(
+            "mute_remote_audio",
+            Call::MuteRemoteAudio {
+                conn_id,
+                remote_uid,
+                mute,
+            },
+        )
+    }
+    fn mute_remote_video(&self, conn_id: connection_id_t, remote_uid: u32, mute: bool) -> i32 {
+        self.record(
+            "mute_remote_video",
+            Call::MuteRemoteVideo {
+                conn_id,
+                remote_uid,
+                mute,
+            },
+        )
+    }
+    fn request_video_key_frame(
+        &self,
+        conn_id: connection_id_t,
+        remote_uid: u32,
+        stream_type: video_stream_type_e,
+    ) -> i32 {
+        self.record(
+            "request_video_key_frame",
+            Call::RequestVideoKeyFrame {
+                conn_id,
+                remote_uid,
+                stream_type,
+            },
+        )
+    }
+    fn send_audio_data(
+        &self,
+        conn_id: connection_id_t,
+        data: &[u8],
+        info: &audio_frame_info_t,
+    ) -> i32 {
+        self.record(
+            "send_audio_data",
+            Call::SendAudioData {
+                conn_id,
+                data: data.to_vec(),
+                data_type: info.data_type,
+            },
+        )
+    }
+    fn send_video_data(
+        &self,
+        conn_id: connection_id_t,
+        data: &[u8],
+        info: &video_frame_info_t,
+    ) -> i32 {
+        self.record(
+            "send_video_data",
+            Call::SendVideoData {
+                conn_id,
+                data: data.to_vec(),
+                data_type: info.data_type,
+                stream_type: info.stream_type,
+                frame_type: info.frame_type,
+                frame_rate: info.frame_rate,
+            },
+        )
+    }
+    fn set_bwe_param(
+        &self,
+        conn_id: connection_id_t,
+        min_bps: u32,
+        max_bps: u32,
+        start_bps: u32,
+    ) -> i32 {
+        self.record(
+            "set_bwe_param",
+            Call::SetBweParam {
+                conn_id,
+                min_bps,
+                max_bps,
+                start_bps,
+            },
+        )
+    }
+    fn set_params(&self, params: &CStr) -> i32 {
+        self.record("set_params", Call::SetParams(lossy(params)))
+    }
+    fn set_cloud_proxy(&self, proxy_type: cloud_proxy_type_e) -> i32 {
+        self.record("set_cloud_proxy", Call::SetCloudProxy(proxy_type))
+    }
+    fn login_rtm(
+        &self,
+        rtm_uid: &CStr,
+        token: Option<&CStr>,
+        handler: &agora_rtm_handler_t,
+    ) -> i32 {
+        let code = self.record(
+            "login_rtm",
+            Call::LoginRtm {
+                rtm_uid: lossy(rtm_uid),
+                token: token.map(lossy),
+            },
+        );
+        if code == 0 {
+            self.state().rtm_handler = Some(*handler);
+        }
+        code
+    }
+    fn logout_rtm(&self) -> i32 {
+        self.state().rtm_handler = None;
+        self.record("logout_rtm", Call::LogoutRtm)
+    }
+    fn send_rtm_data(&self, rtm_uid: &CStr, msg_id: u32, msg: &[u8]) -> i32 {
+        self.record(
+            "send_rtm_data",
+            Call::SendRtmData {
+                rtm_uid: lossy(rtm_uid),
+                msg_id,
+                data: msg.to_vec(),
+            },
+        )
+    }
+}
+
+/// A new fake and a service on it, what most tests start with.
+#[cfg(test)]
+pub(crate) fn fake_service() -> (Arc<FakeBackend>, super::service::RtcService) {
+    use super::{LogLevel, RtcServiceOption, Trampolines};
+    let fake = FakeBackend::new();
+    let opt = RtcServiceOption::new("", "", "", LogLevel::DEFAULT).unwrap();
+    let service = super::service::RtcService::init_with_backend(
+        fake.clone(),
+        "app",
+        agora_rtc_event_handler_t::new(),
+        opt,
+    )
+    .unwrap();
+    (fake, service)
+}
@@ -0,0 +1,337 @@
+use super::super::ffi::*;
+use super::super::utils::err_2_reason;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, RwLock};
+
+/// Safe counterpart of `agora_rtc_event_handler_t`.
+///
+/// Implementors can capture whatever state they need. Every method has a default
+/// implementation which does what the old `extern "C"` callbacks did (mostly logging),
+/// so you only have to override the events you care about.
+///
+/// The methods are called from SDK threads, hence `Send + Sync`.
+/// A panic inside a handler is caught at the FFI boundary and logged.
+pub trait EventHandler: Send + Sync {
+    /// Occurs when local user joins channel successfully.
+    /// * `elapsed_ms` - Time elapsed (ms) since channel is established
+    fn on_join_channel_success(&self, conn_id: u32, uid: u32, elapsed_ms: i32) {
+        info!(
+            "join_channel_success, conn_id: {}, uid: {}, elapsed_ms: {}",
+            conn_id, uid, elapsed_ms
+        );
+    }
+
+    fn on_connection_lost(&self, conn_id: u32) {
+        error!("connection_lost, conn_id: {}", conn_id);
+    }
+
+    fn on_rejoin_channel_success(&self, conn_id: u32, uid: u32, elapsed_ms: i32) {
+        info!(
+            "rejoin_channel_success, conn_id: {}, uid: {}, elapsed_ms: {}",
+            conn_id, uid, elapsed_ms
+        );
+    }
+
+    /// Report error message during runtime.
+    /// In most cases, it means SDK can't fix the issue and application should take action.
+    /// * `code` - Error code, see #agora_err_code_e
+    fn on_error(&self, conn_id: u32, code: i32, msg: &str) {
+        let message = match code as agora_err_code_e {
+            agora_err_code_e_ERR_INVALID_APP_ID => "Invalid App ID. Please double check.",
+            agora_err_code_e_ERR_INVALID_CHANNEL_NAME => "Invalid channel name",
+            agora_err_code_e_ERR_INVALID_TOKEN => "Invalid token",
+            agora_err_code_e_ERR_DYNAMIC_TOKEN_BUT_USE_STATIC_KEY => {
+                "Dynamic token is enabled but is not provided."
+            }
+            _ => "Other Error",
+        };
+        error!(
+            "ERROR!, conn_id: {}, {:?}, {:?}, Code: {}",
+            conn_id, message, msg, code
+        );
+    }
+
+    fn on_user_joined(&self, conn_id: u32, uid: u32, elapsed_ms: i32) {
+        info!(
+            "user_join, conn_id: {}, uid: {}, elapsed_ms: {}",
+            conn_id, uid, elapsed_ms
+        );
+    }
+
+    fn on_user_offline(&self, conn_id: u32, uid: u32, reason: i32) {
+        warn!(
+            "user_offline, conn_id: {}, uid: {}, user_offline_reason_e: {}",
+            conn_id, uid, reason
+        );
+    }
+
+    fn on_user_mute_audio(&self, conn_id: u32, uid: u32, muted: bool) {
+        info!(
+            "user_mute_audio, conn_id: {}, uid: {}, is_muted: {}",
+            conn_id, uid, muted
+        );
+    }
+
+    fn on_user_mute_video(&self, conn_id: u32, uid: u32, muted: bool) {
+        info!(
+            "user_mute_video, conn_id: {}, uid: {}, is_muted: {}",
+            conn_id, uid, muted
+        );
+    }
+
+    /// `data` is only valid during the call. Copy it if you need to keep it.
+    fn on_audio_data(
+        &self,
+        _conn_id: u32,
+        _uid: u32,
+        _sent_ts: u16,
+        _data: &[u8],
+        _info: &audio_frame_info_t,
+    ) {
+        // won't do anything default
+    }
+
+    /// Occurs every 20ms.
+    fn on_mixed_audio_data(&self, _conn_id: u32, _data: &[u8], _info: &audio_frame_info_t) {
+        // won't do anything default
+    }
+
+    /// `data` is only valid during the call. Copy it if you need to keep it.
+    fn on_video_data(
+        &self,
+        _conn_id: u32,
+        _uid: u32,
+        _sent_ts: u16,
+        _data: &[u8],
+        _info: &video_frame_info_t,
+    ) {
+        // won't do anything default
+    }
+
+    fn on_target_bitrate_changed(&self, conn_id: u32, target_bps: u32) {
+        info!(
+            "target_bitrate_changed, conn_id: {}, target_bps: {}",
+            conn_id, target_bps
+        );
+    }
+
+    /// * `stream_type` - see #video_stream_type_e
+    fn on_key_frame_gen_req(&self, conn_id: u32, uid: u32, stream_type: u32) {
+        info!(
+            "key_frame_gen_req, conn_id: {}, uid: {}, video_stream_type_e: {}",
+            conn_id, uid, stream_type
+        );
+    }
+
+    fn on_token_privilege_will_expire(&self, conn_id: u32, token: &str) {
+        info!(
+            "token_privilege_will_expire, conn_id: {}, The token will expire: {:?}",
+            conn_id, token
+        );
+    }
+
+    fn on_license_validation_failure(&self, conn_id: u32, error: i32) {
+        let reason = err_2_reason(error);
+        error!(
+            "license_validation_failure, conn_id: {}, error: {}, reason: {:?}",
+            conn_id, error, reason
+        );
+    }
+}
+
+/// Used for events whose `conn_id` has no handler registered.
+/// Only logs, like the old default callbacks.
+pub struct DefaultHandler;
+
+impl EventHandler for DefaultHandler {}
+
+/// The receivers an observer hands its changes to, one per `watch`.
+pub(crate) struct Watchers<T>(Vec<Sender<T>>);
+
+impl<T> Default for Watchers<T> {
+    fn default() -> Self {
+        Watchers(Vec::new())
+    }
+}
+
+impl<T: Clone> Watchers<T> {
+    pub(crate) fn watch(&mut self) -> Receiver<T> {
+        let (tx, rx) = channel();
+        self.0.push(tx);
+        rx
+    }
+
+    pub(crate) fn send(&mut self, change: T) {
+        // dropped receivers go away here
+        self.0.retain(|tx| tx.send(change.clone()).is_ok());
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Same events as [`EventHandler`] but doing nothing by default.
+/// For the internal trackers a connection feeds before the user's handler.
+#[allow(unused_variables)]
+pub(crate) trait Observer: Send + Sync {
+    fn on_join_channel_success(&self, conn_id: u32, uid: u32, elapsed_ms: i32) {}
+    fn on_connection_lost(&self, conn_id: u32) {}
+    fn on_rejoin_channel_success(&self, conn_id: u32, uid: u32, elapsed_ms: i32) {}
+    fn on_error(&self, conn_id: u32, code: i32, msg: &str) {}
+    fn on_user_joined(&self, conn_id: u32, uid: u32, elapsed_ms: i32) {}
+    fn on_user_offline(&self, conn_id: u32, uid: u32, reason: i32) {}
+    fn on_user_mute_audio(&self, conn_id: u32, uid: u32, muted: bool) {}
+    fn on_user_mute_video(&self, conn_id: u32, uid: u32, muted: bool) {}
+    fn on_audio_data(
+        &self,
+        conn_id: u32,
+        uid: u32,
+        sent_ts: u16,
+        data: &[u8],
+        info: &audio_frame_info_t,
+    ) {
+    }
+    fn on_mixed_audio_data(&self, conn_id: u32, data: &[u8], info: &audio_frame_info_t) {}
+    fn on_video_data(
+        &self,
+        conn_id: u32,
+        uid: u32,
+        sent_ts: u16,
+        data: &[u8],
+        info: &video_frame_info_t,
+    ) {
+    }
+    fn on_target_bitrate_changed(&self, conn_id: u32, target_bps: u32) {}
+    fn on_key_frame_gen_req(&self, conn_id: u32, uid: u32, stream_type: u32) {}
+    fn on_token_privilege_will_expire(&self, conn_id: u32, token: &str) {}
+    fn on_license_validation_failure(&self, conn_id: u32, error: i32) {}
+    /// Not from the SDK: the [`ReconnectPolicy`](super::ReconnectPolicy) destroyed the
+    /// connection and created `conn_id`, about to join. Nothing of the old one carries over.
+    fn on_rebuilt(&self, old_conn_id: u32, conn_id: u32) {}
+}
+
+lazy_static! {
+    static ref HANDLERS: RwLock<HashMap<u32, Arc<dyn EventHandler>>> = RwLock::new(HashMap::new());
+    static ref DEFAULT_HANDLER: Arc<dyn EventHandler> = Arc::new(DefaultHandler);
+}
+
+/// route events of `conn_id` to `handler`. Replaces the previous one if any.
+pub(crate) fn register_handler(conn_id: u32, handler: Arc<dyn EventHandler>) {
+    // a poisoned lock only means some writer panicked, the map itself is fine
+    let mut map = HANDLERS.write().unwrap_or_else(|e| e.into_inner());
+    map.insert(conn_id, handler);
+}
+
+pub(crate) fn unregister_handler(conn_id: u32) {
+    let mut map = HANDLERS.write().unwrap_or_else(|e| e.into_inner());
+    map.remove(&conn_id);
+}
+
+/// The handler registered for `conn_id`, or [`DefaultHandler`].
+/// The lock is released before returning so handlers are free to (un)register.
+pub(crate) fn handler_for(conn_id: u32) -> Arc<dyn EventHandler> {
+    let map = HANDLERS.read().unwrap_or_else(|e| e.into_inner());
+    match map.get(&conn_id) {
+        Some(h) => h.clone(),
+        None => DEFAULT_HANDLER.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::callbacks;
+    use super::*;
+    use std::ffi::CString;
+    use std::os::raw::c_void;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl EventHandler for Recorder {
+        fn on_user_joined(&self, conn_id: u32, uid: u32, _elapsed_ms: i32) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("joined {} {}", conn_id, uid));
+        }
+        fn on_error(&self, conn_id: u32, code: i32, msg: &str) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("error {} {} {}", conn_id, code, msg));
+        }
+        fn on_video_data(
+            &self,
+            _conn_id: u32,
+            uid: u32,
+            _sent_ts: u16,
+            data: &[u8],
+            _info: &video_frame_info_t,
+        ) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("video {} {:?}", uid, data));
+        }
+        fn on_user_offline(&self, _conn_id: u32, _uid: u32, _reason: i32) {
+            panic!("boom");
+        }
+    }
+
+    #[test]
+    fn trampolines_route_by_conn_id() {
+        let a = Arc::new(Recorder::default());
+        let b = Arc::new(Recorder::default());
+        register_handler(1001, a.clone());
+        register_handler(1002, b.clone());
+        let msg = CString::new("bad token").unwrap();
+        let data = [1u8, 2, 3];
+        let info = video_frame_info_t {
+            data_type: video_data_type_e_VIDEO_DATA_TYPE_H264,
+            stream_type: video_stream_type_e_VIDEO_STREAM_HIGH,
+            frame_type: video_frame_type_e_VIDEO_FRAME_KEY,
+            frame_rate: video_frame_rate_e_VIDEO_FRAME_RATE_FPS_30,
+            rotation: video_orientation_e_VIDEO_ORIENTATION_0,
+        };
+        unsafe {
+            callbacks::on_user_joined(1001, 7, 0);
+            callbacks::on_error(1002, 110, msg.as_ptr());
+            callbacks::on_video_data(
+                1001,
+                8,
+                0,
+                data.as_ptr() as *const c_void,
+                data.len() as size_t,
+                &info,
+            );
+            // nobody listens to 1003, falls back to DefaultHandler
+            callbacks::on_user_joined(1003, 9, 0);
+        }
+        assert_eq!(
+            *a.events.lock().unwrap(),
+            vec!["joined 1001 7".to_owned(), "video 8 [1, 2, 3]".to_owned()]
+        );
+        assert_eq!(*b.events.lock().unwrap(), vec!["error 1002 110 bad token"]);
+        unregister_handler(1001);
+        unregister_handler(1002);
+    }
+
+    #[test]
+    fn panic_does_not_cross_ffi() {
+        let a = Arc::new(Recorder::default());
+        register_handler(1004, a.clone());
+        unsafe { callbacks::on_user_offline(1004, 1, 0) };
+        // still usable afterwards
+        unsafe { callbacks::on_user_joined(1004, 2, 0) };
+        assert_eq!(*a.events.lock().unwrap(), vec!["joined 1004 2"]);
+        unregister_handler(1004);
+    }
+}
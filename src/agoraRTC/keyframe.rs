@@ -0,0 +1,236 @@
+//! Key frames asked for by remote users (`on_key_frame_gen_req`) or by us
+//! (`agora_rtc_request_video_key_frame`).
+//!
+//! A viewer joining or losing packets makes the SDK ask for a key frame, often
+//! several times in a row. Repeats within a window are merged so the encoder
+//! only produces one IDR for them.
+use super::super::utils::err_2_result;
+use super::backend::RtcBackend;
+use super::handler::{Observer, Watchers};
+use super::rate_limit::Coalescer;
+use super::{AgoraError, VideoFrameInfo, VideoFrameType, VideoStreamQuality};
+use log::warn;
+use num_traits::FromPrimitive;
+use std::collections::BTreeSet;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Requests closer than this are merged, see `Connection::set_key_frame_window`.
+pub const DEFAULT_KEY_FRAME_WINDOW: Duration = Duration::from_millis(500);
+
+/// A remote user wants a key frame of the `quality` stream of the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyFrameRequest {
+    pub conn_id: u32,
+    pub uid: u32,
+    pub quality: VideoStreamQuality,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyFrameStats {
+    /// `on_key_frame_gen_req` passed to the watchers
+    pub requested: u64,
+    /// `on_key_frame_gen_req` merged into an earlier one
+    pub merged: u64,
+    /// Frames sent after a request that were not `VideoFrameType::KEY`
+    pub not_key: u64,
+}
+
+struct State {
+    incoming: Coalescer<u32>,
+    outgoing: Coalescer<(u32, u32)>,
+    /// stream types a request went out to the watchers for, no frame sent since
+    pending: BTreeSet<u32>,
+    stats: KeyFrameStats,
+    watchers: Watchers<KeyFrameRequest>,
+}
+
+/// Observer of a connection handing key frame requests to the encoders.
+pub(crate) struct KeyFrames {
+    backend: Arc<dyn RtcBackend>,
+    state: Mutex<State>,
+}
+
+impl KeyFrames {
+    pub(crate) fn new(backend: Arc<dyn RtcBackend>) -> Self {
+        KeyFrames {
+            backend,
+            state: Mutex::new(State {
+                incoming: Coalescer::new(DEFAULT_KEY_FRAME_WINDOW),
+                outgoing: Coalescer::new(DEFAULT_KEY_FRAME_WINDOW),
+                pending: BTreeSet::new(),
+                stats: KeyFrameStats::default(),
+                watchers: Watchers::default(),
+            }),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn set_window(&self, window: Duration) {
+        let mut st = self.state();
+        st.incoming.set_window(window);
+        st.outgoing.set_window(window);
+    }
+
+    pub(crate) fn watch(&self) -> Receiver<KeyFrameRequest> {
+        self.state().watchers.watch()
+    }
+
+    pub(crate) fn stats(&self) -> KeyFrameStats {
+        self.state().stats
+    }
+
+    /// Ask `uid` for a key frame, unless we just did.
+    pub(crate) fn request(
+        &self,
+        conn_id: u32,
+        uid: u32,
+        quality: VideoStreamQuality,
+    ) -> Result<(), AgoraError> {
+        let stream_type: u32 = quality.into();
+        let key = (uid, stream_type);
+        if !self.state().outgoing.admit(key, Instant::now()) {
+            return Ok(());
+        }
+        let code = self
+            .backend
+            .request_video_key_frame(conn_id, uid, stream_type);
+        let res = err_2_result(code);
+        if res.is_err() {
+            // didn't go out, the next one shouldn't be merged into it
+            self.state().outgoing.forget(&key);
+        }
+        res
+    }
+
+    /// After every frame sent: the first one of a stream after a request for it
+    /// should be a key frame.
+    pub(crate) fn sent(&self, conn_id: u32, info: &VideoFrameInfo) {
+        let mut st = self.state();
+        if !st.pending.remove(&info.stream_type.into()) {
+            return;
+        }
+        if info.frame_type != VideoFrameType::KEY {
+            st.stats.not_key += 1;
+            warn!(
+                "key frame of the {:?} stream requested but {:?} sent, conn_id: {}",
+                info.stream_type, info.frame_type, conn_id
+            );
+        }
+    }
+}
+
+impl Observer for KeyFrames {
+    fn on_key_frame_gen_req(&self, conn_id: u32, uid: u32, stream_type: u32) {
+        let quality = match VideoStreamQuality::from_u32(stream_type) {
+            Some(q) => q,
+            None => {
+                warn!(
+                    "unknown video_stream_type_e {}, conn_id: {}, uid: {}",
+                    stream_type, conn_id, uid
+                );
+                return;
+            }
+        };
+        let mut st = self.state();
+        // one IDR does for every viewer of the stream
+        if !st.incoming.admit(stream_type, Instant::now()) {
+            st.stats.merged += 1;
+            return;
+        }
+        st.stats.requested += 1;
+        st.pending.insert(stream_type);
+        let req = KeyFrameRequest {
+            conn_id,
+            uid,
+            quality,
+        };
+        st.watchers.send(req);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fake::{fake_service, Call};
+    use super::super::{VideoDataType, VideoFrameInfo};
+    use super::*;
+
+    #[test]
+    fn merges_requests_and_checks_the_next_frame() {
+        let (fake, service) = fake_service();
+        let conn = service.create_connection().unwrap();
+        let id = conn.conn_id();
+        let rx = conn.watch_key_frame_requests();
+
+        fake.key_frame_gen_req(id, 7, VideoStreamQuality::HIGH.into());
+        fake.key_frame_gen_req(id, 8, VideoStreamQuality::HIGH.into());
+        fake.key_frame_gen_req(id, 8, VideoStreamQuality::LOW.into());
+        fake.key_frame_gen_req(id, 8, 9);
+        let reqs: Vec<_> = rx.try_iter().collect();
+        assert_eq!(
+            reqs,
+            [
+                KeyFrameRequest {
+                    conn_id: id,
+                    uid: 7,
+                    quality: VideoStreamQuality::HIGH
+                },
+                KeyFrameRequest {
+                    conn_id: id,
+                    uid: 8,
+                    quality: VideoStreamQuality::LOW
+                },
+            ]
+        );
+
+        let delta = VideoFrameInfo::new(VideoDataType::H264).frame_type(VideoFrameType::DELTA);
+        let key = delta.frame_type(VideoFrameType::KEY);
+        conn.send_video_data(b"p", &delta).unwrap();
+        // only the first frame after a request is checked
+        conn.send_video_data(b"p", &delta).unwrap();
+        conn.set_key_frame_window(Duration::ZERO);
+        fake.key_frame_gen_req(id, 7, VideoStreamQuality::HIGH.into());
+        conn.send_video_data(b"i", &key).unwrap();
+        conn.send_video_data(b"p", &delta).unwrap();
+        // the LOW request is still waiting, the HIGH frames don't count for it
+        let low = delta.stream_type(VideoStreamQuality::LOW);
+        conn.send_video_data(b"p", &low).unwrap();
+        assert_eq!(
+            conn.key_frame_stats(),
+            KeyFrameStats {
+                requested: 3,
+                merged: 1,
+                not_key: 2
+            }
+        );
+
+        conn.set_key_frame_window(DEFAULT_KEY_FRAME_WINDOW);
+        // a failed request isn't one to merge into
+        fake.fail("request_video_key_frame", -1);
+        assert!(conn.request_key_frame(7, VideoStreamQuality::HIGH).is_err());
+        fake.clear_failures();
+        fake.take_calls();
+        conn.request_key_frame(7, VideoStreamQuality::HIGH).unwrap();
+        conn.request_key_frame(7, VideoStreamQuality::HIGH).unwrap();
+        conn.request_key_frame(8, VideoStreamQuality::HIGH).unwrap();
+        assert_eq!(
+            fake.take_calls(),
+            [
+                Call::RequestVideoKeyFrame {
+                    conn_id: id,
+                    remote_uid: 7,
+                    stream_type: 0
+                },
+                Call::RequestVideoKeyFrame {
+                    conn_id: id,
+                    remote_uid: 8,
+                    stream_type: 0
+                },
+            ]
+        );
+    }
+}
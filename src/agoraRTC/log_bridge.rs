@@ -0,0 +1,140 @@
+//! Forwards SDK log lines to the `log` crate.
+//!
+//! The C side (`csrc/log_bridge.c`) formats each line, since Rust can't
+//! implement the variadic `log_printf`, and calls [`sink`] with the result.
+use super::super::ffi::size_t;
+use super::{LogConfig, LogLevel, LogPrintf};
+use lazy_static::lazy_static;
+use std::os::raw::{c_char, c_int};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Once, RwLock};
+
+/// Target used unless `LogConfig::forward_to_log` is given another one.
+pub const DEFAULT_TARGET: &str = "agora_sdk";
+
+extern "C" {
+    fn agora_rs_set_log_sink(sink: Option<unsafe extern "C" fn(*const c_char, size_t)>);
+    fn agora_rs_log_printf(fmt: *const c_char, ...) -> c_int;
+}
+
+lazy_static! {
+    static ref TARGET: RwLock<String> = RwLock::new(DEFAULT_TARGET.to_owned());
+}
+
+// the SDK doesn't tell us the level of a line, so use the one it is set to
+static LEVEL: AtomicU32 = AtomicU32::new(LogLevel::DEFAULT as u32);
+static INSTALL: Once = Once::new();
+
+impl From<LogLevel> for log::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::EMERG | LogLevel::ALERT | LogLevel::CRIT | LogLevel::ERROR => {
+                log::Level::Error
+            }
+            LogLevel::WARNING => log::Level::Warn,
+            LogLevel::DEFAULT | LogLevel::NOTICE | LogLevel::INFO => log::Level::Info,
+            LogLevel::DEBUG => log::Level::Debug,
+        }
+    }
+}
+
+pub(crate) fn set_level(level: LogLevel) {
+    LEVEL.store(level.into(), Ordering::Relaxed);
+}
+
+fn level() -> log::Level {
+    let l: LogLevel = num_traits::FromPrimitive::from_u32(LEVEL.load(Ordering::Relaxed))
+        .unwrap_or(LogLevel::DEFAULT);
+    l.into()
+}
+
+fn set_target(target: &str) {
+    let mut t = TARGET.write().unwrap_or_else(|e| e.into_inner());
+    *t = target.to_owned();
+}
+
+/// Emit one SDK line. Trailing newlines are dropped, `log` adds its own.
+fn emit(line: &str) {
+    let level = level();
+    let target = TARGET.read().unwrap_or_else(|e| e.into_inner());
+    if log::log_enabled!(target: target.as_str(), level) {
+        log::log!(target: target.as_str(), level, "{}", line.trim_end());
+    }
+}
+
+/// Called by `agora_rs_log_printf` with the formatted line.
+unsafe extern "C" fn sink(line: *const c_char, len: size_t) {
+    if line.is_null() {
+        return;
+    }
+    let bytes = std::slice::from_raw_parts(line as *const u8, len as usize);
+    // never unwind into the SDK
+    let _ = std::panic::catch_unwind(|| emit(&String::from_utf8_lossy(bytes)));
+}
+
+/// The `log_printf` which ends up in [`sink`].
+pub fn log_printf() -> LogPrintf {
+    INSTALL.call_once(|| unsafe { agora_rs_set_log_sink(Some(sink)) });
+    agora_rs_log_printf
+}
+
+impl LogConfig {
+    /// Send SDK logs to the `log` crate under `target` (e.g. [`DEFAULT_TARGET`])
+    /// instead of only writing them to files in `log_path`.
+    /// Lines are logged at the level mapped from `log_level`, kept in sync by `set_log_level`.
+    pub fn forward_to_log(mut self, target: &str) -> Self {
+        set_target(target);
+        set_level(self.log_level);
+        self.log_printf = Some(log_printf());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::sync::Mutex;
+
+    struct Capture(Mutex<Vec<String>>);
+
+    impl log::Log for Capture {
+        fn enabled(&self, m: &log::Metadata) -> bool {
+            m.target() == "agora_test"
+        }
+        fn log(&self, r: &log::Record) {
+            if self.enabled(r.metadata()) {
+                let line = format!("{} {}", r.level(), r.args());
+                self.0.lock().unwrap().push(line);
+            }
+        }
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+    #[test]
+    fn printf_reaches_sink() {
+        let cfg = LogConfig {
+            log_disable: false,
+            log_disable_desensitize: true,
+            log_level: LogLevel::WARNING,
+            log_path: String::new(),
+            log_tag: None,
+            log_printf: None,
+        }
+        .forward_to_log("agora_test");
+        log::set_logger(&CAPTURE).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+        assert_eq!(level(), log::Level::Warn);
+        let printf = cfg.log_printf.unwrap();
+        let fmt = CString::new("conn %u: %s\n").unwrap();
+        let arg = CString::new("x".repeat(600)).unwrap();
+        // longer than the stack buffer of the shim
+        let n = unsafe { printf(fmt.as_ptr(), 7 as std::os::raw::c_uint, arg.as_ptr()) };
+        assert_eq!(n, 609);
+        let lines = CAPTURE.0.lock().unwrap();
+        assert_eq!(*lines, vec![format!("WARN conn 7: {}", "x".repeat(600))]);
+        set_target(DEFAULT_TARGET);
+    }
+}
@@ -5,14 +5,67 @@ use super::utils::*;
 use log::warn;
 use num_derive::FromPrimitive;
 use num_enum::IntoPrimitive;
-use std::ffi::{c_void, CStr, CString};
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
 use std::option::Option;
-use std::ptr::{null, null_mut};
+use std::ptr::null;
+use std::sync::Arc;
+
+#[cfg(feature = "access-token")]
+pub mod access_token;
+pub mod annexb;
+pub mod backend;
+pub mod bitrate;
+pub mod connection;
+pub mod error;
+#[cfg(feature = "async")]
+pub mod events;
+#[cfg(any(test, feature = "fake"))]
+pub mod fake;
+pub mod handler;
+pub mod keyframe;
+#[cfg(feature = "log-bridge")]
+pub mod log_bridge;
+#[cfg(all(feature = "netlink", target_os = "linux"))]
+pub mod netlink;
+pub mod publisher;
+pub mod rate_limit;
+pub mod recorder;
+pub mod roster;
+pub mod rtm;
+pub mod service;
+pub mod state;
+pub mod subscription;
+pub mod token;
+pub use annexb::{nal_units, AccessUnit, AnnexBParser};
+pub use backend::{default_backend, RtcBackend};
+pub use bitrate::{EncoderRateController, RateSmoother, SmoothingConfig, SmoothingRateController};
+pub use connection::Connection;
+pub use error::{AgoraError, LicenseError, RtmError, SdkError};
+#[cfg(feature = "async")]
+pub use events::{Event, EventStream};
+#[cfg(any(test, feature = "fake"))]
+pub use fake::{Call, FakeBackend};
+pub use handler::{DefaultHandler, EventHandler};
+pub use keyframe::{KeyFrameRequest, KeyFrameStats, DEFAULT_KEY_FRAME_WINDOW};
+#[cfg(all(feature = "netlink", target_os = "linux"))]
+pub use netlink::NetlinkWatcher;
+pub use publisher::{Clock, FileOptions, FilePublisher, MediaKind, MediaSender, SystemClock};
+pub use rate_limit::{Coalescer, OverflowPolicy, QueueStats};
+pub use recorder::RecorderOptions;
+pub use roster::{RemoteUser, RosterChange, UserOfflineReason};
+pub use rtm::{DefaultRtmHandler, RtmClient, RtmEvent, RtmHandler, RtmRateLimit, RtmSend};
+pub use service::{RtcService, CONNECTION_ID_ALL, CONNECTION_ID_INVALID};
+pub use state::{ConnectionState, ReconnectPolicy, StateChange};
+pub use subscription::SubscribePolicy;
+#[cfg(feature = "async")]
+pub use token::AsyncTokenProvider;
+pub use token::{TokenProvider, TokenRetry};
 
 // https://zhuanlan.zhihu.com/p/148369298
 pub use super::utils::{err_2_result, err_2_reason};
 
-#[derive(Copy, Clone, FromPrimitive, IntoPrimitive)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
 #[repr(u32)]
 pub enum VideoDataType {
     YUV420 = 0,
@@ -22,7 +75,7 @@ pub enum VideoDataType {
     GENERIC_JPEG = 20,
 }
 
-#[derive(Copy, Clone, FromPrimitive, IntoPrimitive)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
 #[repr(u32)]
 pub enum VideoFrameType {
     AUTO = 0,
@@ -30,7 +83,7 @@ pub enum VideoFrameType {
     DELTA = 4,
 }
 
-#[derive(Copy, Clone, FromPrimitive, IntoPrimitive)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
 #[repr(u32)]
 pub enum VideoFrameRate {
     FPS_1 = 1,
@@ -43,13 +96,103 @@ pub enum VideoFrameRate {
     FPS_60 = 60,
 }
 
-#[derive(Copy, Clone, FromPrimitive, IntoPrimitive)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
 #[repr(u32)]
 pub enum VideoStreamQuality {
     HIGH = 0,
     LOW = 1,
 }
 
+/// `video_orientation_e`, clockwise
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
+#[repr(u32)]
+pub enum VideoOrientation {
+    DEG_0 = 0,
+    DEG_90 = 1,
+    DEG_180 = 2,
+    DEG_270 = 3,
+}
+
+/// What `send_video_data` says about a frame, `video_frame_info_t` with the enums above.
+///
+/// ```
+/// use agora_rtsa_rs::agoraRTC::*;
+/// let info = VideoFrameInfo::new(VideoDataType::H264)
+///     .frame_type(VideoFrameType::KEY)
+///     .frame_rate(VideoFrameRate::FPS_30);
+/// assert_eq!(info.stream_type, VideoStreamQuality::HIGH);
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct VideoFrameInfo {
+    pub data_type: VideoDataType,
+    pub stream_type: VideoStreamQuality,
+    pub frame_type: VideoFrameType,
+    /// `None` has the SDK use the real timestamps instead of `frame_per_sec`
+    pub frame_rate: Option<VideoFrameRate>,
+    pub rotation: VideoOrientation,
+}
+
+impl VideoFrameInfo {
+    /// High stream, frame type detected by the SDK, real timestamps, not rotated.
+    pub fn new(data_type: VideoDataType) -> Self {
+        VideoFrameInfo {
+            data_type,
+            stream_type: VideoStreamQuality::HIGH,
+            frame_type: VideoFrameType::AUTO,
+            frame_rate: None,
+            rotation: VideoOrientation::DEG_0,
+        }
+    }
+
+    pub fn stream_type(mut self, stream_type: VideoStreamQuality) -> Self {
+        self.stream_type = stream_type;
+        self
+    }
+
+    pub fn frame_type(mut self, frame_type: VideoFrameType) -> Self {
+        self.frame_type = frame_type;
+        self
+    }
+
+    pub fn frame_rate(mut self, frame_rate: VideoFrameRate) -> Self {
+        self.frame_rate = Some(frame_rate);
+        self
+    }
+
+    pub fn rotation(mut self, rotation: VideoOrientation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+}
+
+impl From<VideoFrameInfo> for video_frame_info_t {
+    fn from(info: VideoFrameInfo) -> Self {
+        video_frame_info_t {
+            data_type: info.data_type.into(),
+            stream_type: info.stream_type.into(),
+            frame_type: info.frame_type.into(),
+            frame_rate: info.frame_rate.map_or(0, |r| r.into()),
+            rotation: info.rotation.into(),
+        }
+    }
+}
+
+/// `audio_data_type_e`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
+#[repr(u32)]
+pub enum AudioDataType {
+    OPUS = 1,
+    OPUSFB = 2,
+    PCMA = 3,
+    PCMU = 4,
+    G722 = 5,
+    AACLC = 8,
+    HEAAC = 9,
+    /// 16 bit interleaved samples. audio codec should be enabled in `audio_codec_option_t`
+    PCM = 100,
+    GENERIC = 253,
+}
+
 #[derive(Copy, Clone, FromPrimitive, IntoPrimitive)]
 #[repr(u32)]
 pub enum AreaCode {
@@ -68,6 +211,18 @@ pub enum AreaCode {
     GLOB = (0xFFFFFFFF),
 }
 
+/// `network_event_type_e`, see `RtcService::notify_network_event`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
+#[repr(u32)]
+pub enum NetworkEvent {
+    /// No network anymore
+    DOWN = 0,
+    /// Network is back
+    UP = 1,
+    /// Switched to another network, e.g. from Ethernet to Wi-Fi
+    CHANGE = 2,
+}
+
 
 #[derive(Copy, Clone, FromPrimitive, IntoPrimitive)]
 #[repr(u32)]
@@ -92,84 +247,143 @@ pub struct RtcServiceOption {
 }
 
 impl RtcServiceOption {
-    pub fn new(log_path: &str, prod_id:&str, license:&str, log_level: LogLevel) -> Self {
+    pub fn new(log_path: &str, prod_id:&str, license:&str, log_level: LogLevel) -> Result<Self, AgoraError> {
         let log_cfg = LogConfig {
             log_disable: false,
             log_disable_desensitize: true,
             log_level,
             log_path: log_path.to_owned(),
+            log_tag: None,
+            log_printf: None,
         };
         
-        RtcServiceOption {
-            product_id: prod_id.to_c_string().unwrap(),
-            license: license.to_c_string().unwrap(),
+        Ok(RtcServiceOption {
+            product_id: prod_id.to_c_string()?,
+            license: license.to_c_string()?,
             area_code: AreaCode::CN,
-            log_cfg: log_cfg,
-        }
+            log_cfg,
+        })
     }
 }
+/// `log_printf` of `log_config_t`.
+/// It's variadic, so it has to be implemented in C (or be `printf` itself).
+pub type LogPrintf = unsafe extern "C" fn(fmt: *const c_char, ...) -> c_int;
+
 #[derive(Clone)]
 pub struct LogConfig {
     pub log_disable: bool,
     pub log_disable_desensitize: bool,
     pub log_level: LogLevel,
     pub log_path: String,
+    /// `None` lets the SDK use its default tag
+    pub log_tag: Option<String>,
+    /// If set the SDK hands every log line to it
+    pub log_printf: Option<LogPrintf>,
+}
+
+/// `log_config_t` together with the strings it points to.
+/// The raw struct is only valid while this is alive.
+pub(crate) struct OwnedLogConfig {
+    log_disable: bool,
+    log_disable_desensitize: bool,
+    log_level: u32,
+    log_path: CString,
+    log_tag: Option<CString>,
+    log_printf: Option<LogPrintf>,
 }
 
-impl From<LogConfig> for log_config_t {
-    fn from(config: LogConfig) -> Self {
-        let cs = CString::new(config.log_path).unwrap();
-        let ptr = CString::into_raw(cs);
-        let tag = CString::new("").unwrap();
-        let tag_ptr = CString::into_raw(tag);
+impl OwnedLogConfig {
+    /// Borrows the strings, so don't let it outlive `self`.
+    pub(crate) fn to_raw(&self) -> log_config_t {
         log_config_t {
+            log_disable: self.log_disable,
+            log_disable_desensitize: self.log_disable_desensitize,
+            log_level: self.log_level,
+            log_path: self.log_path.as_ptr(),
+            log_tag: self.log_tag.as_ref().map_or(null(), |t| t.as_ptr()),
+            log_printf: self.log_printf,
+        }
+    }
+}
+
+impl TryFrom<&LogConfig> for OwnedLogConfig {
+    type Error = AgoraError;
+    fn try_from(config: &LogConfig) -> Result<Self, AgoraError> {
+        Ok(OwnedLogConfig {
             log_disable: config.log_disable,
             log_disable_desensitize: config.log_disable_desensitize,
             log_level: config.log_level.into(),
-            log_path: ptr,
-            log_tag: tag_ptr,
-            log_printf: None,
-        }
+            log_path: config.log_path.to_c_string()?,
+            log_tag: config.log_tag.as_deref().map(CString::new).transpose()?,
+            log_printf: config.log_printf,
+        })
     }
 }
 
-// the trait `Copy` may not be implemented for this type; the type has a destructor
-// not sure if it's correct
-impl Drop for log_config_t {
-    fn drop(&mut self) {
-        let cs = unsafe { CString::from_raw(self.log_path as *mut u8) };
-        drop(cs);
-    }
+/// `rtc_service_option_t` and the log config strings it points to.
+pub(crate) struct OwnedServiceOption {
+    pub(crate) raw: rtc_service_option_t,
+    _log_cfg: OwnedLogConfig,
 }
 
-impl From<RtcServiceOption> for rtc_service_option_t {
-    fn from(opt: RtcServiceOption) -> Self {
-        let mut product_id: [u8; 64] = [0; 64];
-        let mut license_value: [u8; 33] = [0; 33];
-        product_id[..opt.product_id.to_bytes_with_nul().len()].copy_from_slice(opt.product_id.to_bytes_with_nul());
-        license_value[..opt.license.to_bytes_with_nul().len()].copy_from_slice(opt.license.to_bytes_with_nul());
-        rtc_service_option_t {
+impl TryFrom<&RtcServiceOption> for OwnedServiceOption {
+    type Error = AgoraError;
+    fn try_from(opt: &RtcServiceOption) -> Result<Self, AgoraError> {
+        let log_cfg = OwnedLogConfig::try_from(&opt.log_cfg)?;
+        let raw = rtc_service_option_t {
             area_code: opt.area_code.into(),
-            product_id: product_id,
-            log_cfg: opt.log_cfg.clone().into(),
-            license_value: license_value,
+            product_id: copy_to_c_array(&opt.product_id)?,
+            log_cfg: log_cfg.to_raw(),
+            license_value: copy_to_c_array(&opt.license)?,
             domain_limit: false,
-        }
+        };
+        Ok(OwnedServiceOption {
+            raw,
+            _log_cfg: log_cfg,
+        })
     }
 }
 
-/// Get SDK version
+/// Get SDK version, empty without the `ffi` feature
 /// ```
 /// extern crate agora_rtsa_rs;
 /// let v = agora_rtsa_rs::agoraRTC::get_version();
+/// # #[cfg(feature = "ffi")]
 /// assert_eq!(v, "1.8.0");
 /// ```
 pub fn get_version() -> String {
-    unsafe {
-        let pVersion = agora_rtc_get_version();
-        let version = CStr::from_ptr(pVersion);
-        version.to_str().unwrap().to_owned()
+    default_backend().get_version()
+}
+
+/// Change the SDK log level at runtime.
+/// With `log-bridge` the forwarded lines follow the new level too.
+pub fn set_log_level(level: LogLevel) -> Result<(), AgoraError> {
+    err_2_result(default_backend().set_log_level(level.into()))?;
+    #[cfg(feature = "log-bridge")]
+    log_bridge::set_level(level);
+    Ok(())
+}
+
+/// Log file rotation. `size_per_file` is in bytes, up to 10 MB (default 1 MB),
+/// `max_file_count` up to 100 (default 10). 0 for either turns file logging off.
+pub fn config_log(size_per_file: u32, max_file_count: u32) -> Result<(), AgoraError> {
+    const MAX_SIZE: u32 = 10 * 1024 * 1024;
+    const MAX_COUNT: u32 = 100;
+    if size_per_file > MAX_SIZE {
+        return Err(AgoraError::OutOfRange {
+            name: "size_per_file",
+            value: size_per_file as u64,
+            max: MAX_SIZE as u64,
+        });
+    }
+    if max_file_count > MAX_COUNT {
+        return Err(AgoraError::OutOfRange {
+            name: "max_file_count",
+            value: max_file_count as u64,
+            max: MAX_COUNT as u64,
+        });
     }
+    err_2_result(default_backend().config_log(size_per_file as i32, max_file_count as i32))
 }
 
 // See https://adventures.michaelfbryan.com/posts/rust-closures-in-ffi/
@@ -177,9 +391,16 @@ pub fn get_version() -> String {
 // https://doc.rust-lang.org/reference/expressions/operator-expr.html#type-cast-expressions
 // https://users.rust-lang.org/t/rust-and-c-interoperability-c-lambdas/67136/3
 // ** only for closures that do not capture (close over) any local variables
-impl agora_rtc_event_handler_t {
-    /// default impl of event_handler
-    pub fn new() -> Self {
+// so the callbacks are trampolines dispatching to `EventHandler` by conn_id
+/// Callback tables filled with this crate's trampolines.
+/// A trait since the tables are defined in `agora-rtsa-sys`.
+pub trait Trampolines {
+    fn new() -> Self;
+}
+
+impl Trampolines for agora_rtc_event_handler_t {
+    /// default impl of event_handler, see `callbacks.rs`
+    fn new() -> Self {
         agora_rtc_event_handler_t {
             on_join_channel_success: Some(on_join_channel_success),
             on_connection_lost: Some(on_connection_lost),
@@ -200,108 +421,184 @@ impl agora_rtc_event_handler_t {
     }
 }
 
-impl rtc_channel_options_t {
-    /// I don't need audio by default!
-    pub fn new() -> Self {
-        let codec_opt = audio_codec_option_t {
-            audio_codec_type: audio_codec_type_e_AUDIO_CODEC_DISABLED,
-            /// Pcm sample rate. Ignored if audio coded is diabled
-            pcm_sample_rate: 0,
-            /// Pcm channel number. Ignored if audio coded is diabled
-            pcm_channel_num: 0,
-        };
-        let audio_opt = rtc_audio_process_options_t{
-          enable_audio_process: false,
-          enable_aec: false,
-          enable_ns: false,
-          ref_data_from_sdk: false,
-          enable_dump_data: false,
-        };
-        rtc_channel_options_t {
-            auto_subscribe_audio: false,
-            auto_subscribe_video: false,
-            subscribe_local_user: false,
-            enable_audio_jitter_buffer: false,
-            enable_audio_mixer: false,
-            audio_codec_opt: codec_opt,
-            audio_process_opt: audio_opt,
-            enable_aut_encryption: false,
-        }
+/// Convenience wrapper of one [`RtcService`] with one [`Connection`].
+/// If you need more than one channel at a time use them directly.
+pub struct AgoraApp {
+    app_id: String,
+    handlers: agora_rtc_event_handler_t,
+    event_handler: Option<Arc<dyn EventHandler>>,
+    token_provider: Option<(Arc<dyn TokenProvider>, TokenRetry)>,
+    reconnect_policy: Option<ReconnectPolicy>,
+    default_video_info: Option<VideoFrameInfo>,
+    default_audio_type: Option<AudioDataType>,
+    // connection before service, fields drop in declaration order
+    conn: Option<Connection>,
+    service: Option<RtcService>,
+}
+
+/// Size in bytes of 10 ms of 16 bit PCM with the configured rate and channels.
+/// `None` if the codec is disabled or not configured.
+pub fn pcm_frame_bytes(opt: &audio_codec_option_t) -> Option<usize> {
+    if opt.audio_codec_type == audio_codec_type_e_AUDIO_CODEC_DISABLED
+        || opt.pcm_sample_rate <= 0
+        || opt.pcm_channel_num <= 0
+    {
+        return None;
     }
+    let per_10ms = opt.pcm_sample_rate as usize / 100;
+    Some(per_10ms * opt.pcm_channel_num as usize * std::mem::size_of::<i16>())
 }
 
-pub struct AgoraApp {
-    uid: u32,
-    conn_id: Option<u32>,
-    c_app_id: CString,
-    c_channel_name: CString,
-    c_app_token: CString,
-    /// I guess you can only join one channel at a time
-    is_joined: bool,
-    handlers: agora_rtc_event_handler_t,
-    service_option: Option<rtc_service_option_t>,
-    channel_option: Option<rtc_channel_options_t>,
-    default_video_info: Option<video_frame_info_t>,
+/// PCM frames are encoded by the SDK, so they have to be a whole number of
+/// 10 ms blocks of what `audio_codec_option_t` says.
+pub fn validate_pcm_frame(len: usize, opt: &audio_codec_option_t) -> Result<(), AgoraError> {
+    let frame_bytes = pcm_frame_bytes(opt).ok_or(AgoraError::PcmCodecDisabled)?;
+    if len == 0 || frame_bytes == 0 || !len.is_multiple_of(frame_bytes) {
+        return Err(AgoraError::PcmFrameSize { len, frame_bytes });
+    }
+    Ok(())
 }
 
 // https://stackoverflow.com/questions/41510424/most-idiomatic-way-to-create-a-default-struct
-use super::utils::ToCString;
 impl AgoraApp {
+    /// 0 before joining a channel
     pub fn uid(&self) -> u32 {
-        self.uid
+        self.conn.as_ref().map(|c| c.uid()).unwrap_or(0)
     }
     pub fn conn_id(&self) -> Option<u32> {
-        self.conn_id
+        self.conn.as_ref().map(|c| c.conn_id())
     }
     pub fn app_id(&self) -> &str {
-        self.c_app_id.to_str().unwrap()
+        &self.app_id
     }
-    /// I guess you can only join one channel at a time...for now
-    /// There's no necessary to join multiple channels at the same time
     pub fn is_joined(&self) -> bool {
-        self.is_joined
+        self.conn.as_ref().map(|c| c.is_joined()).unwrap_or(false)
     }
     /// using default handler
+    /// `app_id` is checked in `init`
     pub fn new(app_id: &str) -> Self {
         Self {
-            c_app_id: app_id.to_c_string().unwrap(),
-            c_channel_name: "".to_c_string().unwrap(),
-            c_app_token: "".to_c_string().unwrap(),
-            uid: 0,
-            conn_id: None,
-            is_joined: false,
+            app_id: app_id.to_owned(),
             handlers: agora_rtc_event_handler_t::new(),
-            service_option: None,
-            channel_option: None,
+            event_handler: None,
+            token_provider: None,
+            reconnect_policy: None,
             default_video_info: None,
+            default_audio_type: None,
+            conn: None,
+            service: None,
         }
     }
 
+    /// The underlying service, if `init` succeeded.
+    pub fn service(&self) -> Option<&RtcService> {
+        self.service.as_ref()
+    }
+
+    /// The underlying connection, if `create_connection` succeeded.
+    pub fn connection(&self) -> Option<&Connection> {
+        self.conn.as_ref()
+    }
+
+    /// Replace the raw C callbacks. Only needed if you want to bypass [`EventHandler`]
+    /// entirely, since the default ones dispatch to it.
+    /// Has no effect after `init`.
     pub fn set_handlers(&mut self, handlers: agora_rtc_event_handler_t) {
         self.handlers = handlers;
     }
 
-    // TODO: use a object wrapper
+    /// Route the events of this app's connection to `handler`.
+    /// Can be called before or after `create_connection`.
+    /// Events of a connection without handler go to [`DefaultHandler`], which only logs.
+    /// ```no_run
+    /// use agora_rtsa_rs::agoraRTC::{AgoraApp, EventHandler};
+    /// use std::sync::atomic::{AtomicU32, Ordering};
+    ///
+    /// #[derive(Default)]
+    /// struct Counter(AtomicU32);
+    /// impl EventHandler for Counter {
+    ///     fn on_user_joined(&self, _conn_id: u32, _uid: u32, _elapsed_ms: i32) {
+    ///         self.0.fetch_add(1, Ordering::Relaxed);
+    ///     }
+    /// }
+    ///
+    /// let mut app = AgoraApp::new("app_id");
+    /// app.set_event_handler(Counter::default());
+    /// ```
+    pub fn set_event_handler<H: EventHandler + 'static>(&mut self, handler: H) {
+        self.set_event_handler_arc(Arc::new(handler));
+    }
+
+    /// Same as `set_event_handler` but lets you keep a reference to the handler.
+    pub fn set_event_handler_arc(&mut self, handler: Arc<dyn EventHandler>) {
+        if let Some(conn) = &self.conn {
+            conn.set_event_handler_arc(handler.clone());
+        }
+        self.event_handler = Some(handler);
+    }
+
+    /// Keep the token fresh with `provider`, see `Connection::set_token_provider`.
+    /// Can be called before or after `create_connection`.
+    /// ```no_run
+    /// use agora_rtsa_rs::agoraRTC::{AgoraApp, AgoraError, TokenRetry};
+    /// use std::sync::Arc;
+    ///
+    /// fn fetch_token(channel: &str, uid: u32) -> Result<String, AgoraError> {
+    ///     // ask your token server
+    ///     Ok(format!("{}-{}", channel, uid))
+    /// }
+    ///
+    /// let mut app = AgoraApp::new("app_id");
+    /// app.set_token_provider(Arc::new(fetch_token), TokenRetry::default());
+    /// ```
+    pub fn set_token_provider(&mut self, provider: Arc<dyn TokenProvider>, retry: TokenRetry) {
+        if let Some(conn) = &self.conn {
+            conn.set_token_provider(provider.clone(), retry);
+        }
+        self.token_provider = Some((provider, retry));
+    }
+
+    /// See `Connection::set_reconnect_policy`. Can be called before or after `create_connection`.
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        if let Some(conn) = &self.conn {
+            conn.set_reconnect_policy(policy);
+        }
+        self.reconnect_policy = Some(policy);
+    }
+
+    /// `IDLE` without a connection.
+    pub fn state(&self) -> ConnectionState {
+        self.conn
+            .as_ref()
+            .map(|c| c.state())
+            .unwrap_or(ConnectionState::IDLE)
+    }
+
+    /// See `Connection::watch_state`.
+    pub fn watch_state(&self) -> Result<std::sync::mpsc::Receiver<StateChange>, AgoraError> {
+        Ok(self.conn()?.watch_state())
+    }
+
+    /// See `RtcService::notify_network_event`.
+    pub fn notify_network_event(&self, event: NetworkEvent) -> Result<(), AgoraError> {
+        self.service
+            .as_ref()
+            .ok_or(AgoraError::NoService)?
+            .notify_network_event(event)
+    }
+
+    /// Apply a new token right away, see `Connection::renew_token`.
+    pub fn renew_token(&self, token: &str) -> Result<(), AgoraError> {
+        self.conn()?.renew_token(token)
+    }
+
     // https://stackoverflow.com/questions/70840454/passing-a-safe-rust-function-pointer-to-c
     // https://adventures.michaelfbryan.com/posts/rust-closures-in-ffi/
     /// init SDK
-    /// * `app_id` - You have to use CString to handle null-terminated string since rust String/&str is not zero terminated
-    ///    `app_id` should out live the AgoraSDK
-    pub fn init(&mut self, option: RtcServiceOption) -> Result<(), ErrorCode> {
-        self.service_option = Some(option.into());
-        // opt_t should keeps living during the programming running (Static lifetime?)
-        // I will use move for safty
-        let opt: &mut rtc_service_option_t =
-            self.service_option.as_mut().expect("No Service Option");
-        let code = unsafe {
-            agora_rtc_init(
-                self.c_app_id.as_ptr(),
-                std::ptr::addr_of!(self.handlers),
-                opt,
-            )
-        };
-
-        err_2_result(code)
+    pub fn init(&mut self, option: RtcServiceOption) -> Result<(), AgoraError> {
+        let service = RtcService::init_with_handlers(&self.app_id, self.handlers, option)?;
+        self.service = Some(service);
+        Ok(())
     }
 
     pub fn join_channel(
@@ -310,99 +607,100 @@ impl AgoraApp {
         uid: Option<u32>,
         token: &str,
         option: rtc_channel_options_t,
-    ) -> Result<(), ErrorCode> {
-        self.channel_option = Some(option);
-        self.c_channel_name = channel_name.to_c_string().unwrap();
-        self.c_app_token = token.to_c_string().unwrap();
-        self.uid = uid.unwrap_or(0);
-        // https://doc.rust-lang.org/std/primitive.pointer.html
-        // reference will be coerced to *const c_char
-        let opt = self.channel_option.as_mut().expect("No Channel Option");
-
-        let code = unsafe {
-            // I believe this function won't modify token or options
-            agora_rtc_join_channel(
-                self.conn_id.expect("no connection id"),
-                self.c_channel_name.as_ptr(),
-                uid.unwrap_or(0),
-                self.c_app_token.as_ptr(),
-                opt,
-            )
-        };
-        let res = err_2_result(code);
-        if Ok(()) == res {
-            self.is_joined = true;
-        }
-        res
+    ) -> Result<(), AgoraError> {
+        self.conn()?.join_channel(channel_name, uid, token, option)
+    }
+
+    /// See `Connection::join_channel_async`.
+    #[cfg(feature = "async")]
+    pub async fn join_channel_async(
+        &self,
+        channel_name: &str,
+        uid: Option<u32>,
+        token: &str,
+        option: rtc_channel_options_t,
+        timeout: std::time::Duration,
+    ) -> Result<u32, AgoraError> {
+        self.conn()?
+            .join_channel_async(channel_name, uid, token, option, timeout)
+            .await
+    }
+
+    /// See `Connection::events`.
+    #[cfg(feature = "async")]
+    pub fn events(&self) -> Result<EventStream, AgoraError> {
+        Ok(self.conn()?.events())
     }
 
-    // TODO: better error handling
-    // https://stackoverflow.com/questions/53183070/what-is-the-defacto-bytes-type-in-rust
     pub fn send_video_data(
         &mut self,
         buf: &[u8],
-        info: &video_frame_info_t,
-    ) -> Result<(), ErrorCode> {
-        let ptr = buf.as_ptr();
-        let len: size_t = buf
-            .len()
-            .try_into()
-            .expect("error when converting buffer len in send_video_data");
-        // don't think this function will actually mutate info
-        // Trust me
-        let p_i: *mut video_frame_info_t = std::ptr::addr_of!(*info) as *mut video_frame_info_t;
-        let code = unsafe {
-            agora_rtc_send_video_data(
-                self.conn_id.expect("No connection id"),
-                std::mem::transmute(ptr),
-                len,
-                p_i,
-            )
-        };
-        err_2_result(code)
+        info: &VideoFrameInfo,
+    ) -> Result<(), AgoraError> {
+        self.conn()?.send_video_data(buf, info)
     }
 
-    pub fn set_video_info(&mut self, info: video_frame_info_t) {
+    pub fn set_video_info(&mut self, info: VideoFrameInfo) {
         self.default_video_info = Some(info);
     }
 
-    pub fn send_video_data_default(&mut self, buf: &[u8]) -> Result<(), ErrorCode> {
-        let i = self.default_video_info.expect("No Video Info");
+    pub fn send_video_data_default(&mut self, buf: &[u8]) -> Result<(), AgoraError> {
+        let i = self.default_video_info.ok_or(AgoraError::NoVideoInfo)?;
         self.send_video_data(buf, &i)
     }
 
+    /// Send an audio frame of `data_type`.
+    /// For `AudioDataType::PCM` the frame is checked against the `audio_codec_opt`
+    /// passed to `join_channel`, see `validate_pcm_frame`.
+    pub fn send_audio_data(
+        &mut self,
+        buf: &[u8],
+        data_type: AudioDataType,
+    ) -> Result<(), AgoraError> {
+        self.conn()?.send_audio_data(buf, data_type)
+    }
+
+    pub fn set_audio_type(&mut self, data_type: AudioDataType) {
+        self.default_audio_type = Some(data_type);
+    }
+
+    pub fn send_audio_data_default(&mut self, buf: &[u8]) -> Result<(), AgoraError> {
+        let t = self.default_audio_type.ok_or(AgoraError::NoAudioType)?;
+        self.send_audio_data(buf, t)
+    }
+
     /// deinit SDK.
     /// Don't call this function directly. unless you know what you are doing.
-    /// Try to use drop instead.
-    pub unsafe fn deinit() -> Result<(), i32> {
-        let code = agora_rtc_fini();
-        err_2_result(code)
+    /// It bypasses the reference counting of [`RtcService`]. Try to use drop instead.
+    pub unsafe fn deinit() -> Result<(), AgoraError> {
+        err_2_result(default_backend().fini())
     }
 
     /// set connection id and return the new one
-    pub fn create_connection(&mut self) -> Result<u32, ErrorCode> {
-        // https://doc.rust-lang.org/reference/expressions/operator-expr.html#type-cast-expressions
-        let heap = Box::new(0);
-        let ptr = Box::into_raw(heap);
-        let code = unsafe { agora_rtc_create_connection(ptr) };
-        unsafe {
-            match code {
-                0 => {
-                    self.conn_id = Some(*ptr);
-                    Result::Ok(*ptr)
-                }
-                _ => Result::Err(code),
-            }
+    pub fn create_connection(&mut self) -> Result<u32, AgoraError> {
+        let service = self.service.as_ref().ok_or(AgoraError::NoService)?;
+        let conn = service.create_connection()?;
+        if let Some(h) = &self.event_handler {
+            conn.set_event_handler_arc(h.clone());
         }
+        if let Some((p, retry)) = &self.token_provider {
+            conn.set_token_provider(p.clone(), *retry);
+        }
+        if let Some(policy) = self.reconnect_policy {
+            conn.set_reconnect_policy(policy);
+        }
+        let id = conn.conn_id();
+        // replacing an old one destroys it
+        self.conn = Some(conn);
+        Ok(id)
     }
 
-    pub fn destroy_connection(&mut self) -> Result<(), ErrorCode> {
-        match self.conn_id {
-            Some(id) => {
-                let code = unsafe { agora_rtc_destroy_connection(id) };
-                self.conn_id = None;
-                self.is_joined = false;
-                err_2_result(code)
+    /// leaves the channel if needed
+    pub fn destroy_connection(&mut self) -> Result<(), AgoraError> {
+        match self.conn.take() {
+            Some(conn) => {
+                drop(conn);
+                Result::Ok(())
             }
             None => {
                 warn!("No connection id");
@@ -415,18 +713,14 @@ impl AgoraApp {
     /// useful when you want to deinit SDK but don't want to/can't drop the object. (like behind a Arc/Mutex)
     /// Not recommended to use this function directly. 
     pub fn fini(&mut self) {
-        let _ = self.leave_channel();
         let _ = self.destroy_connection();
-        let _ = unsafe { AgoraApp::deinit() };
+        // the SDK is released once no one else holds the service
+        self.service = None;
     }
 
-    pub fn leave_channel(&mut self) -> Result<(), ErrorCode> {
-        match self.conn_id {
-            Some(id) => {
-                let code = unsafe { agora_rtc_leave_channel(id) };
-                self.is_joined = false;
-                err_2_result(code)
-            }
+    pub fn leave_channel(&mut self) -> Result<(), AgoraError> {
+        match &self.conn {
+            Some(conn) => conn.leave_channel(),
             None => {
                 warn!("No connection id");
                 Result::Ok(())
@@ -434,11 +728,90 @@ impl AgoraApp {
         }
     }
 
-    pub fn mute_local_audio(&self, is_muted: bool) -> Result<(), ErrorCode> {
-        let code = unsafe {
-            agora_rtc_mute_local_audio(self.conn_id.expect("No connection id"), is_muted)
-        };
-        err_2_result(code)
+    pub fn mute_local_audio(&self, is_muted: bool) -> Result<(), AgoraError> {
+        self.conn()?.mute_local_audio(is_muted)
+    }
+
+    pub fn mute_local_video(&self, is_muted: bool) -> Result<(), AgoraError> {
+        self.conn()?.mute_local_video(is_muted)
+    }
+
+    pub fn mute_remote_audio(&self, uid: u32, is_muted: bool) -> Result<(), AgoraError> {
+        self.conn()?.mute_remote_audio(uid, is_muted)
+    }
+
+    pub fn mute_remote_video(&self, uid: u32, is_muted: bool) -> Result<(), AgoraError> {
+        self.conn()?.mute_remote_video(uid, is_muted)
+    }
+
+    /// See `Connection::remote_users`.
+    pub fn remote_users(&self) -> Result<Vec<RemoteUser>, AgoraError> {
+        Ok(self.conn()?.remote_users())
+    }
+
+    /// See `Connection::watch_users`.
+    pub fn watch_users(&self) -> Result<std::sync::mpsc::Receiver<RosterChange>, AgoraError> {
+        Ok(self.conn()?.watch_users())
+    }
+
+    /// See `Connection::set_audio_subscription`.
+    pub fn set_audio_subscription(&self, policy: SubscribePolicy) -> Result<(), AgoraError> {
+        self.conn()?.set_audio_subscription(policy)
+    }
+
+    /// See `Connection::set_video_subscription`.
+    pub fn set_video_subscription(&self, policy: SubscribePolicy) -> Result<(), AgoraError> {
+        self.conn()?.set_video_subscription(policy)
+    }
+
+    /// See `Connection::request_key_frame`.
+    pub fn request_key_frame(
+        &self,
+        uid: u32,
+        quality: VideoStreamQuality,
+    ) -> Result<(), AgoraError> {
+        self.conn()?.request_key_frame(uid, quality)
+    }
+
+    /// See `Connection::watch_key_frame_requests`.
+    pub fn watch_key_frame_requests(
+        &self,
+    ) -> Result<std::sync::mpsc::Receiver<KeyFrameRequest>, AgoraError> {
+        Ok(self.conn()?.watch_key_frame_requests())
+    }
+
+    /// See `Connection::set_bwe_params`.
+    pub fn set_bwe_params(
+        &self,
+        min_bps: u32,
+        max_bps: u32,
+        start_bps: u32,
+    ) -> Result<(), AgoraError> {
+        self.conn()?.set_bwe_params(min_bps, max_bps, start_bps)
+    }
+
+    /// See `Connection::set_rate_controller`.
+    pub fn set_rate_controller(
+        &self,
+        controller: std::sync::Arc<dyn EncoderRateController>,
+    ) -> Result<(), AgoraError> {
+        self.conn()?.set_rate_controller(controller);
+        Ok(())
+    }
+
+    /// See `Connection::start_recording`.
+    pub fn start_recording(&self, options: RecorderOptions) -> Result<(), AgoraError> {
+        self.conn()?.start_recording(options)
+    }
+
+    /// See `Connection::stop_recording`.
+    pub fn stop_recording(&self) -> Result<(), AgoraError> {
+        self.conn()?.stop_recording();
+        Ok(())
+    }
+
+    fn conn(&self) -> Result<&Connection, AgoraError> {
+        self.conn.as_ref().ok_or(AgoraError::NoConnection)
     }
 }
 impl Drop for AgoraApp {
@@ -451,3 +824,82 @@ impl Drop for AgoraApp {
 // just a decalration and no implementation (marker traits)
 unsafe impl Send for AgoraApp {}
 unsafe impl Sync for AgoraApp {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    fn codec(rate: i32, channels: i32) -> audio_codec_option_t {
+        audio_codec_option_t {
+            audio_codec_type: audio_codec_type_e_AUDIO_CODEC_TYPE_OPUS,
+            pcm_sample_rate: rate,
+            pcm_channel_num: channels,
+        }
+    }
+
+    #[test]
+    fn pcm_frame_validation() {
+        // 16k mono, 10ms = 320 bytes, 20ms = 640
+        assert_eq!(pcm_frame_bytes(&codec(16000, 1)), Some(320));
+        assert!(validate_pcm_frame(640, &codec(16000, 1)).is_ok());
+        // 48k stereo, 10ms = 1920 bytes
+        assert!(validate_pcm_frame(1920, &codec(48000, 2)).is_ok());
+        assert_eq!(
+            validate_pcm_frame(640, &codec(48000, 2)),
+            Err(AgoraError::PcmFrameSize {
+                len: 640,
+                frame_bytes: 1920
+            })
+        );
+        assert_eq!(
+            validate_pcm_frame(0, &codec(16000, 1)),
+            Err(AgoraError::PcmFrameSize {
+                len: 0,
+                frame_bytes: 320
+            })
+        );
+        let mut disabled = codec(16000, 1);
+        disabled.audio_codec_type = audio_codec_type_e_AUDIO_CODEC_DISABLED;
+        assert_eq!(
+            validate_pcm_frame(640, &disabled),
+            Err(AgoraError::PcmCodecDisabled)
+        );
+    }
+    #[test]
+    fn video_frame_info_to_ffi() {
+        let info = VideoFrameInfo::new(VideoDataType::H265)
+            .stream_type(VideoStreamQuality::LOW)
+            .rotation(VideoOrientation::DEG_90);
+        let raw = video_frame_info_t::from(info);
+        assert_eq!(raw.data_type, video_data_type_e_VIDEO_DATA_TYPE_H265);
+        assert_eq!(raw.stream_type, video_stream_type_e_VIDEO_STREAM_LOW);
+        assert_eq!(raw.frame_type, video_frame_type_e_VIDEO_FRAME_AUTO_DETECT);
+        assert_eq!(raw.frame_rate, 0);
+        assert_eq!(raw.rotation, video_orientation_e_VIDEO_ORIENTATION_90);
+        let raw = video_frame_info_t::from(info.frame_rate(VideoFrameRate::FPS_15));
+        assert_eq!(raw.frame_rate, video_frame_rate_e_VIDEO_FRAME_RATE_FPS_15);
+    }
+    #[test]
+    fn owned_service_option() {
+        let mut opt = RtcServiceOption::new("/tmp/agora", "dev", "", LogLevel::INFO).unwrap();
+        let owned = OwnedServiceOption::try_from(&opt).unwrap();
+        let log = &owned.raw.log_cfg;
+        assert_eq!(unsafe { CStr::from_ptr(log.log_path) }.to_str(), Ok("/tmp/agora"));
+        assert!(log.log_tag.is_null());
+        assert_eq!(log.log_level, u32::from(LogLevel::INFO));
+
+        opt.log_cfg.log_tag = Some("rtsa".to_owned());
+        let owned = OwnedServiceOption::try_from(&opt).unwrap();
+        // moving it around doesn't invalidate the pointers
+        let boxed = Box::new(owned);
+        let tag = unsafe { CStr::from_ptr(boxed.raw.log_cfg.log_tag) };
+        assert_eq!(tag.to_str(), Ok("rtsa"));
+
+        opt.log_cfg.log_tag = Some("a\0b".to_owned());
+        assert!(matches!(
+            OwnedServiceOption::try_from(&opt),
+            Err(AgoraError::InvalidString(_))
+        ));
+    }
+}
@@ -0,0 +1,423 @@
+//! Telling the SDK about network changes on Linux, from rtnetlink.
+//!
+//! Listens to `RTMGRP_LINK` and the IPv4/IPv6 address groups. A link counts when it's
+//! up, running and not a loopback:
+//! - the first one coming up is `UP`, the last one going away is `DOWN`,
+//! - any other link coming or going, or an address of a counted link changing,
+//!   is `CHANGE`.
+use super::service::RtcService;
+use super::NetworkEvent;
+use log::{error, info, warn};
+use std::collections::HashSet;
+use std::io;
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How often the thread checks whether it should stop
+const POLL: Duration = Duration::from_millis(200);
+
+const NLMSG_HDR_LEN: usize = 16;
+const IFINFOMSG_LEN: usize = 16;
+const IFADDRMSG_LEN: usize = 8;
+
+/// What one netlink message means to us.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Msg {
+    Link {
+        index: i32,
+        usable: bool,
+    },
+    LinkGone {
+        index: i32,
+    },
+    Addr {
+        index: i32,
+    },
+    /// end of a dump
+    Done,
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_ne_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_ne_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+/// The messages of one `recv`, the rest is skipped.
+fn parse(mut buf: &[u8]) -> Vec<Msg> {
+    let mut msgs = Vec::new();
+    while buf.len() >= NLMSG_HDR_LEN {
+        let len = u32_at(buf, 0) as usize;
+        if len < NLMSG_HDR_LEN || len > buf.len() {
+            break;
+        }
+        let ty = u16_at(buf, 4);
+        let body = &buf[NLMSG_HDR_LEN..len];
+        let msg = match ty {
+            libc::RTM_NEWLINK if body.len() >= IFINFOMSG_LEN => {
+                let flags = u32_at(body, 8) as i32;
+                let want = libc::IFF_UP | libc::IFF_RUNNING;
+                Some(Msg::Link {
+                    index: u32_at(body, 4) as i32,
+                    usable: flags & want == want && flags & libc::IFF_LOOPBACK == 0,
+                })
+            }
+            libc::RTM_DELLINK if body.len() >= IFINFOMSG_LEN => Some(Msg::LinkGone {
+                index: u32_at(body, 4) as i32,
+            }),
+            libc::RTM_NEWADDR | libc::RTM_DELADDR if body.len() >= IFADDRMSG_LEN => {
+                Some(Msg::Addr {
+                    index: u32_at(body, 4) as i32,
+                })
+            }
+            t if t == libc::NLMSG_DONE as u16 => Some(Msg::Done),
+            _ => None,
+        };
+        msgs.extend(msg);
+        // messages are 4 byte aligned
+        let next = (len + 3) & !3;
+        buf = &buf[next.min(buf.len())..];
+    }
+    msgs
+}
+
+/// The links which count, and what a change of them means.
+#[derive(Default)]
+struct Links {
+    usable: HashSet<i32>,
+}
+
+impl Links {
+    fn apply(&mut self, msg: Msg) -> Option<NetworkEvent> {
+        let before = !self.usable.is_empty();
+        let changed = match msg {
+            Msg::Link {
+                index,
+                usable: true,
+            } => self.usable.insert(index),
+            Msg::Link { index, .. } | Msg::LinkGone { index } => self.usable.remove(&index),
+            Msg::Addr { index } => {
+                return self.usable.contains(&index).then_some(NetworkEvent::CHANGE)
+            }
+            Msg::Done => false,
+        };
+        let after = !self.usable.is_empty();
+        match (before, after) {
+            (false, true) => Some(NetworkEvent::UP),
+            (true, false) => Some(NetworkEvent::DOWN),
+            (true, true) if changed => Some(NetworkEvent::CHANGE),
+            _ => None,
+        }
+    }
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn open_socket() -> io::Result<OwnedFd> {
+    unsafe {
+        let fd = check(libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        ))?;
+        let fd = OwnedFd::from_raw_fd(fd);
+        let mut addr: libc::sockaddr_nl = std::mem::zeroed();
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups =
+            (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+        check(libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        ))?;
+        let timeout = libc::timeval {
+            tv_sec: 0,
+            tv_usec: POLL.as_micros() as libc::suseconds_t,
+        };
+        check(libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const libc::timeval as *const libc::c_void,
+            size_of::<libc::timeval>() as libc::socklen_t,
+        ))?;
+        Ok(fd)
+    }
+}
+
+/// Ask for every link, the answer ends with `NLMSG_DONE`.
+fn request_links(fd: &OwnedFd) -> io::Result<()> {
+    let mut req = [0u8; NLMSG_HDR_LEN + IFINFOMSG_LEN];
+    let len = req.len() as u32;
+    req[0..4].copy_from_slice(&len.to_ne_bytes());
+    req[4..6].copy_from_slice(&libc::RTM_GETLINK.to_ne_bytes());
+    let flags = (libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16;
+    req[6..8].copy_from_slice(&flags.to_ne_bytes());
+    // ifi_family
+    req[NLMSG_HDR_LEN] = libc::AF_UNSPEC as u8;
+    let sent = unsafe {
+        libc::send(
+            fd.as_raw_fd(),
+            req.as_ptr() as *const libc::c_void,
+            req.len(),
+            0,
+        )
+    };
+    check(sent as libc::c_int).map(|_| ())
+}
+
+/// `Ok(None)` on timeout.
+fn recv(fd: &OwnedFd, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    let n = unsafe {
+        libc::recv(
+            fd.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            0,
+        )
+    };
+    if n >= 0 {
+        return Ok(Some(n as usize));
+    }
+    let e = io::Error::last_os_error();
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted => {
+            Ok(None)
+        }
+        _ => Err(e),
+    }
+}
+
+fn run(fd: OwnedFd, links: &mut Links, service: &RtcService, stop: &AtomicBool) {
+    let mut buf = vec![0u8; 32 * 1024];
+    while !stop.load(Ordering::Acquire) {
+        let n = match recv(&fd, &mut buf) {
+            Ok(Some(n)) => n,
+            Ok(None) => continue,
+            Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                // we fell behind and lost messages, whatever they were
+                warn!("netlink overrun, reporting a network change");
+                notify(service, NetworkEvent::CHANGE);
+                continue;
+            }
+            Err(e) => {
+                error!(
+                    "netlink recv failed, network changes won't be notified: {}",
+                    e
+                );
+                return;
+            }
+        };
+        for msg in parse(&buf[..n]) {
+            if let Some(ev) = links.apply(msg) {
+                notify(service, ev);
+            }
+        }
+    }
+}
+
+fn notify(service: &RtcService, ev: NetworkEvent) {
+    info!("network {:?}", ev);
+    if let Err(e) = service.notify_network_event(ev) {
+        warn!("notify_network_event({:?}) failed: {}", ev, e);
+    }
+}
+
+/// Calls `notify_network_event` on link and address changes, from a thread of its own.
+/// Stops when dropped.
+pub struct NetlinkWatcher {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NetlinkWatcher {
+    /// Links up at this point are known, only what changes afterwards is notified.
+    pub fn start(service: RtcService) -> io::Result<Self> {
+        let fd = open_socket()?;
+        request_links(&fd)?;
+        let mut links = Links::default();
+        let mut buf = vec![0u8; 32 * 1024];
+        'dump: loop {
+            let n = match recv(&fd, &mut buf)? {
+                Some(n) => n,
+                None => return Err(io::ErrorKind::TimedOut.into()),
+            };
+            for msg in parse(&buf[..n]) {
+                if msg == Msg::Done {
+                    break 'dump;
+                }
+                links.apply(msg);
+            }
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let s2 = stop.clone();
+        let thread = std::thread::Builder::new()
+            .name("agora-netlink".to_owned())
+            .spawn(move || run(fd, &mut links, &service, &s2))?;
+        Ok(NetlinkWatcher {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for NetlinkWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(t) = self.thread.take() {
+            if t.join().is_err() {
+                warn!("netlink thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fake::{fake_service, Call};
+    use super::*;
+    use std::process::Command;
+    use std::time::Instant;
+
+    fn nlmsg(ty: u16, body: &[u8]) -> Vec<u8> {
+        let len = NLMSG_HDR_LEN + body.len();
+        let mut m = Vec::new();
+        m.extend_from_slice(&(len as u32).to_ne_bytes());
+        m.extend_from_slice(&ty.to_ne_bytes());
+        m.extend_from_slice(&[0; 10]);
+        m.extend_from_slice(body);
+        m.resize((len + 3) & !3, 0);
+        m
+    }
+
+    fn link(ty: u16, index: i32, flags: i32) -> Vec<u8> {
+        let mut body = vec![0u8; 4];
+        body.extend_from_slice(&index.to_ne_bytes());
+        body.extend_from_slice(&(flags as u32).to_ne_bytes());
+        body.extend_from_slice(&[0; 4]);
+        nlmsg(ty, &body)
+    }
+
+    fn addr(ty: u16, index: i32) -> Vec<u8> {
+        let mut body = vec![2, 24, 0, 0];
+        body.extend_from_slice(&index.to_ne_bytes());
+        nlmsg(ty, &body)
+    }
+
+    #[test]
+    fn parses_and_tracks_links() {
+        let running = libc::IFF_UP | libc::IFF_RUNNING;
+        let mut buf = Vec::new();
+        buf.extend(link(libc::RTM_NEWLINK, 1, running | libc::IFF_LOOPBACK));
+        buf.extend(link(libc::RTM_NEWLINK, 2, running));
+        buf.extend(addr(libc::RTM_NEWADDR, 2));
+        buf.extend(addr(libc::RTM_NEWADDR, 1));
+        buf.extend(link(libc::RTM_NEWLINK, 3, running));
+        buf.extend(link(libc::RTM_NEWLINK, 2, libc::IFF_UP));
+        buf.extend(link(libc::RTM_DELLINK, 3, 0));
+        buf.extend(nlmsg(libc::NLMSG_DONE as u16, &[0; 4]));
+        // truncated, ignored
+        buf.extend(&link(libc::RTM_NEWLINK, 4, running)[..20]);
+        let msgs = parse(&buf);
+        assert_eq!(msgs.len(), 8);
+        assert_eq!(
+            msgs[1],
+            Msg::Link {
+                index: 2,
+                usable: true
+            }
+        );
+        assert_eq!(
+            msgs[0],
+            Msg::Link {
+                index: 1,
+                usable: false
+            }
+        );
+
+        let mut links = Links::default();
+        let events: Vec<_> = msgs.into_iter().map(|m| links.apply(m)).collect();
+        use NetworkEvent::*;
+        assert_eq!(
+            events,
+            [
+                None,
+                Some(UP),
+                Some(CHANGE),
+                // loopback doesn't count
+                None,
+                Some(CHANGE),
+                Some(CHANGE),
+                Some(DOWN),
+                None
+            ]
+        );
+    }
+
+    fn ip(args: &str) {
+        let ok = Command::new("ip")
+            .args(args.split(' '))
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false);
+        assert!(ok, "ip {} failed", args);
+    }
+
+    /// Needs CAP_NET_ADMIN, run it in a network namespace of its own:
+    /// `unshare -rn cargo test --features netlink -- --ignored netlink`
+    #[test]
+    #[ignore]
+    fn notifies_on_veth_changes() {
+        let (fake, service) = fake_service();
+        // a veth end only runs once both are up
+        ip("link add agtest0 type veth peer name agtest1");
+        let watcher = NetlinkWatcher::start(service.clone()).unwrap();
+        let events = || -> Vec<u32> {
+            fake.calls()
+                .into_iter()
+                .filter_map(|c| match c {
+                    Call::NotifyNetworkEvent(e) => Some(e),
+                    _ => None,
+                })
+                .collect()
+        };
+        let wait_until = |last: NetworkEvent, n: usize| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                let ev = events();
+                if (ev.len() >= n && ev.last() == Some(&last.into())) || Instant::now() > deadline {
+                    return ev;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        ip("link set agtest0 up");
+        ip("link set agtest1 up");
+        let ev = wait_until(NetworkEvent::CHANGE, 2);
+        assert_eq!(
+            ev[..2],
+            [NetworkEvent::UP.into(), NetworkEvent::CHANGE.into()]
+        );
+        ip("addr add 10.99.0.1/24 dev agtest0");
+        let ev = wait_until(NetworkEvent::CHANGE, ev.len() + 1);
+        assert_eq!(ev.last(), Some(&NetworkEvent::CHANGE.into()));
+        ip("link del agtest0");
+        let ev = wait_until(NetworkEvent::DOWN, ev.len() + 1);
+        assert_eq!(ev.last(), Some(&NetworkEvent::DOWN.into()));
+        drop(watcher);
+    }
+}
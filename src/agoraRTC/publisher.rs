@@ -0,0 +1,604 @@
+//! Publish recorded media from disk, e.g. for soak tests without a camera.
+//!
+//! A [`FilePublisher`] loads one elementary stream, cuts it into frames and sends
+//! them paced as recorded: video at the `VideoFrameRate` of [`FileOptions`], audio
+//! by the duration of each packet (20 ms of PCM/G.711 each).
+//! Sending and time go through [`MediaSender`] and [`Clock`] so both can be faked.
+use super::annexb::AnnexBParser;
+use super::{
+    AgoraApp, AgoraError, AudioDataType, Connection, VideoDataType, VideoFrameInfo, VideoFrameRate,
+    VideoFrameType,
+};
+use log::warn;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// What a [`FilePublisher`] sends the frames with.
+pub trait MediaSender {
+    fn send_video(&mut self, data: &[u8], info: &VideoFrameInfo) -> Result<(), AgoraError>;
+    fn send_audio(&mut self, data: &[u8], data_type: AudioDataType) -> Result<(), AgoraError>;
+}
+
+impl MediaSender for AgoraApp {
+    fn send_video(&mut self, data: &[u8], info: &VideoFrameInfo) -> Result<(), AgoraError> {
+        self.send_video_data(data, info)
+    }
+    fn send_audio(&mut self, data: &[u8], data_type: AudioDataType) -> Result<(), AgoraError> {
+        self.send_audio_data(data, data_type)
+    }
+}
+
+impl MediaSender for Connection {
+    fn send_video(&mut self, data: &[u8], info: &VideoFrameInfo) -> Result<(), AgoraError> {
+        self.send_video_data(data, info)
+    }
+    fn send_audio(&mut self, data: &[u8], data_type: AudioDataType) -> Result<(), AgoraError> {
+        self.send_audio_data(data, data_type)
+    }
+}
+
+/// Time as the publisher sees it.
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep_until(&mut self, deadline: Instant);
+}
+
+/// The real one.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    fn sleep_until(&mut self, deadline: Instant) {
+        let now = Instant::now();
+        if deadline > now {
+            std::thread::sleep(deadline - now);
+        }
+    }
+}
+
+/// The kinds of file a [`FilePublisher`] reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    /// Annex-B
+    H264,
+    /// Annex-B
+    H265,
+    /// Opus in Ogg, one logical stream. Page CRCs aren't checked
+    OGG_OPUS,
+    /// 16 bit interleaved, rate and channels from [`FileOptions`]
+    PCM,
+    /// 8 kHz mono A-law
+    G711A,
+    /// 8 kHz mono μ-law
+    G711U,
+    /// One picture per file
+    JPEG,
+}
+
+impl MediaKind {
+    /// From the extension, `None` if it's none of the above.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        Some(match ext.as_str() {
+            "h264" | "264" => MediaKind::H264,
+            "h265" | "265" | "hevc" => MediaKind::H265,
+            "opus" | "ogg" => MediaKind::OGG_OPUS,
+            "pcm" => MediaKind::PCM,
+            "g711a" | "pcma" => MediaKind::G711A,
+            "g711u" | "pcmu" => MediaKind::G711U,
+            "jpg" | "jpeg" => MediaKind::JPEG,
+            _ => return None,
+        })
+    }
+}
+
+/// What the files don't say.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileOptions {
+    /// Pace of H.264/H.265 and JPEG frames, also passed in their `VideoFrameInfo`
+    pub frame_rate: VideoFrameRate,
+    pub pcm_sample_rate: u32,
+    pub pcm_channels: u32,
+}
+
+impl Default for FileOptions {
+    fn default() -> Self {
+        FileOptions {
+            frame_rate: VideoFrameRate::FPS_30,
+            pcm_sample_rate: 16000,
+            pcm_channels: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Payload {
+    Video(VideoFrameInfo),
+    Audio(AudioDataType),
+}
+
+#[derive(Debug, Clone)]
+struct Frame {
+    /// from the start of the file
+    pts: Duration,
+    data: Vec<u8>,
+    payload: Payload,
+}
+
+impl Frame {
+    /// Where playing can start from
+    fn is_key(&self) -> bool {
+        match self.payload {
+            Payload::Video(info) => info.frame_type == VideoFrameType::KEY,
+            Payload::Audio(_) => true,
+        }
+    }
+}
+
+fn media_err<E: std::fmt::Display>(e: E) -> AgoraError {
+    AgoraError::MediaFile(e.to_string())
+}
+
+/// Put frames of the given durations one after the other.
+fn timeline(frames: Vec<(Vec<u8>, Payload, Duration)>) -> (Vec<Frame>, Duration) {
+    let mut pts = Duration::ZERO;
+    let frames = frames
+        .into_iter()
+        .map(|(data, payload, duration)| {
+            let frame = Frame { pts, data, payload };
+            pts += duration;
+            frame
+        })
+        .collect();
+    (frames, pts)
+}
+
+/// The packets of an Ogg stream, put together from their segments.
+fn ogg_packets(data: &[u8]) -> Result<Vec<Vec<u8>>, AgoraError> {
+    let truncated = || media_err("truncated Ogg page");
+    let mut packets = Vec::new();
+    let mut packet = Vec::new();
+    let mut at = 0;
+    while at < data.len() {
+        let header = data.get(at..at + 27).ok_or_else(truncated)?;
+        if !header.starts_with(b"OggS") {
+            return Err(media_err("not an Ogg page"));
+        }
+        let segments = header[26] as usize;
+        let lacing = data
+            .get(at + 27..at + 27 + segments)
+            .ok_or_else(truncated)?;
+        let mut body = at + 27 + segments;
+        for len in lacing.iter().map(|l| *l as usize) {
+            packet.extend_from_slice(data.get(body..body + len).ok_or_else(truncated)?);
+            body += len;
+            // 255 goes on in the next segment, maybe on the next page
+            if len < 255 {
+                packets.push(std::mem::take(&mut packet));
+            }
+        }
+        at = body;
+    }
+    Ok(packets)
+}
+
+/// From the TOC byte, RFC 6716 section 3.1.
+fn opus_duration(packet: &[u8]) -> Duration {
+    let toc = match packet.first() {
+        Some(toc) => *toc,
+        None => return Duration::ZERO,
+    };
+    let config = (toc >> 3) as usize;
+    let frame_us = match config {
+        0..=11 => [10_000, 20_000, 40_000, 60_000][config % 4],
+        12..=15 => [10_000, 20_000][config % 2],
+        _ => [2_500, 5_000, 10_000, 20_000][config % 4],
+    };
+    let frames = match toc & 3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => packet.get(1).map_or(1, |c| (c & 0x3f) as u64),
+    };
+    Duration::from_micros(frame_us * frames)
+}
+
+fn frame_interval(rate: VideoFrameRate) -> Duration {
+    Duration::from_secs(1) / u32::from(rate)
+}
+
+/// Sends the frames of one file in real time (as the [`Clock`] says), see the module docs.
+///
+/// ```no_run
+/// use agora_rtsa_rs::agoraRTC::*;
+/// use std::sync::atomic::AtomicBool;
+/// # fn publish(app: &mut AgoraApp) -> Result<(), AgoraError> {
+/// let mut video = FilePublisher::open("soak.h264", FileOptions::default())?.looping(true);
+/// let stop = AtomicBool::new(false);
+/// video.run(app, &mut SystemClock, &stop);
+/// # Ok(())
+/// # }
+/// ```
+pub struct FilePublisher {
+    frames: Vec<Frame>,
+    duration: Duration,
+    looping: bool,
+    /// next frame to send
+    pos: usize,
+    /// when the frame at the pts was (or would have been) sent
+    origin: Option<(Instant, Duration)>,
+}
+
+impl FilePublisher {
+    /// The kind is told by the extension, a directory is a sequence of `.jpg`/`.jpeg`
+    /// files in the order of their names.
+    pub fn open<P: AsRef<Path>>(path: P, options: FileOptions) -> Result<Self, AgoraError> {
+        let path = path.as_ref();
+        if path.is_dir() {
+            let mut files: Vec<_> = std::fs::read_dir(path)
+                .map_err(media_err)?
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| MediaKind::from_path(p) == Some(MediaKind::JPEG))
+                .collect();
+            files.sort();
+            let pictures = files
+                .iter()
+                .map(std::fs::read)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(media_err)?;
+            return Self::from_frames(Self::jpeg_frames(pictures, options));
+        }
+        let kind = MediaKind::from_path(path)
+            .ok_or_else(|| media_err(format!("unknown kind of file {}", path.display())))?;
+        let data = std::fs::read(path).map_err(media_err)?;
+        Self::from_bytes(kind, &data, options)
+    }
+
+    /// Same as `open`, for a file already read.
+    pub fn from_bytes(
+        kind: MediaKind,
+        data: &[u8],
+        options: FileOptions,
+    ) -> Result<Self, AgoraError> {
+        let frames = match kind {
+            MediaKind::H264 | MediaKind::H265 => {
+                let data_type = match kind {
+                    MediaKind::H264 => VideoDataType::H264,
+                    _ => VideoDataType::H265,
+                };
+                let mut parser = AnnexBParser::new(data_type)?;
+                let mut aus = parser.push(data);
+                aus.append(&mut parser.flush());
+                let interval = frame_interval(options.frame_rate);
+                aus.into_iter()
+                    .map(|au| {
+                        let info = au.info().frame_rate(options.frame_rate);
+                        (au.data, Payload::Video(info), interval)
+                    })
+                    .collect()
+            }
+            MediaKind::OGG_OPUS => {
+                let packets = ogg_packets(data)?;
+                if !packets.first().is_some_and(|p| p.starts_with(b"OpusHead")) {
+                    return Err(media_err("not an Ogg Opus stream"));
+                }
+                packets
+                    .into_iter()
+                    .filter(|p| !p.starts_with(b"OpusHead") && !p.starts_with(b"OpusTags"))
+                    .map(|p| {
+                        let duration = opus_duration(&p);
+                        (p, Payload::Audio(AudioDataType::OPUS), duration)
+                    })
+                    .collect()
+            }
+            MediaKind::PCM => {
+                let rate = options.pcm_sample_rate;
+                let frame_bytes = (rate / 50 * options.pcm_channels * 2) as usize;
+                if frame_bytes == 0 {
+                    return Err(media_err("no PCM sample rate or channels"));
+                }
+                // a partial frame at the end would be refused by the SDK
+                data.chunks_exact(frame_bytes)
+                    .map(|c| {
+                        let payload = Payload::Audio(AudioDataType::PCM);
+                        (c.to_vec(), payload, Duration::from_millis(20))
+                    })
+                    .collect()
+            }
+            MediaKind::G711A | MediaKind::G711U => {
+                let data_type = match kind {
+                    MediaKind::G711A => AudioDataType::PCMA,
+                    _ => AudioDataType::PCMU,
+                };
+                // 8 samples a millisecond, a byte each
+                data.chunks(160)
+                    .map(|c| {
+                        let duration = Duration::from_micros(c.len() as u64 * 125);
+                        (c.to_vec(), Payload::Audio(data_type), duration)
+                    })
+                    .collect()
+            }
+            MediaKind::JPEG => Self::jpeg_frames(vec![data.to_vec()], options),
+        };
+        Self::from_frames(frames)
+    }
+
+    fn jpeg_frames(
+        pictures: Vec<Vec<u8>>,
+        options: FileOptions,
+    ) -> Vec<(Vec<u8>, Payload, Duration)> {
+        let info = VideoFrameInfo::new(VideoDataType::GENERIC_JPEG)
+            .frame_type(VideoFrameType::KEY)
+            .frame_rate(options.frame_rate);
+        let interval = frame_interval(options.frame_rate);
+        pictures
+            .into_iter()
+            .map(|p| (p, Payload::Video(info), interval))
+            .collect()
+    }
+
+    fn from_frames(frames: Vec<(Vec<u8>, Payload, Duration)>) -> Result<Self, AgoraError> {
+        if frames.is_empty() {
+            return Err(media_err("no frames"));
+        }
+        let (frames, duration) = timeline(frames);
+        Ok(FilePublisher {
+            frames,
+            duration,
+            looping: false,
+            pos: 0,
+            origin: None,
+        })
+    }
+
+    /// Start over at the end, without a gap.
+    pub fn looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Of the next frame to send, `duration` once at the end.
+    pub fn position(&self) -> Duration {
+        self.frames.get(self.pos).map_or(self.duration, |f| f.pts)
+    }
+
+    /// Go on from `to`, wrapped around when looping. Video starts again from
+    /// the key frame before it. The next frame is sent right away.
+    pub fn seek(&mut self, to: Duration) {
+        let to = if self.looping {
+            Duration::from_nanos((to.as_nanos() % self.duration.as_nanos().max(1)) as u64)
+        } else {
+            to
+        };
+        let mut pos = self.frames.partition_point(|f| f.pts < to);
+        if pos < self.frames.len() {
+            while pos > 0 && !self.frames[pos].is_key() {
+                pos -= 1;
+            }
+        }
+        self.pos = pos;
+        self.origin = None;
+    }
+
+    /// Wait until the next frame is due and send it.
+    /// `false` once at the end, which never comes when looping.
+    pub fn step<S, C>(&mut self, sender: &mut S, clock: &mut C) -> Result<bool, AgoraError>
+    where
+        S: MediaSender + ?Sized,
+        C: Clock + ?Sized,
+    {
+        let frame = match self.frames.get(self.pos) {
+            Some(f) => f,
+            None => return Ok(false),
+        };
+        let (at, pts) = *self.origin.get_or_insert((clock.now(), frame.pts));
+        let due = at + frame.pts.saturating_sub(pts);
+        if due > clock.now() {
+            clock.sleep_until(due);
+        }
+        let sent = match &frame.payload {
+            Payload::Video(info) => sender.send_video(&frame.data, info),
+            Payload::Audio(data_type) => sender.send_audio(&frame.data, *data_type),
+        };
+        self.pos += 1;
+        if self.pos == self.frames.len() && self.looping {
+            self.pos = 0;
+            self.origin = Some((at + self.duration.saturating_sub(pts), Duration::ZERO));
+        }
+        sent.map(|_| true)
+    }
+
+    /// `step` until the end or `stop` is set. Frames the sender refuses are skipped
+    /// with a warning, e.g. while the channel is not joined yet.
+    pub fn run<S, C>(&mut self, sender: &mut S, clock: &mut C, stop: &AtomicBool)
+    where
+        S: MediaSender + ?Sized,
+        C: Clock + ?Sized,
+    {
+        while !stop.load(Ordering::Relaxed) {
+            // `step` moves on to the next frame, failed or not
+            let at = self.position();
+            match self.step(sender, clock) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => warn!("failed to send the frame at {:?}, {}", at, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct VirtualClock(Instant);
+
+    impl Clock for VirtualClock {
+        fn now(&self) -> Instant {
+            self.0
+        }
+        fn sleep_until(&mut self, deadline: Instant) {
+            self.0 = self.0.max(deadline);
+        }
+    }
+
+    /// The frames sent, with their frame or audio type
+    #[derive(Default)]
+    struct Recorder(Vec<(Vec<u8>, String)>);
+
+    impl MediaSender for Recorder {
+        fn send_video(&mut self, data: &[u8], info: &VideoFrameInfo) -> Result<(), AgoraError> {
+            self.0
+                .push((data.to_vec(), format!("{:?}", info.frame_type)));
+            Ok(())
+        }
+        fn send_audio(&mut self, data: &[u8], data_type: AudioDataType) -> Result<(), AgoraError> {
+            self.0.push((data.to_vec(), format!("{:?}", data_type)));
+            Ok(())
+        }
+    }
+
+    /// Up to `n` steps on a virtual clock, with the ms each frame was sent at
+    fn step_all(p: &mut FilePublisher, n: usize) -> Vec<(u128, Vec<u8>, String)> {
+        let t0 = Instant::now();
+        let mut clock = VirtualClock(t0);
+        let mut r = Recorder::default();
+        let mut times = Vec::new();
+        for _ in 0..n {
+            if !p.step(&mut r, &mut clock).unwrap() {
+                break;
+            }
+            times.push((clock.now() - t0).as_millis());
+        }
+        times
+            .into_iter()
+            .zip(r.0)
+            .map(|(t, (data, what))| (t, data, what))
+            .collect()
+    }
+
+    fn h264(pictures: &[&[u8]]) -> Vec<u8> {
+        pictures
+            .iter()
+            .flat_map(|p| [0, 0, 0, 1].iter().chain(p.iter()).copied())
+            .collect()
+    }
+
+    const IDR: &[u8] = &[0x65, 0x88, 0x84];
+    const P: &[u8] = &[0x41, 0x9a, 0x02];
+
+    #[test]
+    fn paces_loops_and_seeks_video() {
+        let options = FileOptions {
+            frame_rate: VideoFrameRate::FPS_10,
+            ..Default::default()
+        };
+        let stream = h264(&[IDR, P, P, IDR, P]);
+        let mut p = FilePublisher::from_bytes(MediaKind::H264, &stream, options).unwrap();
+        assert_eq!(p.duration(), Duration::from_millis(500));
+        let sent = step_all(&mut p, 10);
+        let times: Vec<_> = sent.iter().map(|s| s.0).collect();
+        assert_eq!(times, [0, 100, 200, 300, 400]);
+        assert_eq!(sent[3].2, "KEY");
+        assert_eq!(sent[4].2, "DELTA");
+
+        let mut p = p.looping(true);
+        p.seek(Duration::ZERO);
+        let sent = step_all(&mut p, 7);
+        let times: Vec<_> = sent.iter().map(|s| s.0).collect();
+        // no gap when starting over
+        assert_eq!(times, [0, 100, 200, 300, 400, 500, 600]);
+        assert_eq!(sent[5].2, "KEY");
+
+        p.seek(Duration::from_millis(250));
+        assert_eq!(p.position(), Duration::from_millis(300));
+        p.seek(Duration::from_millis(1250));
+        assert_eq!(p.position(), Duration::from_millis(300));
+        // back to the key frame
+        p.seek(Duration::from_millis(150));
+        assert_eq!(p.position(), Duration::ZERO);
+        let sent = step_all(&mut p, 2);
+        assert_eq!((sent[0].0, sent[1].0), (0, 100));
+    }
+
+    fn ogg_page(packets: &[&[u8]]) -> Vec<u8> {
+        let mut lacing = Vec::new();
+        for p in packets {
+            lacing.extend(std::iter::repeat_n(255, p.len() / 255));
+            lacing.push((p.len() % 255) as u8);
+        }
+        let mut page = b"OggS".to_vec();
+        page.extend_from_slice(&[0; 22]);
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(&lacing);
+        packets.iter().for_each(|p| page.extend_from_slice(p));
+        page
+    }
+
+    #[test]
+    fn reads_audio_files() {
+        let mut ogg = ogg_page(&[b"OpusHead\x01\x01"]);
+        ogg.extend(ogg_page(&[b"OpusTags"]));
+        // 20 ms hybrid over two segments, then two 20 ms CELT frames
+        let long = [&[0x78][..], &[1; 299]].concat();
+        ogg.extend(ogg_page(&[&long, &[0xf9, 2, 3]]));
+        ogg.extend(ogg_page(&[&[0x78, 4]]));
+        let mut p =
+            FilePublisher::from_bytes(MediaKind::OGG_OPUS, &ogg, FileOptions::default()).unwrap();
+        let sent = step_all(&mut p, 10);
+        let got: Vec<_> = sent.iter().map(|s| (s.0, s.1.len())).collect();
+        assert_eq!(got, [(0, 300), (20, 3), (60, 2)]);
+        assert_eq!(sent[0].2, "OPUS");
+        assert!(
+            FilePublisher::from_bytes(MediaKind::OGG_OPUS, b"OggS", Default::default()).is_err()
+        );
+
+        // 1 s of 16 kHz mono and a bit
+        let pcm = vec![0u8; 32000 + 100];
+        let mut p = FilePublisher::from_bytes(MediaKind::PCM, &pcm, Default::default()).unwrap();
+        let mut clock = VirtualClock(Instant::now());
+        let t0 = clock.now();
+        let mut r = Recorder::default();
+        p.run(&mut r, &mut clock, &AtomicBool::new(false));
+        assert_eq!(r.0.len(), 50);
+        assert!(r.0.iter().all(|s| s.0.len() == 640 && s.1 == "PCM"));
+        assert_eq!(clock.now() - t0, Duration::from_millis(980));
+
+        let mut p =
+            FilePublisher::from_bytes(MediaKind::G711U, &[0xff; 400], Default::default()).unwrap();
+        assert_eq!(p.duration(), Duration::from_millis(50));
+        let sent = step_all(&mut p, 10);
+        let got: Vec<_> = sent.iter().map(|s| (s.0, s.1.len())).collect();
+        assert_eq!(got, [(0, 160), (20, 160), (40, 80)]);
+        assert_eq!(sent[0].2, "PCMU");
+    }
+
+    #[test]
+    fn opens_files_and_jpeg_sequences() {
+        let dir = std::env::temp_dir().join(format!("agora-publisher-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, data) in [("b.jpg", &[2u8][..]), ("a.jpg", &[1]), ("c.txt", &[3])] {
+            std::fs::write(dir.join(name), data).unwrap();
+        }
+        std::fs::write(dir.join("soak.h265"), h264(&[&[0x26, 0x01, 0xaf]])).unwrap();
+
+        let mut p = FilePublisher::open(&dir, FileOptions::default()).unwrap();
+        let sent = step_all(&mut p, 10);
+        let got: Vec<_> = sent.iter().map(|s| (s.0, s.1[0], s.2.as_str())).collect();
+        assert_eq!(got, [(0, 1, "KEY"), (33, 2, "KEY")]);
+        let p = FilePublisher::open(dir.join("soak.h265"), FileOptions::default()).unwrap();
+        assert_eq!(p.duration(), Duration::from_secs(1) / 30);
+        assert!(matches!(
+            FilePublisher::open(dir.join("c.txt"), FileOptions::default()),
+            Err(AgoraError::MediaFile(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+//! Pacing for calls the SDK rate limits, e.g. `agora_rtc_send_rtm_data` (60 qps).
+//!
+//! Everything takes `now` explicitly so it can be driven by a fake clock.
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Classic token bucket. Starts full.
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// `rate_per_sec` tokens are added per second, at most `burst` are kept.
+    pub fn new(rate_per_sec: u32, burst: u32, now: Instant) -> Self {
+        let capacity = burst.max(1) as f64;
+        TokenBucket {
+            rate: rate_per_sec.max(1) as f64,
+            capacity,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        // `now` before `last` just adds nothing
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = self.last.max(now);
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long until a token is available, zero if one is.
+    pub fn wait_time(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+        }
+    }
+}
+
+/// Merges repeats of the same request: only the first one per key in `window` gets through.
+pub struct Coalescer<K> {
+    window: Duration,
+    last: BTreeMap<K, Instant>,
+}
+
+impl<K: Ord> Coalescer<K> {
+    pub fn new(window: Duration) -> Self {
+        Coalescer {
+            window,
+            last: BTreeMap::new(),
+        }
+    }
+
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    /// `false` if `key` already got through less than `window` before `now`.
+    pub fn admit(&mut self, key: K, now: Instant) -> bool {
+        let window = self.window;
+        // nothing older than the window matters anymore
+        self.last
+            .retain(|_, t| now.saturating_duration_since(*t) < window);
+        if self.last.contains_key(&key) {
+            return false;
+        }
+        self.last.insert(key, now);
+        true
+    }
+
+    /// Let `key` through again, when what it admitted didn't happen after all.
+    pub fn forget(&mut self, key: &K) {
+        self.last.remove(key);
+    }
+}
+
+/// What to do when the queue is full.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// drop the oldest queued message to make room
+    DROP_OLDEST,
+    /// drop the message being sent
+    DROP_NEWEST,
+    /// wait for room
+    BLOCK,
+    /// return an error
+    ERROR,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// messages waiting for a token right now
+    pub depth: usize,
+    /// highest `depth` seen
+    pub peak_depth: usize,
+    /// messages taken out of the queue to be sent
+    pub sent: u64,
+    /// messages dropped by `DROP_OLDEST`/`DROP_NEWEST`
+    pub dropped: u64,
+    /// messages refused by `ERROR`
+    pub rejected: u64,
+}
+
+pub(crate) enum Push<T> {
+    Queued,
+    /// queued, but this one (the oldest or the new one) was dropped
+    Dropped(T),
+    /// no room, `BLOCK` or `ERROR`. The caller gets the item back.
+    Full(T),
+}
+
+pub(crate) enum Pop<T> {
+    Ready(T),
+    /// nothing may be sent for this long
+    Wait(Duration),
+    Empty,
+}
+
+/// A bounded FIFO released at the pace of a [`TokenBucket`].
+pub(crate) struct Pacer<T> {
+    bucket: TokenBucket,
+    queue: VecDeque<T>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    stats: QueueStats,
+}
+
+impl<T> Pacer<T> {
+    pub(crate) fn new(bucket: TokenBucket, capacity: usize, policy: OverflowPolicy) -> Self {
+        Pacer {
+            bucket,
+            queue: VecDeque::new(),
+            capacity: capacity.max(1),
+            policy,
+            stats: QueueStats::default(),
+        }
+    }
+
+    pub(crate) fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    pub(crate) fn push(&mut self, item: T) -> Push<T> {
+        let res = if self.queue.len() < self.capacity {
+            self.queue.push_back(item);
+            Push::Queued
+        } else {
+            match self.policy {
+                OverflowPolicy::DROP_OLDEST => {
+                    let old = self.queue.pop_front();
+                    self.queue.push_back(item);
+                    self.stats.dropped += 1;
+                    match old {
+                        Some(old) => Push::Dropped(old),
+                        None => Push::Queued,
+                    }
+                }
+                OverflowPolicy::DROP_NEWEST => {
+                    self.stats.dropped += 1;
+                    Push::Dropped(item)
+                }
+                OverflowPolicy::ERROR => {
+                    self.stats.rejected += 1;
+                    Push::Full(item)
+                }
+                OverflowPolicy::BLOCK => Push::Full(item),
+            }
+        };
+        self.stats.peak_depth = self.stats.peak_depth.max(self.queue.len());
+        res
+    }
+
+    pub(crate) fn pop(&mut self, now: Instant) -> Pop<T> {
+        if self.queue.is_empty() {
+            return Pop::Empty;
+        }
+        if !self.bucket.try_take(now) {
+            return Pop::Wait(self.bucket.wait_time(now));
+        }
+        self.stats.sent += 1;
+        match self.queue.pop_front() {
+            Some(item) => Pop::Ready(item),
+            None => Pop::Empty,
+        }
+    }
+
+    pub(crate) fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.queue.drain(..)
+    }
+
+    pub(crate) fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.queue.len(),
+            ..self.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(t0: Instant, ms: u64) -> Instant {
+        t0 + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn coalescer_merges_within_window() {
+        let t0 = Instant::now();
+        let mut c = Coalescer::new(Duration::from_millis(500));
+        assert!(c.admit(1, t0));
+        assert!(!c.admit(1, ms(t0, 100)));
+        assert!(c.admit(2, ms(t0, 100)));
+        assert!(!c.admit(1, ms(t0, 499)));
+        assert!(c.admit(1, ms(t0, 500)));
+        c.set_window(Duration::ZERO);
+        assert!(c.admit(1, ms(t0, 500)));
+    }
+
+    #[test]
+    fn bucket_paces_at_rate() {
+        let t0 = Instant::now();
+        let mut b = TokenBucket::new(60, 1, t0);
+        assert!(b.try_take(t0));
+        assert!(!b.try_take(t0));
+        assert!(!b.try_take(ms(t0, 16)));
+        assert!(b.try_take(ms(t0, 17)));
+        // what the send thread does, one second of it
+        let mut b = TokenBucket::new(60, 1, t0);
+        let (mut t, mut sent) = (t0, 0);
+        while t < ms(t0, 1000) {
+            if b.try_take(t) {
+                sent += 1;
+            } else {
+                t += b.wait_time(t);
+            }
+        }
+        assert_eq!(sent, 60);
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let t0 = Instant::now();
+        let mut p = Pacer::new(TokenBucket::new(10, 1, t0), 2, OverflowPolicy::DROP_OLDEST);
+        p.push(1);
+        p.push(2);
+        assert!(matches!(p.push(3), Push::Dropped(1)));
+        assert!(matches!(p.pop(t0), Pop::Ready(2)));
+        assert!(matches!(p.pop(t0), Pop::Wait(d) if d > Duration::from_millis(99)));
+        assert!(matches!(p.pop(ms(t0, 100)), Pop::Ready(3)));
+        assert!(matches!(p.pop(ms(t0, 200)), Pop::Empty));
+        assert_eq!(
+            p.stats(),
+            QueueStats {
+                depth: 0,
+                peak_depth: 2,
+                sent: 2,
+                dropped: 1,
+                rejected: 0
+            }
+        );
+
+        let mut p = Pacer::new(TokenBucket::new(10, 1, t0), 1, OverflowPolicy::DROP_NEWEST);
+        p.push(1);
+        assert!(matches!(p.push(2), Push::Dropped(2)));
+        assert!(matches!(p.pop(t0), Pop::Ready(1)));
+
+        let mut p = Pacer::new(TokenBucket::new(10, 1, t0), 1, OverflowPolicy::ERROR);
+        p.push(1);
+        assert!(matches!(p.push(2), Push::Full(2)));
+        assert_eq!(p.stats().rejected, 1);
+
+        let mut p = Pacer::new(TokenBucket::new(10, 1, t0), 1, OverflowPolicy::BLOCK);
+        p.push(1);
+        assert!(matches!(p.push(2), Push::Full(2)));
+        assert_eq!(p.stats().depth, 1);
+    }
+}
@@ -0,0 +1,1430 @@
+//! Recording what remote users send, one Matroska file per uid at a time.
+//!
+//! `on_video_data` and `on_audio_data` are handed to a thread of its own which
+//! writes H.264/H.265 video and Opus, AAC (ADTS) or G.711 audio into `.mkv` files,
+//! timed by `sent_ts`. Files are written as they go (clusters of unknown count in a
+//! segment of unknown size), so they stay playable if the process dies.
+use super::super::ffi::*;
+use super::annexb::{nal_units, unescape, Bits};
+use super::connection::ConnShared;
+use super::handler::Observer;
+use super::{AgoraError, AudioDataType, VideoDataType};
+use log::{error, warn};
+use num_traits::FromPrimitive;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Where and how to record, see `Connection::start_recording`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecorderOptions {
+    /// Files are named `<uid>-<n>.mkv` in here, created if missing
+    pub dir: PathBuf,
+    /// Start a new file once this many bytes are written
+    pub max_bytes: Option<u64>,
+    /// Start a new file once it's this long
+    pub max_duration: Option<Duration>,
+    /// How long to wait for the other kind of media before starting a file
+    /// with only one track
+    pub probe: Duration,
+}
+
+impl RecorderOptions {
+    /// No rotation, 500 ms probe.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        RecorderOptions {
+            dir: dir.into(),
+            max_bytes: None,
+            max_duration: None,
+            probe: Duration::from_millis(500),
+        }
+    }
+
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub fn max_duration(mut self, max_duration: Duration) -> Self {
+        self.max_duration = Some(max_duration);
+        self
+    }
+
+    pub fn probe(mut self, probe: Duration) -> Self {
+        self.probe = probe;
+        self
+    }
+}
+
+mod id {
+    pub const EBML: u32 = 0x1A45DFA3;
+    pub const EBML_VERSION: u32 = 0x4286;
+    pub const EBML_READ_VERSION: u32 = 0x42F7;
+    pub const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+    pub const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+    pub const DOC_TYPE: u32 = 0x4282;
+    pub const DOC_TYPE_VERSION: u32 = 0x4287;
+    pub const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+    pub const SEGMENT: u32 = 0x18538067;
+    pub const INFO: u32 = 0x1549A966;
+    pub const TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+    pub const MUXING_APP: u32 = 0x4D80;
+    pub const WRITING_APP: u32 = 0x5741;
+    pub const TRACKS: u32 = 0x1654AE6B;
+    pub const TRACK_ENTRY: u32 = 0xAE;
+    pub const TRACK_NUMBER: u32 = 0xD7;
+    pub const TRACK_UID: u32 = 0x73C5;
+    pub const TRACK_TYPE: u32 = 0x83;
+    pub const FLAG_LACING: u32 = 0x9C;
+    pub const CODEC_ID: u32 = 0x86;
+    pub const CODEC_PRIVATE: u32 = 0x63A2;
+    pub const VIDEO: u32 = 0xE0;
+    pub const PIXEL_WIDTH: u32 = 0xB0;
+    pub const PIXEL_HEIGHT: u32 = 0xBA;
+    pub const AUDIO: u32 = 0xE1;
+    pub const SAMPLING_FREQUENCY: u32 = 0xB5;
+    pub const CHANNELS: u32 = 0x9F;
+    pub const BIT_DEPTH: u32 = 0x6264;
+    pub const CLUSTER: u32 = 0x1F43B675;
+    pub const TIMESTAMP: u32 = 0xE7;
+    pub const SIMPLE_BLOCK: u32 = 0xA3;
+}
+
+/// EBML writing, just what's needed here.
+mod ebml {
+    /// IDs are written as is, they carry their own length marker.
+    pub fn id(out: &mut Vec<u8>, id: u32) {
+        let b = id.to_be_bytes();
+        let skip = b.iter().position(|x| *x != 0).unwrap_or(3);
+        out.extend_from_slice(&b[skip..]);
+    }
+
+    pub fn size(out: &mut Vec<u8>, size: u64) {
+        // all ones means unknown, so one length up for those
+        let mut len = 1;
+        while len < 8 && size >= (1u64 << (7 * len)) - 1 {
+            len += 1;
+        }
+        let v = size | (1u64 << (7 * len));
+        out.extend_from_slice(&v.to_be_bytes()[8 - len..]);
+    }
+
+    pub const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+
+    pub fn element(out: &mut Vec<u8>, id: u32, body: &[u8]) {
+        self::id(out, id);
+        size(out, body.len() as u64);
+        out.extend_from_slice(body);
+    }
+
+    pub fn master<F: FnOnce(&mut Vec<u8>)>(out: &mut Vec<u8>, id: u32, f: F) {
+        let mut body = Vec::new();
+        f(&mut body);
+        element(out, id, &body);
+    }
+
+    pub fn uint(out: &mut Vec<u8>, id: u32, v: u64) {
+        let b = v.to_be_bytes();
+        let skip = b.iter().position(|x| *x != 0).unwrap_or(7);
+        element(out, id, &b[skip..]);
+    }
+
+    pub fn float(out: &mut Vec<u8>, id: u32, v: f64) {
+        element(out, id, &v.to_be_bytes());
+    }
+
+    pub fn string(out: &mut Vec<u8>, id: u32, s: &str) {
+        element(out, id, s.as_bytes());
+    }
+}
+
+const VIDEO_TRACK: u64 = 1;
+const AUDIO_TRACK: u64 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+enum TrackKind {
+    Video {
+        width: u32,
+        height: u32,
+    },
+    Audio {
+        rate: f64,
+        channels: u64,
+        bit_depth: Option<u64>,
+    },
+}
+
+/// A track as declared in `Tracks`. A change means a new file.
+#[derive(Debug, Clone, PartialEq)]
+struct Track {
+    number: u64,
+    codec_id: &'static str,
+    private: Option<Vec<u8>>,
+    kind: TrackKind,
+}
+
+impl Track {
+    fn write(&self, out: &mut Vec<u8>) {
+        ebml::master(out, id::TRACK_ENTRY, |t| {
+            ebml::uint(t, id::TRACK_NUMBER, self.number);
+            ebml::uint(t, id::TRACK_UID, self.number);
+            let track_type = match self.kind {
+                TrackKind::Video { .. } => 1,
+                TrackKind::Audio { .. } => 2,
+            };
+            ebml::uint(t, id::TRACK_TYPE, track_type);
+            ebml::uint(t, id::FLAG_LACING, 0);
+            ebml::string(t, id::CODEC_ID, self.codec_id);
+            if let Some(p) = &self.private {
+                ebml::element(t, id::CODEC_PRIVATE, p);
+            }
+            match self.kind {
+                TrackKind::Video { width, height } => ebml::master(t, id::VIDEO, |v| {
+                    ebml::uint(v, id::PIXEL_WIDTH, width as u64);
+                    ebml::uint(v, id::PIXEL_HEIGHT, height as u64);
+                }),
+                TrackKind::Audio {
+                    rate,
+                    channels,
+                    bit_depth,
+                } => ebml::master(t, id::AUDIO, |a| {
+                    ebml::float(a, id::SAMPLING_FREQUENCY, rate);
+                    ebml::uint(a, id::CHANNELS, channels);
+                    if let Some(d) = bit_depth {
+                        ebml::uint(a, id::BIT_DEPTH, d);
+                    }
+                }),
+            }
+        })
+    }
+}
+
+/// A frame on the timeline of its uid, in ms.
+#[derive(Debug, Clone)]
+struct Sample {
+    track: u64,
+    ms: u64,
+    key: bool,
+    data: Vec<u8>,
+}
+
+/// One Matroska file being written.
+struct MkvWriter<W: Write> {
+    out: W,
+    tracks: Vec<Track>,
+    /// ms of the uid's timeline at 0 in the file
+    base: u64,
+    cluster: Vec<u8>,
+    cluster_ts: Option<u64>,
+    written: u64,
+    duration: u64,
+}
+
+impl<W: Write> MkvWriter<W> {
+    fn create(mut out: W, tracks: Vec<Track>, base: u64) -> io::Result<Self> {
+        let mut head = Vec::new();
+        ebml::master(&mut head, id::EBML, |e| {
+            ebml::uint(e, id::EBML_VERSION, 1);
+            ebml::uint(e, id::EBML_READ_VERSION, 1);
+            ebml::uint(e, id::EBML_MAX_ID_LENGTH, 4);
+            ebml::uint(e, id::EBML_MAX_SIZE_LENGTH, 8);
+            ebml::string(e, id::DOC_TYPE, "matroska");
+            ebml::uint(e, id::DOC_TYPE_VERSION, 4);
+            ebml::uint(e, id::DOC_TYPE_READ_VERSION, 2);
+        });
+        ebml::id(&mut head, id::SEGMENT);
+        head.extend_from_slice(&ebml::UNKNOWN_SIZE);
+        ebml::master(&mut head, id::INFO, |i| {
+            // ms
+            ebml::uint(i, id::TIMESTAMP_SCALE, 1_000_000);
+            ebml::string(i, id::MUXING_APP, "agora-rtsa-rs");
+            ebml::string(i, id::WRITING_APP, "agora-rtsa-rs");
+        });
+        ebml::master(&mut head, id::TRACKS, |t| {
+            tracks.iter().for_each(|track| track.write(t))
+        });
+        out.write_all(&head)?;
+        Ok(MkvWriter {
+            out,
+            tracks,
+            base,
+            cluster: Vec::new(),
+            cluster_ts: None,
+            written: head.len() as u64,
+            duration: 0,
+        })
+    }
+
+    fn has_video(&self) -> bool {
+        self.tracks.iter().any(|t| t.number == VIDEO_TRACK)
+    }
+
+    fn write(&mut self, s: &Sample) -> io::Result<()> {
+        let ts = s.ms.saturating_sub(self.base);
+        let video = self.has_video();
+        // block timestamps are i16 relative to the cluster's
+        let new_cluster = match self.cluster_ts {
+            None => true,
+            Some(c) => {
+                ts < c
+                    || ts - c > 30_000
+                    || (video && s.track == VIDEO_TRACK && s.key && !self.cluster.is_empty())
+                    || (!video && ts - c >= 5_000)
+            }
+        };
+        if new_cluster {
+            self.flush_cluster()?;
+            self.cluster_ts = Some(ts);
+        }
+        let rel = (ts - self.cluster_ts.unwrap_or(ts)) as i16;
+        ebml::id(&mut self.cluster, id::SIMPLE_BLOCK);
+        ebml::size(&mut self.cluster, 4 + s.data.len() as u64);
+        // track number as a 1 byte vint
+        self.cluster.push(0x80 | s.track as u8);
+        self.cluster.extend_from_slice(&rel.to_be_bytes());
+        self.cluster.push(if s.key { 0x80 } else { 0 });
+        self.cluster.extend_from_slice(&s.data);
+        self.duration = self.duration.max(ts);
+        Ok(())
+    }
+
+    fn flush_cluster(&mut self) -> io::Result<()> {
+        let ts = match self.cluster_ts {
+            Some(ts) if !self.cluster.is_empty() => ts,
+            _ => return Ok(()),
+        };
+        let mut body = Vec::with_capacity(self.cluster.len() + 10);
+        ebml::uint(&mut body, id::TIMESTAMP, ts);
+        body.append(&mut self.cluster);
+        let mut cluster = Vec::with_capacity(body.len() + 12);
+        ebml::element(&mut cluster, id::CLUSTER, &body);
+        self.out.write_all(&cluster)?;
+        self.written += cluster.len() as u64;
+        Ok(())
+    }
+
+    fn bytes(&self) -> u64 {
+        self.written + self.cluster.len() as u64
+    }
+
+    fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration)
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.flush_cluster()?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// `sent_ts` is in ms and wraps every 65.5 s.
+#[derive(Default)]
+struct TsUnwrapper {
+    last: Option<(u16, u64, Instant)>,
+}
+
+impl TsUnwrapper {
+    /// ms since the first one.
+    fn unwrap(&mut self, ts: u16, arrival: Instant) -> u64 {
+        let (last_ts, last_ms, last_arrival) = match self.last {
+            Some(last) => last,
+            None => {
+                self.last = Some((ts, 0, arrival));
+                return 0;
+            }
+        };
+        let d = ts.wrapping_sub(last_ts) as u64;
+        let elapsed = arrival.saturating_duration_since(last_arrival).as_millis() as u64;
+        let ms = if d >= 0x8000 && elapsed < 0x8000 {
+            // a bit behind the last one, out of order
+            last_ms.saturating_sub(0x10000 - d)
+        } else {
+            // how often it wrapped in a gap (e.g. a long mute) is told by the arrival times
+            let wraps = (elapsed.saturating_sub(d) + 0x8000) / 0x10000;
+            last_ms + d + wraps * 0x10000
+        };
+        if ms >= last_ms {
+            self.last = Some((ts, ms, arrival));
+        }
+        ms
+    }
+}
+
+/// Pixels cropped off one dimension, `None` if it doesn't fit a `u32`.
+fn crop(unit: u32, a: u32, b: u32) -> Option<u32> {
+    a.checked_add(b)?.checked_mul(unit)
+}
+
+/// Width and height from an H.264 SPS, cropping applied.
+fn h264_size(sps: &[u8]) -> Option<(u32, u32)> {
+    let rbsp = unescape(sps);
+    let mut b = Bits::new(rbsp.get(1..)?);
+    let profile = b.bits(8)?;
+    b.skip(16);
+    b.ue()?;
+    let mut chroma = 1;
+    if [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135].contains(&profile) {
+        chroma = b.ue()?;
+        if chroma == 3 {
+            b.skip(1);
+        }
+        b.ue()?;
+        b.ue()?;
+        b.skip(1);
+        if b.bit()? == 1 {
+            for i in 0..if chroma == 3 { 12 } else { 8 } {
+                if b.bit()? == 1 {
+                    // scaling_list(), only to get past it
+                    let (mut last, mut next) = (8i32, 8i32);
+                    for _ in 0..if i < 6 { 16 } else { 64 } {
+                        if next != 0 {
+                            let delta = b.se()?;
+                            if !(-128..=127).contains(&delta) {
+                                return None;
+                            }
+                            next = (last + delta + 256) % 256;
+                        }
+                        last = if next == 0 { last } else { next };
+                    }
+                }
+            }
+        }
+    }
+    b.ue()?;
+    match b.ue()? {
+        0 => {
+            b.ue()?;
+        }
+        1 => {
+            b.skip(1);
+            b.se()?;
+            b.se()?;
+            for _ in 0..b.ue()? {
+                b.se()?;
+            }
+        }
+        _ => {}
+    }
+    b.ue()?;
+    b.skip(1);
+    let width_mbs = b.ue()?.checked_add(1)?;
+    let height_units = b.ue()?.checked_add(1)?;
+    let frame_mbs_only = b.bit()?;
+    if frame_mbs_only == 0 {
+        b.skip(1);
+    }
+    b.skip(1);
+    // all of it from the wire, nothing keeps it from overflowing
+    let mut width = width_mbs.checked_mul(16)?;
+    let mut height = height_units.checked_mul(16 * (2 - frame_mbs_only))?;
+    if b.bit()? == 1 {
+        let (sub_w, sub_h) = match chroma {
+            0 => (1, 1),
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        let (crop_x, crop_y) = (sub_w, sub_h * (2 - frame_mbs_only));
+        let (l, r, t, bo) = (b.ue()?, b.ue()?, b.ue()?, b.ue()?);
+        width = width.checked_sub(crop(crop_x, l, r)?)?;
+        height = height.checked_sub(crop(crop_y, t, bo)?)?;
+    }
+    Some((width, height))
+}
+
+/// What `hvcC` needs from an H.265 SPS.
+#[derive(Debug, PartialEq, Eq)]
+struct HevcSps {
+    /// general profile_tier_level, 12 bytes
+    ptl: Vec<u8>,
+    sub_layers: u32,
+    temporal_id_nesting: u32,
+    chroma: u32,
+    bit_depth_luma: u32,
+    bit_depth_chroma: u32,
+    width: u32,
+    height: u32,
+}
+
+fn h265_sps(sps: &[u8]) -> Option<HevcSps> {
+    let rbsp = unescape(sps);
+    let rbsp = rbsp.get(2..)?;
+    let mut b = Bits::new(rbsp);
+    b.skip(4);
+    let max_sub_layers_minus1 = b.bits(3)?;
+    let temporal_id_nesting = b.bit()?;
+    let ptl = rbsp.get(1..13)?.to_vec();
+    b.skip_profile_tier_level(max_sub_layers_minus1)?;
+    b.ue()?;
+    let chroma = b.ue()?;
+    if chroma == 3 {
+        b.skip(1);
+    }
+    let mut width = b.ue()?;
+    let mut height = b.ue()?;
+    if b.bit()? == 1 {
+        let (sub_w, sub_h) = match chroma {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        let (l, r, t, bo) = (b.ue()?, b.ue()?, b.ue()?, b.ue()?);
+        width = width.checked_sub(crop(sub_w, l, r)?)?;
+        height = height.checked_sub(crop(sub_h, t, bo)?)?;
+    }
+    Some(HevcSps {
+        ptl,
+        sub_layers: max_sub_layers_minus1 + 1,
+        temporal_id_nesting,
+        chroma,
+        bit_depth_luma: b.ue()?.checked_add(8)?,
+        bit_depth_chroma: b.ue()?.checked_add(8)?,
+        width,
+        height,
+    })
+}
+
+fn h264_type(nal: &[u8]) -> u8 {
+    nal[0] & 0x1f
+}
+
+fn h265_type(nal: &[u8]) -> u8 {
+    (nal[0] >> 1) & 0x3f
+}
+
+/// The video track of a key frame, `None` without the parameter sets.
+fn video_track(data_type: VideoDataType, nals: &[&[u8]]) -> Option<Track> {
+    let put = |out: &mut Vec<u8>, nal: &[u8]| {
+        out.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+        out.extend_from_slice(nal);
+    };
+    match data_type {
+        VideoDataType::H264 => {
+            let sps = nals.iter().find(|n| h264_type(n) == 7)?;
+            let pps = nals.iter().find(|n| h264_type(n) == 8)?;
+            let (width, height) = h264_size(sps)?;
+            // AVCDecoderConfigurationRecord, 4 byte lengths
+            let mut avcc = vec![1, *sps.get(1)?, *sps.get(2)?, *sps.get(3)?, 0xff, 0xe1];
+            put(&mut avcc, sps);
+            avcc.push(1);
+            put(&mut avcc, pps);
+            Some(Track {
+                number: VIDEO_TRACK,
+                codec_id: "V_MPEG4/ISO/AVC",
+                private: Some(avcc),
+                kind: TrackKind::Video { width, height },
+            })
+        }
+        VideoDataType::H265 => {
+            let find = |t| nals.iter().find(|n| h265_type(n) == t);
+            let (vps, sps, pps) = (find(32)?, find(33)?, find(34)?);
+            let info = h265_sps(sps)?;
+            // HEVCDecoderConfigurationRecord
+            let mut hvcc = vec![1];
+            hvcc.extend_from_slice(&info.ptl);
+            hvcc.extend_from_slice(&[0xf0, 0x00, 0xfc]);
+            hvcc.push(0xfc | info.chroma as u8);
+            hvcc.push(0xf8 | (info.bit_depth_luma - 8) as u8);
+            hvcc.push(0xf8 | (info.bit_depth_chroma - 8) as u8);
+            hvcc.extend_from_slice(&[0, 0]);
+            hvcc.push(((info.sub_layers as u8) << 3) | ((info.temporal_id_nesting as u8) << 2) | 3);
+            hvcc.push(3);
+            for (t, nal) in [(32, vps), (33, sps), (34, pps)] {
+                hvcc.push(0x80 | t);
+                hvcc.extend_from_slice(&1u16.to_be_bytes());
+                put(&mut hvcc, nal);
+            }
+            Some(Track {
+                number: VIDEO_TRACK,
+                codec_id: "V_MPEGH/ISO/HEVC",
+                private: Some(hvcc),
+                kind: TrackKind::Video {
+                    width: info.width,
+                    height: info.height,
+                },
+            })
+        }
+        _ => None,
+    }
+}
+
+const AAC_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// The frames of an ADTS buffer, without their headers, and the track they make.
+fn adts_frames(data: &[u8]) -> Option<(Track, Vec<&[u8]>)> {
+    let mut frames = Vec::new();
+    let mut config = None;
+    let mut at = 0;
+    while at + 7 <= data.len() {
+        let h = &data[at..];
+        if h[0] != 0xff || h[1] & 0xf0 != 0xf0 {
+            return None;
+        }
+        let header_len = if h[1] & 1 == 1 { 7 } else { 9 };
+        let len = (((h[3] & 3) as usize) << 11) | ((h[4] as usize) << 3) | (h[5] as usize >> 5);
+        let frame = data.get(at + header_len..at + len)?;
+        let object_type = (h[2] >> 6) + 1;
+        let rate_index = (h[2] >> 2) & 0xf;
+        let channels = ((h[2] & 1) << 2) | (h[3] >> 6);
+        config = Some((object_type, rate_index, channels));
+        frames.push(frame);
+        at += len;
+    }
+    let (object_type, rate_index, channels) = config?;
+    // AudioSpecificConfig
+    let asc = vec![
+        (object_type << 3) | (rate_index >> 1),
+        ((rate_index & 1) << 7) | (channels << 3),
+    ];
+    let track = Track {
+        number: AUDIO_TRACK,
+        codec_id: "A_AAC",
+        private: Some(asc),
+        kind: TrackKind::Audio {
+            rate: *AAC_RATES.get(rate_index as usize)? as f64,
+            channels: channels as u64,
+            bit_depth: None,
+        },
+    };
+    Some((track, frames))
+}
+
+/// Always stereo: the TOC stereo flag may change from packet to packet, a
+/// decoder set up for 2 channels plays mono packets too.
+fn opus_track() -> Track {
+    let channels = 2u8;
+    let mut head = b"OpusHead".to_vec();
+    head.extend_from_slice(&[1, channels, 0, 0]);
+    head.extend_from_slice(&48000u32.to_le_bytes());
+    head.extend_from_slice(&[0, 0, 0]);
+    Track {
+        number: AUDIO_TRACK,
+        codec_id: "A_OPUS",
+        private: Some(head),
+        kind: TrackKind::Audio {
+            rate: 48000.0,
+            channels: channels as u64,
+            bit_depth: None,
+        },
+    }
+}
+
+fn g711_track(format_tag: u16) -> Track {
+    // WAVEFORMATEX, 8 kHz mono 8 bit
+    let mut wave = Vec::with_capacity(18);
+    wave.extend_from_slice(&format_tag.to_le_bytes());
+    wave.extend_from_slice(&1u16.to_le_bytes());
+    wave.extend_from_slice(&8000u32.to_le_bytes());
+    wave.extend_from_slice(&8000u32.to_le_bytes());
+    wave.extend_from_slice(&1u16.to_le_bytes());
+    wave.extend_from_slice(&8u16.to_le_bytes());
+    wave.extend_from_slice(&0u16.to_le_bytes());
+    Track {
+        number: AUDIO_TRACK,
+        codec_id: "A_MS/ACM",
+        private: Some(wave),
+        kind: TrackKind::Audio {
+            rate: 8000.0,
+            channels: 1,
+            bit_depth: Some(8),
+        },
+    }
+}
+
+/// The timeline of one track: `sent_ts` unwrapped, starting where it arrived.
+struct TrackClock {
+    unwrap: TsUnwrapper,
+    anchor: u64,
+}
+
+/// The recording of one remote user.
+struct UidRecorder {
+    uid: u32,
+    options: RecorderOptions,
+    t0: Option<Instant>,
+    clocks: [Option<TrackClock>; 2],
+    video: Option<Track>,
+    audio: Option<Track>,
+    /// waiting for a file
+    pending: Vec<Sample>,
+    file: Option<MkvWriter<BufWriter<File>>>,
+    seq: u32,
+    rotate: bool,
+    /// a write failed, recording of this uid stopped
+    failed: bool,
+    warned: bool,
+}
+
+impl UidRecorder {
+    fn new(uid: u32, options: RecorderOptions) -> Self {
+        UidRecorder {
+            uid,
+            options,
+            t0: None,
+            clocks: [None, None],
+            video: None,
+            audio: None,
+            pending: Vec::new(),
+            file: None,
+            seq: 0,
+            rotate: false,
+            failed: false,
+            warned: false,
+        }
+    }
+
+    fn ms(&mut self, track: u64, sent_ts: u16, arrival: Instant) -> u64 {
+        let t0 = *self.t0.get_or_insert(arrival);
+        let clock = self.clocks[(track - 1) as usize].get_or_insert_with(|| TrackClock {
+            unwrap: TsUnwrapper::default(),
+            anchor: arrival.saturating_duration_since(t0).as_millis() as u64,
+        });
+        clock.anchor + clock.unwrap.unwrap(sent_ts, arrival)
+    }
+
+    fn unsupported(&mut self, what: &str) {
+        if !std::mem::replace(&mut self.warned, true) {
+            warn!("not recording {} of uid {}", what, self.uid);
+        }
+    }
+
+    fn video(&mut self, sent_ts: u16, arrival: Instant, data_type: u32, data: &[u8]) {
+        let data_type = match VideoDataType::from_u32(data_type) {
+            Some(t @ (VideoDataType::H264 | VideoDataType::H265)) => t,
+            _ => return self.unsupported(&format!("video_data_type_e {}", data_type)),
+        };
+        let nals = nal_units(data);
+        let (kind, aud): (fn(&[u8]) -> u8, _) = match data_type {
+            VideoDataType::H264 => (h264_type, 9),
+            _ => (h265_type, 35),
+        };
+        let key = nals.iter().any(|n| match data_type {
+            VideoDataType::H264 => kind(n) == 5,
+            _ => (16..=23).contains(&kind(n)),
+        });
+        if key {
+            if let Some(track) = video_track(data_type, &nals) {
+                self.video = Some(track);
+            }
+        }
+        if self.video.is_none() {
+            // can't be decoded before the first parameter sets
+            return;
+        }
+        let mut payload = Vec::with_capacity(data.len());
+        for n in nals.iter().filter(|n| kind(n) != aud) {
+            payload.extend_from_slice(&(n.len() as u32).to_be_bytes());
+            payload.extend_from_slice(n);
+        }
+        let ms = self.ms(VIDEO_TRACK, sent_ts, arrival);
+        self.push(Sample {
+            track: VIDEO_TRACK,
+            ms,
+            key,
+            data: payload,
+        });
+    }
+
+    fn audio(&mut self, sent_ts: u16, arrival: Instant, data_type: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let (track, frames, frame_ms) = match AudioDataType::from_u32(data_type) {
+            Some(AudioDataType::OPUS | AudioDataType::OPUSFB) => (opus_track(), vec![data], 0),
+            Some(AudioDataType::PCMA) => (g711_track(6), vec![data], 0),
+            Some(AudioDataType::PCMU) => (g711_track(7), vec![data], 0),
+            Some(AudioDataType::AACLC | AudioDataType::HEAAC) => match adts_frames(data) {
+                Some((track, frames)) => {
+                    let ms = match &track.kind {
+                        TrackKind::Audio { rate, .. } => (1_024_000.0 / rate) as u64,
+                        _ => 0,
+                    };
+                    (track, frames, ms)
+                }
+                None => return self.unsupported("AAC without ADTS headers"),
+            },
+            _ => return self.unsupported(&format!("audio_data_type_e {}", data_type)),
+        };
+        self.audio = Some(track);
+        let ms = self.ms(AUDIO_TRACK, sent_ts, arrival);
+        for (i, frame) in frames.into_iter().enumerate() {
+            self.push(Sample {
+                track: AUDIO_TRACK,
+                ms: ms + i as u64 * frame_ms,
+                key: true,
+                data: frame.to_vec(),
+            });
+        }
+    }
+
+    fn tracks(&self) -> Vec<Track> {
+        self.video
+            .iter()
+            .chain(self.audio.iter())
+            .cloned()
+            .collect()
+    }
+
+    fn push(&mut self, s: Sample) {
+        if self.failed {
+            return;
+        }
+        if let Some(file) = &self.file {
+            let full = self.options.max_bytes.is_some_and(|m| file.bytes() >= m)
+                || self
+                    .options
+                    .max_duration
+                    .is_some_and(|d| file.duration() >= d);
+            if full || file.tracks != self.tracks() {
+                self.rotate = true;
+            }
+        }
+        if self.rotate {
+            if let Some(file) = &self.file {
+                // video has to start with a key frame
+                let cut = s.key && (s.track == VIDEO_TRACK || self.video.is_none());
+                let track = if s.track == VIDEO_TRACK {
+                    &self.video
+                } else {
+                    &self.audio
+                };
+                if cut {
+                    self.close();
+                } else if file.tracks.iter().any(|t| Some(t) == track.as_ref()) {
+                    // same codec and parameters, still fits the old file
+                    return self.write(s);
+                } else {
+                    return self.pending.push(s);
+                }
+            }
+            self.rotate = false;
+        }
+        if self.file.is_some() {
+            return self.write(s);
+        }
+        let has_video = self.pending.iter().any(|p| p.track == VIDEO_TRACK);
+        if s.track == VIDEO_TRACK && !s.key && !has_video {
+            return;
+        }
+        self.pending.push(s);
+        let (min, max) = self
+            .pending
+            .iter()
+            .fold((u64::MAX, 0), |(lo, hi), p| (lo.min(p.ms), hi.max(p.ms)));
+        let probed = Duration::from_millis(max - min) >= self.options.probe;
+        if (self.video.is_some() && self.audio.is_some()) || probed {
+            self.open(min);
+        }
+    }
+
+    fn open(&mut self, base: u64) {
+        let path = self
+            .options
+            .dir
+            .join(format!("{}-{:04}.mkv", self.uid, self.seq));
+        self.seq += 1;
+        let created = File::create(&path)
+            .and_then(|f| MkvWriter::create(BufWriter::new(f), self.tracks(), base));
+        match created {
+            Ok(file) => self.file = Some(file),
+            Err(e) => {
+                error!("failed to create {}, {}", path.display(), e);
+                self.failed = true;
+                return;
+            }
+        }
+        for s in std::mem::take(&mut self.pending) {
+            self.write(s);
+        }
+    }
+
+    fn write(&mut self, s: Sample) {
+        if let Some(file) = &mut self.file {
+            if let Err(e) = file.write(&s) {
+                error!("failed to record uid {}, {}", self.uid, e);
+                self.file = None;
+                self.failed = true;
+            }
+        }
+    }
+
+    /// Finish the current file.
+    fn close(&mut self) {
+        if let Some(file) = self.file.take() {
+            if let Err(e) = file.finish() {
+                error!("failed to finish recording uid {}, {}", self.uid, e);
+            }
+        }
+    }
+
+    /// Finish the current file, and write out what's waiting for one.
+    fn finish(&mut self) {
+        self.close();
+        if let Some(base) = self.pending.iter().map(|p| p.ms).min() {
+            if !self.failed {
+                self.open(base);
+                self.close();
+            }
+        }
+        self.pending.clear();
+    }
+
+    /// When they come back it's a new file with new tracks and timeline.
+    fn offline(&mut self) {
+        self.finish();
+        *self = UidRecorder {
+            seq: self.seq,
+            ..UidRecorder::new(self.uid, self.options.clone())
+        };
+    }
+}
+
+enum Msg {
+    Video {
+        uid: u32,
+        sent_ts: u16,
+        arrival: Instant,
+        data_type: u32,
+        data: Vec<u8>,
+    },
+    Audio {
+        uid: u32,
+        sent_ts: u16,
+        arrival: Instant,
+        data_type: u32,
+        data: Vec<u8>,
+    },
+    Offline(u32),
+    Stop,
+}
+
+/// Observer of a connection handing the media to the recording thread.
+struct Tap(Mutex<Sender<Msg>>);
+
+impl Tap {
+    fn send(&self, msg: Msg) {
+        // gone once the thread stopped
+        let _ = self.0.lock().unwrap_or_else(|e| e.into_inner()).send(msg);
+    }
+}
+
+impl Observer for Tap {
+    fn on_video_data(
+        &self,
+        _conn_id: u32,
+        uid: u32,
+        sent_ts: u16,
+        data: &[u8],
+        info: &video_frame_info_t,
+    ) {
+        self.send(Msg::Video {
+            uid,
+            sent_ts,
+            arrival: Instant::now(),
+            data_type: info.data_type,
+            data: data.to_vec(),
+        })
+    }
+
+    fn on_audio_data(
+        &self,
+        _conn_id: u32,
+        uid: u32,
+        sent_ts: u16,
+        data: &[u8],
+        info: &audio_frame_info_t,
+    ) {
+        self.send(Msg::Audio {
+            uid,
+            sent_ts,
+            arrival: Instant::now(),
+            data_type: info.data_type,
+            data: data.to_vec(),
+        })
+    }
+
+    fn on_user_offline(&self, _conn_id: u32, uid: u32, _reason: i32) {
+        self.send(Msg::Offline(uid))
+    }
+}
+
+fn user<'a>(
+    users: &'a mut HashMap<u32, UidRecorder>,
+    uid: u32,
+    options: &RecorderOptions,
+) -> &'a mut UidRecorder {
+    users
+        .entry(uid)
+        .or_insert_with(|| UidRecorder::new(uid, options.clone()))
+}
+
+fn run(rx: Receiver<Msg>, options: RecorderOptions) {
+    let mut users: HashMap<u32, UidRecorder> = HashMap::new();
+    for msg in rx.iter() {
+        match msg {
+            Msg::Video {
+                uid,
+                sent_ts,
+                arrival,
+                data_type,
+                data,
+            } => user(&mut users, uid, &options).video(sent_ts, arrival, data_type, &data),
+            Msg::Audio {
+                uid,
+                sent_ts,
+                arrival,
+                data_type,
+                data,
+            } => user(&mut users, uid, &options).audio(sent_ts, arrival, data_type, &data),
+            // a new file if they come back
+            Msg::Offline(uid) => {
+                if let Some(r) = users.get_mut(&uid) {
+                    r.offline()
+                }
+            }
+            Msg::Stop => break,
+        }
+    }
+    users.values_mut().for_each(|r| r.finish());
+}
+
+/// Records the remote users of a connection until dropped, see the module docs.
+pub(crate) struct Recorder {
+    tap: Arc<Tap>,
+    conn: Arc<ConnShared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    pub(crate) fn start(
+        conn: Arc<ConnShared>,
+        options: RecorderOptions,
+    ) -> Result<Self, AgoraError> {
+        std::fs::create_dir_all(&options.dir)
+            .map_err(|e| AgoraError::MediaFile(format!("{}: {}", options.dir.display(), e)))?;
+        let (tx, rx) = channel();
+        let thread = std::thread::Builder::new()
+            .name("agora-recorder".to_owned())
+            .spawn(move || run(rx, options))
+            .map_err(|e| AgoraError::MediaFile(format!("no recorder thread, {}", e)))?;
+        let tap = Arc::new(Tap(Mutex::new(tx)));
+        conn.add_observer(tap.clone());
+        Ok(Recorder {
+            tap,
+            conn,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let observer: Arc<dyn Observer> = self.tap.clone();
+        self.conn.remove_observer(&observer);
+        self.tap.send(Msg::Stop);
+        if let Some(t) = self.thread.take() {
+            if t.join().is_err() {
+                warn!("recorder thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fake::fake_service;
+    use super::super::VideoFrameInfo;
+    use super::*;
+    use std::path::Path;
+
+    fn vint(data: &[u8], pos: &mut usize, keep_marker: bool) -> Option<u64> {
+        let first = data[*pos];
+        let len = first.leading_zeros() as usize + 1;
+        let mut v = if keep_marker {
+            first as u64
+        } else {
+            (first as u64) & ((1 << (8 - len)) - 1)
+        };
+        let unknown = !keep_marker && v == (1 << (8 - len)) - 1;
+        for b in &data[*pos + 1..*pos + len] {
+            v = (v << 8) | *b as u64;
+        }
+        *pos += len;
+        if unknown && data[*pos - len + 1..*pos].iter().all(|b| *b == 0xff) {
+            return None;
+        }
+        Some(v)
+    }
+
+    /// (id, body) of the elements in `data`, unknown sizes run to the end
+    fn children(data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let id = vint(data, &mut pos, true).unwrap() as u32;
+            let end = vint(data, &mut pos, false).map_or(data.len(), |s| pos + s as usize);
+            out.push((id, &data[pos..end]));
+            pos = end;
+        }
+        out
+    }
+
+    fn child(data: &[u8], id: u32) -> &[u8] {
+        children(data).into_iter().find(|c| c.0 == id).unwrap().1
+    }
+
+    fn uint(data: &[u8]) -> u64 {
+        data.iter().fold(0, |v, b| (v << 8) | *b as u64)
+    }
+
+    #[derive(Debug)]
+    struct Mkv {
+        /// (number, codec id, private)
+        tracks: Vec<(u64, String, Vec<u8>)>,
+        /// (track, ms, key, data)
+        blocks: Vec<(u64, u64, bool, Vec<u8>)>,
+    }
+
+    fn parse(path: &Path) -> Mkv {
+        let data = std::fs::read(path).unwrap();
+        let top = children(&data);
+        assert_eq!(top[0].0, id::EBML);
+        assert_eq!(child(top[0].1, id::DOC_TYPE), b"matroska");
+        assert_eq!(top[1].0, id::SEGMENT);
+        let segment = children(top[1].1);
+        let info = segment.iter().find(|c| c.0 == id::INFO).unwrap().1;
+        assert_eq!(uint(child(info, id::TIMESTAMP_SCALE)), 1_000_000);
+        let tracks = children(segment.iter().find(|c| c.0 == id::TRACKS).unwrap().1)
+            .into_iter()
+            .map(|(_, e)| {
+                let codec = String::from_utf8(child(e, id::CODEC_ID).to_vec()).unwrap();
+                (
+                    uint(child(e, id::TRACK_NUMBER)),
+                    codec,
+                    child(e, id::CODEC_PRIVATE).to_vec(),
+                )
+            })
+            .collect();
+        let mut blocks = Vec::new();
+        for (_, cluster) in segment.iter().filter(|c| c.0 == id::CLUSTER) {
+            let mut ts = 0;
+            for (id, body) in children(cluster) {
+                match id {
+                    id::TIMESTAMP => ts = uint(body),
+                    id::SIMPLE_BLOCK => {
+                        let rel = i16::from_be_bytes([body[1], body[2]]);
+                        assert!(rel >= 0);
+                        let key = body[3] & 0x80 != 0;
+                        blocks.push((
+                            (body[0] & 0x7f) as u64,
+                            ts + rel as u64,
+                            key,
+                            body[4..].to_vec(),
+                        ));
+                    }
+                    _ => panic!("unexpected {:x} in a cluster", id),
+                }
+            }
+        }
+        Mkv { tracks, blocks }
+    }
+
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agora-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    const SPS: &[u8] = &[0x67, 0x42, 0x00, 0x0a, 0xf8, 0x41, 0xa2];
+    const PPS: &[u8] = &[0x68, 0xce, 0x38, 0x80];
+
+    fn annexb(nals: &[&[u8]]) -> Vec<u8> {
+        nals.iter()
+            .flat_map(|n| [&[0, 0, 0, 1][..], n].concat())
+            .collect()
+    }
+
+    fn key_frame(n: u8) -> Vec<u8> {
+        annexb(&[&[0x09, 0xf0], SPS, PPS, &[0x65, 0x88, n]])
+    }
+
+    #[test]
+    fn unwraps_sent_ts() {
+        let t = Instant::now();
+        let at = |ms| t + Duration::from_millis(ms);
+        let mut u = TsUnwrapper::default();
+        assert_eq!(u.unwrap(0, at(0)), 0);
+        assert_eq!(u.unwrap(65530, at(65530)), 65530);
+        // wrapped
+        assert_eq!(u.unwrap(10, at(65546)), 65546);
+        // late
+        assert_eq!(u.unwrap(65535, at(65546)), 65535);
+        // 200 s without anything, wrapped 3 times in between
+        assert_eq!(u.unwrap(110, at(265546)), 65546 + 100 + 3 * 65536);
+    }
+
+    #[test]
+    fn parses_sps() {
+        assert_eq!(h264_size(SPS), Some((128, 96)));
+        let sps = [
+            0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00,
+            0x00, 0x03, 0x00, 0x5d, 0xa0, 0x0a, 0x08, 0x0f, 0x17,
+        ];
+        let info = h265_sps(&sps).unwrap();
+        assert_eq!((info.width, info.height), (320, 240));
+        assert_eq!(
+            (info.chroma, info.bit_depth_luma, info.bit_depth_chroma),
+            (1, 8, 8)
+        );
+        assert_eq!(info.ptl, [0x01, 0x60, 0, 0, 0, 0x90, 0, 0, 0, 0, 0, 0x5d]);
+    }
+
+    #[test]
+    fn rejects_sps_overflowing_the_size() {
+        // baseline SPS, or high with the first scaling list and its first
+        // delta_scale (as ue) given, Exp-Golomb written out bit by bit
+        let sps = |width_mbs_minus1: u32, crop: Option<u32>, scaling: Option<u32>| {
+            let mut bits = String::new();
+            let ue = |bits: &mut String, v: u32| {
+                let v = format!("{:b}", v as u64 + 1);
+                bits.push_str(&"0".repeat(v.len() - 1));
+                bits.push_str(&v);
+            };
+            match scaling {
+                Some(delta) => {
+                    bits.push_str("011001000000000000001010");
+                    for v in [0, 1, 0, 0] {
+                        ue(&mut bits, v);
+                    }
+                    bits.push_str("011");
+                    ue(&mut bits, delta);
+                    bits.push_str("0000000");
+                }
+                None => {
+                    bits.push_str("010000100000000000001010");
+                    ue(&mut bits, 0);
+                }
+            }
+            for v in [0, 0, 0, 1] {
+                ue(&mut bits, v);
+            }
+            bits.push('0');
+            ue(&mut bits, width_mbs_minus1);
+            ue(&mut bits, 5);
+            bits.push_str("11");
+            match crop {
+                Some(c) => {
+                    bits.push('1');
+                    for v in [c, c, 0, 0] {
+                        ue(&mut bits, v);
+                    }
+                }
+                None => bits.push('0'),
+            }
+            bits.push('1');
+            while !bits.len().is_multiple_of(8) {
+                bits.push('0');
+            }
+            let mut out = vec![0x67];
+            for i in (0..bits.len()).step_by(8) {
+                out.push(u8::from_str_radix(&bits[i..i + 8], 2).unwrap());
+            }
+            out
+        };
+        assert_eq!(h264_size(&sps(7, None, None)), Some((128, 96)));
+        assert_eq!(h264_size(&sps(7, Some(4), None)), Some((112, 96)));
+        assert_eq!(h264_size(&sps(0x0fff_ffff, None, None)), None);
+        assert_eq!(h264_size(&sps(u32::MAX - 1, None, None)), None);
+        assert_eq!(h264_size(&sps(7, Some(0x8000_0000), None)), None);
+        // -8 ends the list right away, the rest as it was
+        assert_eq!(h264_size(&sps(7, None, Some(16))), Some((128, 96)));
+        // 2^31 - 1 would overflow the running scale
+        assert_eq!(h264_size(&sps(7, None, Some(u32::MAX - 2))), None);
+        assert_eq!(h264_size(&sps(7, None, Some(256))), None);
+    }
+
+    #[test]
+    fn rotates_h264_opus() {
+        let dir = temp_dir("recorder");
+        std::fs::create_dir_all(&dir).unwrap();
+        let options = RecorderOptions::new(&dir).max_duration(Duration::from_secs(1));
+        let mut r = UidRecorder::new(9, options);
+        let t = Instant::now();
+        // sent_ts wraps after 536 ms
+        let base: u16 = 65000;
+        let (mut video, mut audio) = (0, 0);
+        for ms in 0..3000u64 {
+            let sent_ts = base.wrapping_add(ms as u16);
+            let at = t + Duration::from_millis(ms + 40);
+            if ms % 33 == 0 {
+                let n = (ms / 33) as u8;
+                let frame = if n.is_multiple_of(15) {
+                    key_frame(n)
+                } else {
+                    annexb(&[&[0x09, 0xf0], &[0x41, 0x9a, n]])
+                };
+                r.video(sent_ts, at, VideoDataType::H264.into(), &frame);
+                video += 1;
+            }
+            if ms % 20 == 0 {
+                // mono and stereo packets mixed, no new file for that
+                let toc = if ms % 40 == 0 { 0x78 } else { 0x7c };
+                r.audio(sent_ts, at, AudioDataType::OPUS.into(), &[toc, ms as u8]);
+                audio += 1;
+            }
+        }
+        r.finish();
+
+        let files = files(&dir);
+        let names: Vec<_> = files
+            .iter()
+            .map(|f| f.file_name().unwrap().to_owned())
+            .collect();
+        assert_eq!(names, ["9-0000.mkv", "9-0001.mkv", "9-0002.mkv"]);
+        let (mut got_video, mut got_audio) = (0, 0);
+        let mut last: Option<u8> = None;
+        for f in &files {
+            let mkv = parse(f);
+            let mut avcc = vec![1, 0x42, 0x00, 0x0a, 0xff, 0xe1, 0, 7];
+            avcc.extend_from_slice(SPS);
+            avcc.extend_from_slice(&[1, 0, 4]);
+            avcc.extend_from_slice(PPS);
+            assert_eq!(mkv.tracks[0], (1, "V_MPEG4/ISO/AVC".to_owned(), avcc));
+            assert_eq!(mkv.tracks[1].1, "A_OPUS");
+            assert_eq!(&mkv.tracks[1].2[..10], b"OpusHead\x01\x02");
+
+            // starts at 0 with a key frame, AUD gone, 4 byte lengths
+            let first = mkv.blocks.iter().find(|b| b.0 == 1).unwrap();
+            assert!(first.2);
+            assert_eq!(first.3[..4], [0, 0, 0, 7]);
+            assert_eq!(&first.3[4..11], SPS);
+            assert_eq!(mkv.blocks[0].1, 0);
+            for track in [1, 2] {
+                let ts: Vec<_> = mkv
+                    .blocks
+                    .iter()
+                    .filter(|b| b.0 == track)
+                    .map(|b| b.1)
+                    .collect();
+                assert!(ts.windows(2).all(|w| w[0] < w[1]), "{:?}", ts);
+            }
+            let end = mkv.blocks.iter().map(|b| b.1).max().unwrap();
+            assert!(end < 1600, "{} ms in {:?}", end, f);
+            // the audio of a file lines up with the end of the previous one
+            let first_audio = mkv.blocks.iter().find(|b| b.0 == 2).unwrap();
+            if let Some(last) = last {
+                assert_eq!(first_audio.3[1], last.wrapping_add(20));
+            }
+            last = mkv.blocks.iter().rev().find(|b| b.0 == 2).map(|b| b.3[1]);
+            got_video += mkv.blocks.iter().filter(|b| b.0 == 1).count();
+            got_audio += mkv.blocks.iter().filter(|b| b.0 == 2).count();
+        }
+        assert_eq!((got_video, got_audio), (video, audio));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn holds_back_audio_of_a_new_codec_until_the_cut() {
+        let dir = temp_dir("codec-change");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut r = UidRecorder::new(4, RecorderOptions::new(&dir));
+        let t = Instant::now();
+        for ms in (0..600u64).step_by(20) {
+            let at = t + Duration::from_millis(ms);
+            if ms % 100 == 0 {
+                let frame = if ms % 400 == 0 {
+                    key_frame(ms as u8)
+                } else {
+                    annexb(&[&[0x41, 0x9a, ms as u8]])
+                };
+                r.video(ms as u16, at, VideoDataType::H264.into(), &frame);
+            }
+            // Opus, then PCMA from 200 ms on, the next key frame at 400 ms
+            let (data_type, toc) = if ms < 200 {
+                (AudioDataType::OPUS, 0x78)
+            } else {
+                (AudioDataType::PCMA, 0xd5)
+            };
+            r.audio(ms as u16, at, data_type.into(), &[toc, ms as u8]);
+        }
+        r.finish();
+
+        let files = files(&dir);
+        assert_eq!(files.len(), 2);
+        let (old, new) = (parse(&files[0]), parse(&files[1]));
+        assert_eq!(old.tracks[1].1, "A_OPUS");
+        assert_eq!(new.tracks[1].1, "A_MS/ACM");
+        let audio = |mkv: &Mkv| -> Vec<_> {
+            mkv.blocks
+                .iter()
+                .filter(|b| b.0 == 2)
+                .map(|b| b.3.clone())
+                .collect()
+        };
+        assert!(audio(&old).iter().all(|a| a[0] == 0x78));
+        // nothing lost in between, PCMA from 200 ms on in the new file
+        let new_audio = audio(&new);
+        assert_eq!(new_audio[0], [0xd5, 200]);
+        assert_eq!(audio(&old).len() + new_audio.len(), 30);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn records_connection_on_fake() {
+        let dir = temp_dir("recording");
+        let (fake, service) = fake_service();
+        let conn = service.create_connection().unwrap();
+        let id = conn.conn_id();
+        conn.start_recording(RecorderOptions::new(&dir)).unwrap();
+
+        let info: video_frame_info_t = VideoFrameInfo::new(VideoDataType::H264).into();
+        let pcma = audio_frame_info_t {
+            data_type: AudioDataType::PCMA.into(),
+        };
+        // no key frame yet, dropped
+        fake.video_data(id, 7, 0, &annexb(&[&[0x41, 0x9a, 0]]), &info);
+        fake.audio_data(id, 7, 0, &[0xd5; 160], &pcma);
+        fake.video_data(id, 7, 10, &key_frame(1), &info);
+        fake.audio_data(id, 7, 20, &[0xd5; 160], &pcma);
+        fake.user_offline(id, 7, 0);
+        fake.audio_data(id, 7, 40, &[0xd5; 160], &pcma);
+        conn.stop_recording();
+        // after it stopped, not recorded
+        fake.audio_data(id, 8, 0, &[0xd5; 160], &pcma);
+
+        let files = files(&dir);
+        assert_eq!(files.len(), 2, "{:?}", files);
+        assert!(files[0].ends_with("7-0000.mkv"));
+        let mkv = parse(&files[0]);
+        let codecs: Vec<_> = mkv.tracks.iter().map(|t| t.1.as_str()).collect();
+        assert_eq!(codecs, ["V_MPEG4/ISO/AVC", "A_MS/ACM"]);
+        assert_eq!(mkv.tracks[1].2[..4], [6, 0, 1, 0]);
+        let blocks: Vec<_> = mkv.blocks.iter().map(|b| (b.0, b.1, b.3.len())).collect();
+        // each track starts where it first arrived, here at once; SPS, PPS and IDR
+        assert_eq!(blocks, [(2, 0, 160), (1, 0, 26), (2, 20, 160)]);
+        // back after going offline: audio only, from 0 again
+        let mkv = parse(&files[1]);
+        assert_eq!(mkv.tracks.len(), 1);
+        assert_eq!(mkv.tracks[0].1, "A_MS/ACM");
+        assert_eq!(mkv.blocks.len(), 1);
+        assert_eq!((mkv.blocks[0].0, mkv.blocks[0].1), (2, 0));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
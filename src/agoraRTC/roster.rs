@@ -0,0 +1,195 @@
+//! The remote users of a [`Connection`](super::Connection), kept from
+//! `on_user_joined`, `on_user_offline`, `on_user_mute_audio` and `on_user_mute_video`.
+use super::handler::{Observer, Watchers};
+use log::warn;
+use num_derive::FromPrimitive;
+use num_enum::IntoPrimitive;
+use num_traits::FromPrimitive;
+use std::collections::BTreeMap;
+use std::sync::mpsc::Receiver;
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+/// `user_offline_reason_e`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, IntoPrimitive)]
+#[repr(u32)]
+pub enum UserOfflineReason {
+    /// Left the channel
+    QUIT = 0,
+    /// Timed out
+    DROPPED = 1,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteUser {
+    pub uid: u32,
+    /// When `on_user_joined` arrived
+    pub joined_at: SystemTime,
+    /// From `on_user_joined`
+    pub elapsed_ms: i32,
+    pub audio_muted: bool,
+    pub video_muted: bool,
+    /// Set once the user is offline, `None` while in the channel.
+    /// A reason the header doesn't know is reported as `DROPPED`.
+    pub offline_reason: Option<UserOfflineReason>,
+}
+
+impl RemoteUser {
+    fn new(uid: u32, elapsed_ms: i32) -> Self {
+        RemoteUser {
+            uid,
+            joined_at: SystemTime::now(),
+            elapsed_ms,
+            audio_muted: false,
+            video_muted: false,
+            offline_reason: None,
+        }
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.offline_reason.is_none()
+    }
+}
+
+/// A change of the roster, with the entry as it is afterwards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RosterChange {
+    Joined(RemoteUser),
+    Offline(RemoteUser),
+    AudioMuted(RemoteUser),
+    VideoMuted(RemoteUser),
+}
+
+#[derive(Default)]
+struct State {
+    users: BTreeMap<u32, RemoteUser>,
+    watchers: Watchers<RosterChange>,
+}
+
+/// Observer of a connection keeping its roster.
+#[derive(Default)]
+pub(crate) struct Roster {
+    state: Mutex<State>,
+}
+
+impl Roster {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Users of the previous channel are forgotten, those still online go
+    /// `Offline` (`DROPPED`) first.
+    pub(crate) fn clear(&self) {
+        let mut st = self.state();
+        let st = &mut *st;
+        for mut user in std::mem::take(&mut st.users).into_values() {
+            if user.is_online() {
+                user.offline_reason = Some(UserOfflineReason::DROPPED);
+                st.watchers.send(RosterChange::Offline(user));
+            }
+        }
+    }
+
+    pub(crate) fn users(&self) -> Vec<RemoteUser> {
+        self.state().users.values().cloned().collect()
+    }
+
+    pub(crate) fn watch(&self) -> Receiver<RosterChange> {
+        self.state().watchers.watch()
+    }
+
+    /// Update (or add) the entry of `uid` and tell the watchers.
+    fn update<F, C>(&self, uid: u32, f: F, change: C)
+    where
+        F: FnOnce(&mut RemoteUser),
+        C: FnOnce(RemoteUser) -> RosterChange,
+    {
+        let mut st = self.state();
+        let user = st
+            .users
+            .entry(uid)
+            .or_insert_with(|| RemoteUser::new(uid, 0));
+        f(user);
+        let change = change(user.clone());
+        st.watchers.send(change);
+    }
+}
+
+impl Observer for Roster {
+    fn on_user_joined(&self, _conn_id: u32, uid: u32, elapsed_ms: i32) {
+        self.update(
+            uid,
+            // a rejoin starts over
+            |u| *u = RemoteUser::new(uid, elapsed_ms),
+            RosterChange::Joined,
+        )
+    }
+
+    fn on_user_offline(&self, conn_id: u32, uid: u32, reason: i32) {
+        let reason = UserOfflineReason::from_i32(reason).unwrap_or_else(|| {
+            warn!(
+                "unknown user_offline_reason_e {}, conn_id: {}, uid: {}",
+                reason, conn_id, uid
+            );
+            UserOfflineReason::DROPPED
+        });
+        self.update(
+            uid,
+            |u| u.offline_reason = Some(reason),
+            RosterChange::Offline,
+        )
+    }
+
+    fn on_user_mute_audio(&self, _conn_id: u32, uid: u32, muted: bool) {
+        self.update(uid, |u| u.audio_muted = muted, RosterChange::AudioMuted)
+    }
+
+    fn on_user_mute_video(&self, _conn_id: u32, uid: u32, muted: bool) {
+        self.update(uid, |u| u.video_muted = muted, RosterChange::VideoMuted)
+    }
+
+    fn on_rebuilt(&self, _old_conn_id: u32, _conn_id: u32) {
+        // who's there is told again once the new connection joins
+        self.clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_users_and_notifies() {
+        let roster = Roster::default();
+        let rx = roster.watch();
+        roster.on_user_joined(1, 7, 120);
+        roster.on_user_mute_audio(1, 7, true);
+        roster.on_user_joined(1, 8, 0);
+        roster.on_user_offline(1, 8, 0);
+        roster.on_user_offline(1, 7, 42);
+
+        let users = roster.users();
+        assert_eq!(users.len(), 2);
+        assert_eq!(
+            (users[0].uid, users[0].elapsed_ms, users[0].audio_muted),
+            (7, 120, true)
+        );
+        assert_eq!(users[0].offline_reason, Some(UserOfflineReason::DROPPED));
+        assert_eq!(users[1].offline_reason, Some(UserOfflineReason::QUIT));
+
+        let changes: Vec<_> = rx.try_iter().collect();
+        assert_eq!(changes.len(), 5);
+        assert!(matches!(&changes[1], RosterChange::AudioMuted(u) if u.uid == 7 && u.audio_muted));
+        assert!(matches!(&changes[3], RosterChange::Offline(u) if u.uid == 8));
+
+        // back again, starts clean
+        roster.on_user_joined(1, 7, 5);
+        let u = &roster.users()[0];
+        assert!(u.is_online() && !u.audio_muted);
+        drop(rx);
+        roster.on_user_joined(1, 9, 0);
+        assert!(roster.state().watchers.is_empty());
+        roster.clear();
+        assert!(roster.users().is_empty());
+    }
+}
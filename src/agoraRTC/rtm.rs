@@ -0,0 +1,673 @@
+use super::super::ffi::*;
+use super::super::utils::*;
+use super::backend::RtcBackend;
+use super::rate_limit::{OverflowPolicy, Pacer, Pop, Push, QueueStats, TokenBucket};
+use super::service::RtcService;
+use super::{AgoraError, Trampolines};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// `AGORA_RTM_UID_MAX_LEN`. The uid has to be shorter than this.
+pub const RTM_UID_MAX_LEN: usize = 64;
+/// `AGORA_RTM_DATA_MAX_LEN`
+pub const RTM_DATA_MAX_LEN: usize = 32 * 1024;
+/// `agora_rtc_send_rtm_data` is limited to 60 messages per second.
+pub const RTM_MAX_QPS: u32 = 60;
+
+/// How [`RtmClient`] paces sends.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RtmRateLimit {
+    /// messages per second
+    pub rate: u32,
+    /// messages which may go out back to back after being idle
+    pub burst: u32,
+    /// messages waiting for their turn, at least 1
+    pub queue_len: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for RtmRateLimit {
+    /// `RTM_MAX_QPS` spread evenly, 256 queued, new messages wait for room.
+    fn default() -> Self {
+        RtmRateLimit {
+            rate: RTM_MAX_QPS,
+            burst: 1,
+            queue_len: 256,
+            overflow: OverflowPolicy::BLOCK,
+        }
+    }
+}
+
+/// `rtm_event_type_e`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
+#[repr(u32)]
+pub enum RtmEvent {
+    LOGIN = 0,
+    KICKOFF = 1,
+    EXIT = 2,
+}
+
+/// Events of `agora_rtm_handler_t`, except the send results which are
+/// delivered to the [`RtmSend`] returned by [`RtmClient::send`].
+///
+/// Called from SDK threads. Panics are caught at the FFI boundary.
+pub trait RtmHandler: Send + Sync {
+    /// `data` is only valid during the call.
+    fn on_rtm_data(&self, rtm_uid: &str, data: &[u8]) {
+        info!("rtm_data, from: {:?}, {} bytes", rtm_uid, data.len());
+    }
+
+    /// `result` is the `err_code` of the event
+    fn on_rtm_event(&self, rtm_uid: &str, event: RtmEvent, result: Result<(), AgoraError>) {
+        match result {
+            Ok(()) => info!("rtm_event, uid: {:?}, {:?}", rtm_uid, event),
+            Err(e) => warn!("rtm_event, uid: {:?}, {:?}, {}", rtm_uid, event, e),
+        }
+    }
+}
+
+/// Only logs.
+pub struct DefaultRtmHandler;
+
+impl RtmHandler for DefaultRtmHandler {}
+
+fn rtm_result(code: u32) -> Result<(), AgoraError> {
+    if code == rtm_err_code_e_ERR_RTM_OK {
+        Ok(())
+    } else {
+        Err(AgoraError::from_rtm_code(code))
+    }
+}
+
+fn check_uid(rtm_uid: &str) -> Result<CString, AgoraError> {
+    if rtm_uid.len() >= RTM_UID_MAX_LEN {
+        return Err(AgoraError::BufferTooLarge {
+            len: rtm_uid.len(),
+            max: RTM_UID_MAX_LEN - 1,
+        });
+    }
+    Ok(rtm_uid.to_c_string()?)
+}
+
+fn check_data(data: &[u8]) -> Result<(), AgoraError> {
+    if data.len() > RTM_DATA_MAX_LEN {
+        return Err(AgoraError::BufferTooLarge {
+            len: data.len(),
+            max: RTM_DATA_MAX_LEN,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Slot {
+    result: Mutex<Option<Result<(), AgoraError>>>,
+    cond: Condvar,
+}
+
+impl Slot {
+    fn complete(&self, result: Result<(), AgoraError>) {
+        let mut r = self.result.lock().unwrap_or_else(|e| e.into_inner());
+        if r.is_none() {
+            *r = Some(result);
+            self.cond.notify_all();
+        }
+    }
+}
+
+/// A message handed to the SDK, resolved by `on_send_rtm_data_result`.
+pub struct RtmSend {
+    msg_id: u32,
+    slot: Arc<Slot>,
+}
+
+impl RtmSend {
+    pub fn msg_id(&self) -> u32 {
+        self.msg_id
+    }
+
+    /// The result if it already arrived.
+    pub fn try_result(&self) -> Option<Result<(), AgoraError>> {
+        self.slot
+            .result
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Block until the result arrives.
+    /// Don't call it from an SDK callback, the result would never come.
+    pub fn wait(&self) -> Result<(), AgoraError> {
+        let mut r = self.slot.result.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(res) = r.as_ref() {
+                return res.clone();
+            }
+            r = self.slot.cond.wait(r).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Like `wait` but gives up after `timeout`, returning `None`.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<Result<(), AgoraError>> {
+        let deadline = Instant::now() + timeout;
+        let mut r = self.slot.result.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(res) = r.as_ref() {
+                return Some(res.clone());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            r = self
+                .slot
+                .cond
+                .wait_timeout(r, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+}
+
+/// State the RTM trampolines dispatch to. There is only one RTM login per process.
+pub(crate) struct RtmShared {
+    backend: Arc<dyn RtcBackend>,
+    handler: RwLock<Option<Arc<dyn RtmHandler>>>,
+    pending: Mutex<HashMap<u32, Arc<Slot>>>,
+    logged_in: AtomicBool,
+}
+
+impl RtmShared {
+    fn new(backend: Arc<dyn RtcBackend>) -> Self {
+        RtmShared {
+            backend,
+            handler: RwLock::new(None),
+            pending: Mutex::new(HashMap::new()),
+            logged_in: AtomicBool::new(false),
+        }
+    }
+
+    fn pending(&self) -> MutexGuard<'_, HashMap<u32, Arc<Slot>>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn user(&self) -> Arc<dyn RtmHandler> {
+        let h = self.handler.read().unwrap_or_else(|e| e.into_inner());
+        match h.as_ref() {
+            Some(h) => h.clone(),
+            None => Arc::new(DefaultRtmHandler),
+        }
+    }
+
+    pub(crate) fn on_data(&self, rtm_uid: &str, data: &[u8]) {
+        self.user().on_rtm_data(rtm_uid, data)
+    }
+
+    pub(crate) fn on_event(&self, rtm_uid: &str, event_type: u32, err_code: u32) {
+        let event = match RtmEvent::from_u32(event_type) {
+            Some(e) => e,
+            None => {
+                warn!("unknown rtm event {}, uid: {:?}", event_type, rtm_uid);
+                return;
+            }
+        };
+        let result = rtm_result(err_code);
+        match event {
+            RtmEvent::LOGIN => self.logged_in.store(result.is_ok(), Ordering::SeqCst),
+            RtmEvent::KICKOFF | RtmEvent::EXIT => self.logged_in.store(false, Ordering::SeqCst),
+        }
+        self.user().on_rtm_event(rtm_uid, event, result)
+    }
+
+    pub(crate) fn on_send_result(&self, msg_id: u32, err_code: u32) {
+        let slot = self.pending().remove(&msg_id);
+        match slot {
+            Some(s) => s.complete(rtm_result(err_code)),
+            None => warn!("rtm send result for unknown msg_id {}", msg_id),
+        }
+    }
+
+    /// Hand a message to the SDK. `slot` is resolved by `on_send_result`.
+    fn send_now(
+        &self,
+        peer: &CStr,
+        msg_id: u32,
+        data: &[u8],
+        slot: Arc<Slot>,
+    ) -> Result<(), AgoraError> {
+        // the result may arrive before agora_rtc_send_rtm_data returns
+        self.pending().insert(msg_id, slot);
+        let res = err_2_result(self.backend.send_rtm_data(peer, msg_id, data));
+        if res.is_err() {
+            self.pending().remove(&msg_id);
+        }
+        res
+    }
+
+    /// fail everything still in flight
+    fn cancel_all(&self) {
+        let pending: Vec<_> = self.pending().drain().collect();
+        for (_, s) in pending {
+            s.complete(Err(AgoraError::RtmLoggedOut));
+        }
+    }
+}
+
+struct Outgoing {
+    peer: CString,
+    data: Vec<u8>,
+    msg_id: u32,
+    slot: Arc<Slot>,
+}
+
+struct QueueState {
+    pacer: Pacer<Outgoing>,
+    closed: bool,
+}
+
+/// Messages waiting for a token. Drained by a thread running `run`.
+struct SendQueue {
+    state: Mutex<QueueState>,
+    // signaled on push, pop and close
+    cond: Condvar,
+}
+
+impl SendQueue {
+    fn new(limit: &RtmRateLimit) -> Self {
+        let bucket = TokenBucket::new(limit.rate, limit.burst, Instant::now());
+        SendQueue {
+            state: Mutex::new(QueueState {
+                pacer: Pacer::new(bucket, limit.queue_len, limit.overflow),
+                closed: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, mut msg: Outgoing) -> Result<(), AgoraError> {
+        let mut st = self.state();
+        loop {
+            if st.closed {
+                return Err(AgoraError::RtmLoggedOut);
+            }
+            match st.pacer.push(msg) {
+                Push::Queued => break,
+                Push::Dropped(dropped) => {
+                    dropped.slot.complete(Err(AgoraError::RtmDropped));
+                    break;
+                }
+                Push::Full(m) if st.pacer.policy() == OverflowPolicy::BLOCK => {
+                    msg = m;
+                    st = self.cond.wait(st).unwrap_or_else(|e| e.into_inner());
+                }
+                Push::Full(_) => return Err(AgoraError::RtmQueueFull),
+            }
+        }
+        self.cond.notify_all();
+        Ok(())
+    }
+
+    fn close(&self) {
+        self.state().closed = true;
+        self.cond.notify_all();
+    }
+
+    fn run(&self, shared: &RtmShared) {
+        let mut st = self.state();
+        while !st.closed {
+            match st.pacer.pop(Instant::now()) {
+                Pop::Empty => st = self.cond.wait(st).unwrap_or_else(|e| e.into_inner()),
+                Pop::Wait(d) => {
+                    st = self
+                        .cond
+                        .wait_timeout(st, d)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                Pop::Ready(m) => {
+                    // room for blocked senders
+                    self.cond.notify_all();
+                    drop(st);
+                    if let Err(e) = shared.send_now(&m.peer, m.msg_id, &m.data, m.slot.clone()) {
+                        m.slot.complete(Err(e));
+                    }
+                    st = self.state();
+                }
+            }
+        }
+        for m in st.pacer.drain() {
+            m.slot.complete(Err(AgoraError::RtmLoggedOut));
+        }
+    }
+}
+
+lazy_static! {
+    static ref RTM: RwLock<Option<Arc<RtmShared>>> = RwLock::new(None);
+}
+
+/// The logged in client, if any.
+pub(crate) fn rtm_shared() -> Option<Arc<RtmShared>> {
+    RTM.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+impl Trampolines for agora_rtm_handler_t {
+    /// trampolines dispatching to the logged in [`RtmClient`], see `callbacks.rs`
+    fn new() -> Self {
+        use super::super::callbacks::*;
+        agora_rtm_handler_t {
+            on_rtm_data: Some(on_rtm_data),
+            on_rtm_event: Some(on_rtm_event),
+            on_send_rtm_data_result: Some(on_send_rtm_data_result),
+        }
+    }
+}
+
+/// A login to the Agora RTM service, a reliable data channel to other RTM uids.
+///
+/// Only one can exist at a time. Dropping it logs out and fails the sends still in flight.
+pub struct RtmClient {
+    shared: Arc<RtmShared>,
+    next_msg_id: AtomicU32,
+    queue: Option<(Arc<SendQueue>, JoinHandle<()>)>,
+    rtm_uid: String,
+    // the SDK keeps the pointer
+    _handler: Box<agora_rtm_handler_t>,
+    _service: RtcService,
+}
+
+impl RtmClient {
+    /// `rtm_uid` is not the RTC uid and must be shorter than 64 bytes.
+    /// `token` is the RTM token, `None` if token authorization is disabled.
+    ///
+    /// Returns once the request is sent. The outcome arrives as [`RtmEvent::LOGIN`],
+    /// see `set_handler` and `is_logged_in`.
+    ///
+    /// Sends are paced by the default [`RtmRateLimit`].
+    pub fn login(
+        service: &RtcService,
+        rtm_uid: &str,
+        token: Option<&str>,
+    ) -> Result<Self, AgoraError> {
+        Self::login_with(service, rtm_uid, token, Some(RtmRateLimit::default()))
+    }
+
+    /// `login` with another rate limit. `None` sends right away,
+    /// and whatever goes over `RTM_MAX_QPS` is dropped by the SDK.
+    pub fn login_with(
+        service: &RtcService,
+        rtm_uid: &str,
+        token: Option<&str>,
+        rate_limit: Option<RtmRateLimit>,
+    ) -> Result<Self, AgoraError> {
+        let uid = check_uid(rtm_uid)?;
+        let token = token.map(CString::new).transpose()?;
+        let mut rtm = RTM.write().unwrap_or_else(|e| e.into_inner());
+        if rtm.is_some() {
+            return Err(AgoraError::RtmAlreadyLoggedIn);
+        }
+        let shared = Arc::new(RtmShared::new(service.backend().clone()));
+        *rtm = Some(shared.clone());
+        // the SDK may call back before login returns
+        drop(rtm);
+        let handler = Box::new(agora_rtm_handler_t::new());
+        let code = service
+            .backend()
+            .login_rtm(&uid, token.as_deref(), &handler);
+        if let Err(e) = err_2_result(code) {
+            *RTM.write().unwrap_or_else(|e| e.into_inner()) = None;
+            return Err(e);
+        }
+        let queue = match rate_limit {
+            Some(limit) => {
+                let q = Arc::new(SendQueue::new(&limit));
+                let (q2, s2) = (q.clone(), shared.clone());
+                let spawned = std::thread::Builder::new()
+                    .name("rtm-send".to_owned())
+                    .spawn(move || q2.run(&s2));
+                match spawned {
+                    Ok(t) => Some((q, t)),
+                    Err(e) => {
+                        warn!("failed to spawn rtm send thread, sending unpaced: {}", e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        Ok(RtmClient {
+            shared,
+            next_msg_id: AtomicU32::new(1),
+            queue,
+            rtm_uid: rtm_uid.to_owned(),
+            _handler: handler,
+            _service: service.clone(),
+        })
+    }
+
+    pub fn rtm_uid(&self) -> &str {
+        &self.rtm_uid
+    }
+
+    /// Whether `RtmEvent::LOGIN` succeeded and no kickoff/exit came after.
+    pub fn is_logged_in(&self) -> bool {
+        self.shared.logged_in.load(Ordering::SeqCst)
+    }
+
+    /// Route RTM data and events to `handler`. Without one they are only logged.
+    pub fn set_handler<H: RtmHandler + 'static>(&self, handler: H) {
+        self.set_handler_arc(Arc::new(handler));
+    }
+
+    pub fn set_handler_arc(&self, handler: Arc<dyn RtmHandler>) {
+        let mut h = self
+            .shared
+            .handler
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        *h = Some(handler);
+    }
+
+    fn alloc_msg_id(&self) -> u32 {
+        // skip 0 so a zeroed id never matches a live message
+        loop {
+            let id = self.next_msg_id.fetch_add(1, Ordering::Relaxed);
+            if id != 0 {
+                return id;
+            }
+        }
+    }
+
+    /// Send `data` (at most `RTM_DATA_MAX_LEN` bytes) to `peer`.
+    ///
+    /// With a rate limit the message is queued and the returned [`RtmSend`] also
+    /// resolves to an error if it is dropped or the SDK refuses it.
+    /// With `OverflowPolicy::BLOCK` this waits for room in the queue.
+    pub fn send(&self, peer: &str, data: &[u8]) -> Result<RtmSend, AgoraError> {
+        let peer = check_uid(peer)?;
+        check_data(data)?;
+        let msg_id = self.alloc_msg_id();
+        let slot = Arc::new(Slot::default());
+        match &self.queue {
+            Some((q, _)) => q.push(Outgoing {
+                peer,
+                data: data.to_vec(),
+                msg_id,
+                slot: slot.clone(),
+            })?,
+            None => self.shared.send_now(&peer, msg_id, data, slot.clone())?,
+        }
+        Ok(RtmSend { msg_id, slot })
+    }
+
+    /// Number of sends waiting for `on_send_rtm_data_result`.
+    pub fn in_flight(&self) -> usize {
+        self.shared.pending().len()
+    }
+
+    /// Counters of the send queue, `None` without a rate limit.
+    pub fn queue_stats(&self) -> Option<QueueStats> {
+        self.queue.as_ref().map(|(q, _)| q.state().pacer.stats())
+    }
+}
+
+impl Drop for RtmClient {
+    fn drop(&mut self) {
+        if let Some((q, t)) = self.queue.take() {
+            q.close();
+            if t.join().is_err() {
+                warn!("rtm send thread panicked");
+            }
+        }
+        if let Err(e) = err_2_result(self.shared.backend.logout_rtm()) {
+            warn!("agora_rtc_logout_rtm failed: {}", e);
+        }
+        *RTM.write().unwrap_or_else(|e| e.into_inner()) = None;
+        self.shared.cancel_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::callbacks;
+    use super::super::fake::{fake_service, Call, FakeBackend};
+    use super::super::RtmError;
+    use super::*;
+    use std::ptr::null;
+
+    lazy_static! {
+        // RTM is global, tests touching it take turns
+        static ref RTM_TEST: Mutex<()> = Mutex::new(());
+    }
+
+    #[test]
+    fn limits() {
+        assert!(check_uid(&"a".repeat(63)).is_ok());
+        assert_eq!(
+            check_uid(&"a".repeat(64)).err(),
+            Some(AgoraError::BufferTooLarge { len: 64, max: 63 })
+        );
+        assert!(check_data(&[0; RTM_DATA_MAX_LEN]).is_ok());
+        assert_eq!(
+            check_data(&[0; RTM_DATA_MAX_LEN + 1]).err(),
+            Some(AgoraError::BufferTooLarge {
+                len: RTM_DATA_MAX_LEN + 1,
+                max: RTM_DATA_MAX_LEN
+            })
+        );
+    }
+
+    #[test]
+    fn send_results_resolve_by_msg_id() {
+        let _turn = RTM_TEST.lock().unwrap_or_else(|e| e.into_inner());
+        let shared = Arc::new(RtmShared::new(FakeBackend::new()));
+        let a = Arc::new(Slot::default());
+        let b = Arc::new(Slot::default());
+        shared.pending().insert(1, a.clone());
+        shared.pending().insert(2, b.clone());
+        *RTM.write().unwrap() = Some(shared.clone());
+        unsafe {
+            callbacks::on_send_rtm_data_result(2, rtm_err_code_e_ERR_RTM_FAILED);
+            callbacks::on_rtm_event(null(), rtm_event_type_e_RTM_EVENT_TYPE_LOGIN, 0);
+        }
+        *RTM.write().unwrap() = None;
+        let a = RtmSend { msg_id: 1, slot: a };
+        let b = RtmSend { msg_id: 2, slot: b };
+        assert_eq!(b.wait(), Err(AgoraError::Rtm(RtmError::FAILED)));
+        assert_eq!(a.wait_timeout(Duration::from_millis(1)), None);
+        assert!(shared.logged_in.load(Ordering::SeqCst));
+        shared.cancel_all();
+        assert_eq!(a.try_result(), Some(Err(AgoraError::RtmLoggedOut)));
+    }
+
+    #[test]
+    fn queue_drops_oldest_and_cancels_on_close() {
+        let limit = RtmRateLimit {
+            rate: 1,
+            burst: 1,
+            queue_len: 1,
+            overflow: OverflowPolicy::DROP_OLDEST,
+        };
+        let q = SendQueue::new(&limit);
+        let msg = |id| Outgoing {
+            peer: CString::new("peer").unwrap(),
+            data: vec![],
+            msg_id: id,
+            slot: Arc::new(Slot::default()),
+        };
+        let (m1, m2) = (msg(1), msg(2));
+        let (s1, s2) = (m1.slot.clone(), m2.slot.clone());
+        q.push(m1).unwrap();
+        q.push(m2).unwrap();
+        assert_eq!(
+            *s1.result.lock().unwrap(),
+            Some(Err(AgoraError::RtmDropped))
+        );
+        let stats = q.state().pacer.stats();
+        assert_eq!((stats.depth, stats.dropped), (1, 1));
+        // closed before the worker ever ran, so nothing reaches the SDK
+        q.close();
+        q.run(&RtmShared::new(FakeBackend::new()));
+        assert_eq!(
+            *s2.result.lock().unwrap(),
+            Some(Err(AgoraError::RtmLoggedOut))
+        );
+        assert_eq!(q.push(msg(3)).err(), Some(AgoraError::RtmLoggedOut));
+    }
+
+    #[test]
+    fn login_send_logout() {
+        let _turn = RTM_TEST.lock().unwrap_or_else(|e| e.into_inner());
+        let (fake, service) = fake_service();
+        fake.fail("login_rtm", -1);
+        assert!(RtmClient::login(&service, "me", None).is_err());
+        assert!(rtm_shared().is_none());
+        fake.clear_failures();
+
+        let client = RtmClient::login_with(&service, "me", Some("tok"), None).unwrap();
+        fake.rtm_event("me", rtm_event_type_e_RTM_EVENT_TYPE_LOGIN, 0);
+        assert!(client.is_logged_in());
+        let sent = client.send("peer", b"hi").unwrap();
+        assert_eq!(client.in_flight(), 1);
+        fake.send_rtm_data_result(sent.msg_id(), 0);
+        assert_eq!(sent.wait(), Ok(()));
+        let lost = client.send("peer", b"bye").unwrap();
+        drop(client);
+        assert_eq!(lost.try_result(), Some(Err(AgoraError::RtmLoggedOut)));
+        drop(service);
+        let mut calls = fake.take_calls();
+        calls.drain(..2);
+        assert_eq!(
+            calls,
+            vec![
+                Call::LoginRtm {
+                    rtm_uid: "me".into(),
+                    token: Some("tok".into())
+                },
+                Call::SendRtmData {
+                    rtm_uid: "peer".into(),
+                    msg_id: 1,
+                    data: b"hi".to_vec()
+                },
+                Call::SendRtmData {
+                    rtm_uid: "peer".into(),
+                    msg_id: 2,
+                    data: b"bye".to_vec()
+                },
+                Call::LogoutRtm,
+                Call::Fini,
+            ]
+        );
+    }
+}
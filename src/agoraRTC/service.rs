@@ -0,0 +1,223 @@
+use super::super::callbacks::in_sdk_callback;
+use super::super::ffi::*;
+use super::super::utils::*;
+use super::backend::{default_backend, RtcBackend};
+use super::bitrate::check_bwe;
+use super::connection::{send_audio, send_video, Connection};
+use super::{
+    AgoraError, AudioDataType, NetworkEvent, OwnedServiceOption, RtcServiceOption, Trampolines,
+    VideoFrameInfo,
+};
+use lazy_static::lazy_static;
+use log::{error, warn};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// `CONNECTION_ID_ALL` in the header. bindgen doesn't pick up casted macros.
+pub const CONNECTION_ID_ALL: u32 = 0;
+/// `CONNECTION_ID_INVALID` in the header.
+pub const CONNECTION_ID_INVALID: u32 = u32::MAX;
+
+/// The SDK can only be initialized once per process, so this is global.
+/// One per backend, so fakes in tests don't share the real SDK's state.
+struct SdkState {
+    /// number of live `RtcService`
+    refs: usize,
+    backend: Arc<dyn RtcBackend>,
+    // the SDK keeps the pointers we hand to `agora_rtc_init`,
+    // so these are boxed and live as long as the SDK is initialized
+    app_id: CString,
+    _handlers: Box<agora_rtc_event_handler_t>,
+    _option: Box<OwnedServiceOption>,
+}
+
+// only pointers to the boxed data above, which nobody mutates after init
+unsafe impl Send for SdkState {}
+
+lazy_static! {
+    static ref SDK: Mutex<HashMap<usize, SdkState>> = Mutex::new(HashMap::new());
+}
+
+fn sdk() -> MutexGuard<'static, HashMap<usize, SdkState>> {
+    SDK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn key(backend: &Arc<dyn RtcBackend>) -> usize {
+    Arc::as_ptr(backend) as *const () as usize
+}
+
+/// A reference to the initialized SDK.
+///
+/// `agora_rtc_init` runs when the first one is created and `agora_rtc_fini` when
+/// the last one (including clones and the ones held by each [`Connection`]) is dropped,
+/// so several users in one process don't tear the SDK down under each other.
+/// Neither may happen on an SDK callback thread.
+pub struct RtcService {
+    // not constructible outside, so every instance holds one ref
+    backend: Arc<dyn RtcBackend>,
+}
+
+impl RtcService {
+    /// init SDK with the default event handler, which dispatches to the
+    /// [`EventHandler`](super::EventHandler) of each connection
+    pub fn init(app_id: &str, option: RtcServiceOption) -> Result<Self, AgoraError> {
+        Self::init_with_handlers(app_id, agora_rtc_event_handler_t::new(), option)
+    }
+
+    /// init SDK with raw C callbacks.
+    /// If the SDK is already initialized with the same `app_id` this only takes
+    /// another reference, and `handlers` and `option` are ignored.
+    pub fn init_with_handlers(
+        app_id: &str,
+        handlers: agora_rtc_event_handler_t,
+        option: RtcServiceOption,
+    ) -> Result<Self, AgoraError> {
+        Self::init_with_backend(default_backend(), app_id, handlers, option)
+    }
+
+    /// `init_with_handlers` on top of `backend` instead of the real SDK,
+    /// e.g. a [`FakeBackend`](super::FakeBackend) in tests.
+    /// Everything created from this service uses the same backend.
+    pub fn init_with_backend(
+        backend: Arc<dyn RtcBackend>,
+        app_id: &str,
+        handlers: agora_rtc_event_handler_t,
+        option: RtcServiceOption,
+    ) -> Result<Self, AgoraError> {
+        if in_sdk_callback() {
+            return Err(AgoraError::Reentrant);
+        }
+        let app_id = app_id.to_c_string()?;
+        let mut sdk = sdk();
+        if let Some(state) = sdk.get_mut(&key(&backend)) {
+            if state.app_id != app_id {
+                return Err(AgoraError::AlreadyInitialized);
+            }
+            if state.refs > 0 {
+                warn!("SDK already initialized, handlers and option are ignored");
+            }
+            state.refs += 1;
+            return Ok(RtcService { backend });
+        }
+        let handlers = Box::new(handlers);
+        let mut option = Box::new(OwnedServiceOption::try_from(&option)?);
+        err_2_result(backend.init(&app_id, &handlers, &mut option.raw))?;
+        sdk.insert(
+            key(&backend),
+            SdkState {
+                refs: 1,
+                backend: backend.clone(),
+                app_id,
+                _handlers: handlers,
+                _option: option,
+            },
+        );
+        Ok(RtcService { backend })
+    }
+
+    /// Whether some `RtcService` currently keeps the SDK initialized.
+    pub fn is_initialized() -> bool {
+        matches!(sdk().get(&key(&default_backend())), Some(s) if s.refs > 0)
+    }
+
+    /// What this service calls into.
+    pub fn backend(&self) -> &Arc<dyn RtcBackend> {
+        &self.backend
+    }
+
+    /// Create a new connection. Each connection can join its own channel.
+    pub fn create_connection(&self) -> Result<Connection, AgoraError> {
+        Connection::new(self.clone())
+    }
+
+    /// Send a video frame to all connections (`CONNECTION_ID_ALL`).
+    pub fn send_video_data(&self, buf: &[u8], info: &VideoFrameInfo) -> Result<(), AgoraError> {
+        send_video(&*self.backend, CONNECTION_ID_ALL, buf, info)
+    }
+
+    /// Send an audio frame to all connections (`CONNECTION_ID_ALL`).
+    /// PCM is not validated here since each connection may configure its own codec.
+    pub fn send_audio_data(&self, buf: &[u8], data_type: AudioDataType) -> Result<(), AgoraError> {
+        send_audio(&*self.backend, CONNECTION_ID_ALL, buf, data_type)
+    }
+
+    /// Mute local audio of all connections (`CONNECTION_ID_ALL`).
+    pub fn mute_local_audio(&self, is_muted: bool) -> Result<(), AgoraError> {
+        err_2_result(self.backend.mute_local_audio(CONNECTION_ID_ALL, is_muted))
+    }
+
+    /// Bandwidth estimation range of all connections (`CONNECTION_ID_ALL`), in bps.
+    pub fn set_bwe_params(
+        &self,
+        min_bps: u32,
+        max_bps: u32,
+        start_bps: u32,
+    ) -> Result<(), AgoraError> {
+        check_bwe(min_bps, max_bps, start_bps)?;
+        let code = self
+            .backend
+            .set_bwe_param(CONNECTION_ID_ALL, min_bps, max_bps, start_bps);
+        err_2_result(code)
+    }
+
+    /// Tell the SDK the network went down, came back or changed, so it reconnects
+    /// right away instead of waiting for its timeouts.
+    /// With the `netlink` feature `NetlinkWatcher` does this by itself on Linux.
+    pub fn notify_network_event(&self, event: NetworkEvent) -> Result<(), AgoraError> {
+        err_2_result(self.backend.notify_network_event(event.into()))
+    }
+}
+
+impl Clone for RtcService {
+    fn clone(&self) -> Self {
+        if let Some(state) = sdk().get_mut(&key(&self.backend)) {
+            state.refs += 1;
+        }
+        RtcService {
+            backend: self.backend.clone(),
+        }
+    }
+}
+
+impl Drop for RtcService {
+    fn drop(&mut self) {
+        let mut sdk = sdk();
+        let key = key(&self.backend);
+        let state = match sdk.get_mut(&key) {
+            Some(s) => s,
+            None => return,
+        };
+        state.refs = state.refs.saturating_sub(1);
+        if state.refs > 0 {
+            return;
+        }
+        if in_sdk_callback() {
+            // keep it initialized, the next `init` picks it up again
+            // and the next drop outside of a callback releases it
+            error!("last RtcService dropped in an SDK callback, agora_rtc_fini skipped");
+            return;
+        }
+        if let Err(e) = err_2_result(state.backend.fini()) {
+            warn!("agora_rtc_fini failed: {}", e);
+        }
+        sdk.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::callbacks::CallbackScope;
+    use super::super::LogLevel;
+    use super::*;
+
+    #[test]
+    fn no_init_from_callback() {
+        let _scope = CallbackScope::enter();
+        let opt = RtcServiceOption::new("", "", "", LogLevel::DEFAULT).unwrap();
+        assert_eq!(
+            RtcService::init("app", opt).err(),
+            Some(AgoraError::Reentrant)
+        );
+    }
+}
@@ -0,0 +1,475 @@
+//! The state of a [`Connection`](super::Connection), driven by the SDK callbacks,
+//! and the reconnection done when the SDK doesn't manage to rejoin by itself.
+//!
+//! ```text
+//! IDLE -> JOINING -> JOINED -> LOST -> JOINED (on_rejoin_channel_success)
+//!            |                   |
+//!          FAILED                +--> REJOINING -> JOINED
+//!                                          |
+//!                                        FAILED
+//! ```
+//! `leave_channel` goes to `LEFT` from anywhere.
+use super::connection::ConnShared;
+use super::handler::{Observer, Watchers};
+use super::service::RtcService;
+use super::{AgoraError, SdkError};
+use log::{error, info, warn};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Created, `join_channel` not called yet
+    IDLE,
+    /// `join_channel` returned, waiting for `on_join_channel_success`
+    JOINING,
+    JOINED,
+    /// `on_connection_lost`, the SDK is trying to rejoin by itself
+    LOST,
+    /// Rebuilt by the [`ReconnectPolicy`], waiting for `on_join_channel_success`
+    REJOINING,
+    /// `leave_channel`
+    LEFT,
+    /// The join was refused, or the [`ReconnectPolicy`] gave up
+    FAILED,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StateChange {
+    /// Changes when the connection is rebuilt
+    pub conn_id: u32,
+    pub from: ConnectionState,
+    pub to: ConnectionState,
+}
+
+/// When to stop waiting for the SDK to rejoin and leave, destroy, create and join again.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// How long to stay `LOST` before rebuilding
+    pub rejoin_timeout: Duration,
+    /// How long a rebuilt connection has to reach `JOINED`
+    pub join_timeout: Duration,
+    /// Rebuilds before giving up with `FAILED`, at least 1
+    pub max_attempts: u32,
+    /// Wait before the second rebuild, doubled before each one after
+    pub retry_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            rejoin_timeout: Duration::from_secs(30),
+            join_timeout: Duration::from_secs(10),
+            max_attempts: 3,
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Errors from `on_error` meaning the join won't succeed.
+pub(crate) fn fails_join(code: i32) -> bool {
+    matches!(
+        AgoraError::from_code(code),
+        AgoraError::Sdk(
+            SdkError::JOIN_CHANNEL_REJECTED
+                | SdkError::INVALID_APP_ID
+                | SdkError::INVALID_CHANNEL_NAME
+                | SdkError::NO_SERVER_RESOURCES
+                | SdkError::LOOKUP_CHANNEL_REJECTED
+                | SdkError::OPEN_CHANNEL_REJECTED
+                | SdkError::TOKEN_EXPIRED
+                | SdkError::INVALID_TOKEN
+                | SdkError::DYNAMIC_TOKEN_BUT_USE_STATIC_KEY
+                | SdkError::OPEN_CHANNEL_INVALID_TICKET
+                | SdkError::CLIENT_IS_BANNED_BY_SERVER
+        )
+    )
+}
+
+struct Inner {
+    state: ConnectionState,
+    watchers: Watchers<StateChange>,
+    /// the reconnect thread is asked to stop
+    stopping: bool,
+}
+
+/// Observer of a connection keeping its state.
+pub(crate) struct StateMachine {
+    inner: Mutex<Inner>,
+    cond: Condvar,
+}
+
+impl Default for StateMachine {
+    fn default() -> Self {
+        StateMachine {
+            inner: Mutex::new(Inner {
+                state: ConnectionState::IDLE,
+                watchers: Watchers::default(),
+                stopping: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+}
+
+impl StateMachine {
+    fn inner(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn get(&self) -> ConnectionState {
+        self.inner().state
+    }
+
+    pub(crate) fn set(&self, conn_id: u32, to: ConnectionState) {
+        self.set_if(conn_id, to, |_| true)
+    }
+
+    /// Move to `to` if the current state passes `from`.
+    fn set_if<F: Fn(ConnectionState) -> bool>(&self, conn_id: u32, to: ConnectionState, from: F) {
+        let mut inner = self.inner();
+        let prev = inner.state;
+        if prev == to || !from(prev) {
+            return;
+        }
+        inner.state = to;
+        let change = StateChange {
+            conn_id,
+            from: prev,
+            to,
+        };
+        info!("conn_id: {}, {:?} -> {:?}", conn_id, prev, to);
+        inner.watchers.send(change);
+        self.cond.notify_all();
+    }
+
+    pub(crate) fn watch(&self) -> Receiver<StateChange> {
+        self.inner().watchers.watch()
+    }
+
+    /// Wait until `done` holds or `timeout` passes, then the state.
+    /// `None` if asked to stop meanwhile.
+    fn wait<F: Fn(ConnectionState) -> bool>(
+        &self,
+        timeout: Option<Duration>,
+        done: F,
+    ) -> Option<ConnectionState> {
+        let keep_waiting = |i: &mut Inner| !i.stopping && !done(i.state);
+        let inner = match timeout {
+            Some(t) => {
+                self.cond
+                    .wait_timeout_while(self.inner(), t, keep_waiting)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0
+            }
+            None => self
+                .cond
+                .wait_while(self.inner(), keep_waiting)
+                .unwrap_or_else(|e| e.into_inner()),
+        };
+        (!inner.stopping).then_some(inner.state)
+    }
+
+    fn stop(&self, stopping: bool) {
+        self.inner().stopping = stopping;
+        self.cond.notify_all();
+    }
+}
+
+impl Observer for StateMachine {
+    fn on_join_channel_success(&self, conn_id: u32, _uid: u32, _elapsed_ms: i32) {
+        self.set_if(conn_id, ConnectionState::JOINED, |s| {
+            !matches!(s, ConnectionState::IDLE | ConnectionState::LEFT)
+        })
+    }
+    fn on_rejoin_channel_success(&self, conn_id: u32, _uid: u32, _elapsed_ms: i32) {
+        self.set_if(conn_id, ConnectionState::JOINED, |s| {
+            s == ConnectionState::LOST
+        })
+    }
+    fn on_connection_lost(&self, conn_id: u32) {
+        self.set_if(conn_id, ConnectionState::LOST, |s| {
+            s == ConnectionState::JOINED
+        })
+    }
+    fn on_error(&self, conn_id: u32, code: i32, _msg: &str) {
+        // while REJOINING the reconnect thread decides
+        if fails_join(code) {
+            self.set_if(conn_id, ConnectionState::FAILED, |s| {
+                s == ConnectionState::JOINING
+            })
+        }
+    }
+}
+
+fn run(
+    machine: &StateMachine,
+    conn: &Arc<ConnShared>,
+    service: &RtcService,
+    policy: ReconnectPolicy,
+) {
+    use ConnectionState::*;
+    let attempts = policy.max_attempts.max(1);
+    while machine.wait(None, |s| s == LOST).is_some() {
+        // the SDK's turn first
+        match machine.wait(Some(policy.rejoin_timeout), |s| s != LOST) {
+            None => return,
+            Some(LOST) => {}
+            Some(_) => continue,
+        }
+        let mut state = LOST;
+        let mut delay = policy.retry_delay;
+        for attempt in 0..attempts {
+            if attempt > 0 && matches!(state, REJOINING | FAILED) {
+                // not straight away when the rebuild failed right away
+                let rejoined = |s| !matches!(s, REJOINING | FAILED);
+                state = match machine.wait(Some(delay), rejoined) {
+                    None => return,
+                    Some(s) => s,
+                };
+                delay = delay.saturating_mul(2);
+            }
+            // LOST again means it did join meanwhile, the SDK gets its turn again
+            if attempt > 0 && !matches!(state, REJOINING | FAILED) {
+                break;
+            }
+            warn!(
+                "rebuilding the connection, conn_id: {}, attempt {} of {}",
+                conn.conn_id(),
+                attempt + 1,
+                attempts
+            );
+            machine.set_if(conn.conn_id(), REJOINING, |s| {
+                matches!(s, LOST | REJOINING | FAILED)
+            });
+            if let Err(e) = conn.rebuild(&**service.backend()) {
+                warn!("rebuilding the connection failed, {}", e);
+                machine.set(conn.conn_id(), FAILED);
+            }
+            state = match machine.wait(Some(policy.join_timeout), |s| s != REJOINING) {
+                None => return,
+                Some(s) => s,
+            };
+        }
+        if matches!(state, REJOINING | FAILED) {
+            error!(
+                "giving up reconnecting after {} attempts, conn_id: {}",
+                attempts,
+                conn.conn_id()
+            );
+            machine.set(conn.conn_id(), FAILED);
+        }
+    }
+}
+
+/// The reconnect thread of one connection. Stops when dropped.
+pub(crate) struct Reconnector {
+    machine: Arc<StateMachine>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reconnector {
+    pub(crate) fn start(
+        machine: Arc<StateMachine>,
+        conn: Arc<ConnShared>,
+        service: RtcService,
+        policy: ReconnectPolicy,
+    ) -> Self {
+        machine.stop(false);
+        let m2 = machine.clone();
+        let spawned = std::thread::Builder::new()
+            .name("agora-reconnect".to_owned())
+            .spawn(move || run(&m2, &conn, &service, policy));
+        let thread = match spawned {
+            Ok(t) => Some(t),
+            Err(e) => {
+                error!("failed to spawn reconnect thread, won't reconnect: {}", e);
+                None
+            }
+        };
+        Reconnector { machine, thread }
+    }
+}
+
+impl Drop for Reconnector {
+    fn drop(&mut self) {
+        self.machine.stop(true);
+        if let Some(t) = self.thread.take() {
+            if t.join().is_err() {
+                warn!("reconnect thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::ffi::*;
+    use super::super::fake::{fake_service, Call};
+    use super::super::RosterChange;
+    use super::*;
+    use std::time::Instant;
+
+    fn wait_for(rx: &Receiver<StateChange>, to: ConnectionState) -> StateChange {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let change = rx.recv_timeout(left).expect("state change");
+            if change.to == to {
+                return change;
+            }
+        }
+    }
+
+    #[test]
+    fn follows_callbacks() {
+        use ConnectionState::*;
+        let m = StateMachine::default();
+        let rx = m.watch();
+        m.on_join_channel_success(1, 2, 0);
+        assert_eq!(m.get(), IDLE);
+        m.set(1, JOINING);
+        m.on_connection_lost(1);
+        m.on_error(1, 200, "");
+        assert_eq!(m.get(), JOINING);
+        m.on_join_channel_success(1, 2, 0);
+        m.on_connection_lost(1);
+        m.on_rejoin_channel_success(1, 2, 0);
+        m.set(1, LEFT);
+        m.set(1, JOINING);
+        m.on_error(1, 110, "invalid token");
+        let seen: Vec<_> = rx.try_iter().map(|c| (c.from, c.to)).collect();
+        assert_eq!(
+            seen,
+            [
+                (IDLE, JOINING),
+                (JOINING, JOINED),
+                (JOINED, LOST),
+                (LOST, JOINED),
+                (JOINED, LEFT),
+                (LEFT, JOINING),
+                (JOINING, FAILED)
+            ]
+        );
+    }
+
+    #[test]
+    fn rebuilds_when_the_sdk_does_not_rejoin() {
+        use ConnectionState::*;
+        let (fake, service) = fake_service();
+        let conn = service.create_connection().unwrap();
+        let rx = conn.watch_state();
+        conn.set_reconnect_policy(ReconnectPolicy {
+            rejoin_timeout: Duration::from_millis(10),
+            join_timeout: Duration::from_millis(100),
+            max_attempts: 2,
+            retry_delay: Duration::from_millis(50),
+        });
+        let old = conn.conn_id();
+        conn.join_channel("ch", Some(5), "tok", rtc_channel_options_t::new())
+            .unwrap();
+        assert!(!conn.is_joined());
+        fake.join_channel_success(old, 5);
+        assert!(conn.is_joined());
+        fake.user_joined(old, 7);
+        conn.mute_local_video(true).unwrap();
+        conn.set_bwe_params(100_000, 1_000_000, 500_000).unwrap();
+        let roster = conn.watch_users();
+        fake.take_calls();
+
+        fake.connection_lost(old);
+        wait_for(&rx, REJOINING);
+        // the new id is known once it joins again
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !fake
+            .calls()
+            .iter()
+            .any(|c| matches!(c, Call::JoinChannel { .. }))
+            && Instant::now() < deadline
+        {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let new = conn.conn_id();
+        assert_ne!(new, old);
+        fake.join_channel_success(new, 5);
+        wait_for(&rx, JOINED);
+        assert_eq!(
+            fake.take_calls()[..6],
+            [
+                Call::LeaveChannel(old),
+                Call::DestroyConnection(old),
+                Call::CreateConnection(new),
+                Call::JoinChannel {
+                    conn_id: new,
+                    channel_name: "ch".to_owned(),
+                    uid: 5,
+                    token: "tok".to_owned()
+                },
+                Call::MuteLocalVideo {
+                    conn_id: new,
+                    mute: true
+                },
+                Call::SetBweParam {
+                    conn_id: new,
+                    min_bps: 100_000,
+                    max_bps: 1_000_000,
+                    start_bps: 500_000
+                },
+            ]
+        );
+        // whoever is still there joins the new connection again
+        assert!(conn.remote_users().is_empty());
+        let change = roster.try_recv().unwrap();
+        assert!(matches!(change, RosterChange::Offline(u) if u.uid == 7));
+
+        // nobody answers, both attempts time out
+        fake.connection_lost(new);
+        let failed = wait_for(&rx, FAILED);
+        assert_eq!(failed.from, REJOINING);
+        let rebuilt = fake
+            .calls()
+            .iter()
+            .filter(|c| matches!(c, Call::CreateConnection(_)))
+            .count();
+        assert_eq!(rebuilt, 2);
+        conn.leave_channel().unwrap();
+        assert_eq!(conn.state(), LEFT);
+    }
+
+    #[test]
+    fn backs_off_between_failed_rebuilds() {
+        use ConnectionState::*;
+        let (fake, service) = fake_service();
+        let conn = service.create_connection().unwrap();
+        let rx = conn.watch_state();
+        conn.set_reconnect_policy(ReconnectPolicy {
+            rejoin_timeout: Duration::from_millis(10),
+            join_timeout: Duration::from_millis(100),
+            max_attempts: 3,
+            retry_delay: Duration::from_millis(40),
+        });
+        let id = conn.conn_id();
+        conn.join_channel("ch", Some(5), "tok", rtc_channel_options_t::new())
+            .unwrap();
+        fake.join_channel_success(id, 5);
+        fake.fail("create_connection", -1);
+
+        fake.connection_lost(id);
+        wait_for(&rx, REJOINING);
+        let start = Instant::now();
+        // each attempt fails right away
+        for _ in 0..3 {
+            wait_for(&rx, FAILED);
+        }
+        // 40 then 80 ms between them
+        assert!(start.elapsed() >= Duration::from_millis(120));
+        let rebuilt = fake
+            .calls()
+            .iter()
+            .filter(|c| matches!(c, Call::DestroyConnection(_)))
+            .count();
+        assert_eq!(rebuilt, 3);
+    }
+}
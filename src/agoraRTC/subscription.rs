@@ -0,0 +1,267 @@
+//! Who to receive audio and video from, when the channel doesn't auto subscribe.
+//!
+//! With `auto_subscribe_audio`/`auto_subscribe_video` off the SDK sends nothing of a
+//! remote user until `agora_rtc_mute_remote_audio`/`_video(uid, false)`. The
+//! [`Connection`](super::Connection) tracks remote users from `on_user_joined` and
+//! `on_user_offline` and calls those for every user, as the [`SubscribePolicy`] says.
+use super::super::utils::err_2_result;
+use super::backend::RtcBackend;
+use super::handler::Observer;
+use super::AgoraError;
+use log::warn;
+use std::collections::{BTreeSet, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Remote users to receive one kind of media from.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum SubscribePolicy {
+    /// Everyone
+    #[default]
+    All,
+    /// No one
+    Nobody,
+    /// Only these uids
+    Allow(HashSet<u32>),
+    /// Everyone but these uids
+    Deny(HashSet<u32>),
+}
+
+impl SubscribePolicy {
+    pub fn allows(&self, uid: u32) -> bool {
+        match self {
+            SubscribePolicy::All => true,
+            SubscribePolicy::Nobody => false,
+            SubscribePolicy::Allow(uids) => uids.contains(&uid),
+            SubscribePolicy::Deny(uids) => !uids.contains(&uid),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Media {
+    Audio,
+    Video,
+}
+
+#[derive(Default)]
+struct State {
+    users: BTreeSet<u32>,
+    /// the channel auto subscribes, nothing to do
+    auto_audio: bool,
+    auto_video: bool,
+    audio: SubscribePolicy,
+    video: SubscribePolicy,
+}
+
+/// Observer of a connection keeping the subscriptions in line with the policies.
+pub(crate) struct Subscriptions {
+    backend: Arc<dyn RtcBackend>,
+    state: Mutex<State>,
+}
+
+impl Subscriptions {
+    pub(crate) fn new(backend: Arc<dyn RtcBackend>) -> Self {
+        Subscriptions {
+            backend,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn mute_remote_audio(
+        &self,
+        conn_id: u32,
+        uid: u32,
+        mute: bool,
+    ) -> Result<(), AgoraError> {
+        err_2_result(self.backend.mute_remote_audio(conn_id, uid, mute))
+    }
+
+    pub(crate) fn mute_remote_video(
+        &self,
+        conn_id: u32,
+        uid: u32,
+        mute: bool,
+    ) -> Result<(), AgoraError> {
+        err_2_result(self.backend.mute_remote_video(conn_id, uid, mute))
+    }
+
+    /// From the `rtc_channel_options_t` of `join_channel`. Users of the previous
+    /// channel are forgotten.
+    pub(crate) fn joining(&self, auto_audio: bool, auto_video: bool) {
+        let mut st = self.state();
+        st.users.clear();
+        st.auto_audio = auto_audio;
+        st.auto_video = auto_video;
+    }
+
+    pub(crate) fn set_audio_policy(
+        &self,
+        conn_id: u32,
+        policy: SubscribePolicy,
+    ) -> Result<(), AgoraError> {
+        let (users, auto) = {
+            let mut st = self.state();
+            st.audio = policy;
+            (st.users.clone(), st.auto_audio)
+        };
+        if auto {
+            return Ok(());
+        }
+        users
+            .into_iter()
+            .try_for_each(|uid| self.apply(conn_id, Media::Audio, uid))
+    }
+
+    pub(crate) fn set_video_policy(
+        &self,
+        conn_id: u32,
+        policy: SubscribePolicy,
+    ) -> Result<(), AgoraError> {
+        let (users, auto) = {
+            let mut st = self.state();
+            st.video = policy;
+            (st.users.clone(), st.auto_video)
+        };
+        if auto {
+            return Ok(());
+        }
+        users
+            .into_iter()
+            .try_for_each(|uid| self.apply(conn_id, Media::Video, uid))
+    }
+
+    /// Subscribe or not to `media` of `uid` as the current policy says.
+    fn apply(&self, conn_id: u32, media: Media, uid: u32) -> Result<(), AgoraError> {
+        // not holding the lock while calling the SDK
+        let allowed = match media {
+            Media::Audio => self.state().audio.allows(uid),
+            Media::Video => self.state().video.allows(uid),
+        };
+        match media {
+            Media::Audio => self.mute_remote_audio(conn_id, uid, !allowed),
+            Media::Video => self.mute_remote_video(conn_id, uid, !allowed),
+        }
+    }
+}
+
+impl Observer for Subscriptions {
+    fn on_user_joined(&self, conn_id: u32, uid: u32, _elapsed_ms: i32) {
+        let (auto_audio, auto_video) = {
+            let mut st = self.state();
+            st.users.insert(uid);
+            (st.auto_audio, st.auto_video)
+        };
+        let media = [(Media::Audio, auto_audio), (Media::Video, auto_video)];
+        for (m, _) in media.iter().filter(|(_, auto)| !auto) {
+            if let Err(e) = self.apply(conn_id, *m, uid) {
+                warn!(
+                    "failed to apply the {:?} subscription, conn_id: {}, uid: {}, {}",
+                    m, conn_id, uid, e
+                );
+            }
+        }
+    }
+
+    fn on_user_offline(&self, _conn_id: u32, uid: u32, _reason: i32) {
+        self.state().users.remove(&uid);
+    }
+
+    fn on_rebuilt(&self, _old_conn_id: u32, _conn_id: u32) {
+        // applied again as they join the new connection
+        self.state().users.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::ffi::*;
+    use super::super::fake::{fake_service, Call, FakeBackend};
+    use super::*;
+
+    fn remote_mutes(fake: &FakeBackend) -> Vec<Call> {
+        fake.take_calls()
+            .into_iter()
+            .filter(|c| {
+                matches!(
+                    c,
+                    Call::MuteRemoteAudio { .. } | Call::MuteRemoteVideo { .. }
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn applies_policies_to_joined_users() {
+        let (fake, service) = fake_service();
+        let conn = service.create_connection().unwrap();
+        let id = conn.conn_id();
+        let mut option = rtc_channel_options_t::new();
+        option.auto_subscribe_audio = false;
+        option.auto_subscribe_video = true;
+        conn.set_audio_subscription(SubscribePolicy::Allow([7].into()))
+            .unwrap();
+        conn.set_video_subscription(SubscribePolicy::Nobody)
+            .unwrap();
+        conn.join_channel("ch", None, "", option).unwrap();
+        fake.take_calls();
+
+        fake.user_joined(id, 7);
+        fake.user_joined(id, 8);
+        // video is auto subscribed, left alone
+        assert_eq!(
+            remote_mutes(&fake),
+            [
+                Call::MuteRemoteAudio {
+                    conn_id: id,
+                    remote_uid: 7,
+                    mute: false
+                },
+                Call::MuteRemoteAudio {
+                    conn_id: id,
+                    remote_uid: 8,
+                    mute: true
+                },
+            ]
+        );
+        fake.user_offline(id, 7, 0);
+        let online: Vec<u32> = conn
+            .remote_users()
+            .iter()
+            .filter(|u| u.is_online())
+            .map(|u| u.uid)
+            .collect();
+        assert_eq!(online, [8]);
+
+        conn.set_audio_subscription(SubscribePolicy::Deny([7].into()))
+            .unwrap();
+        assert_eq!(
+            remote_mutes(&fake),
+            [Call::MuteRemoteAudio {
+                conn_id: id,
+                remote_uid: 8,
+                mute: false
+            }]
+        );
+
+        conn.mute_remote_video(8, true).unwrap();
+        conn.mute_local_video(true).unwrap();
+        assert_eq!(
+            fake.take_calls(),
+            [
+                Call::MuteRemoteVideo {
+                    conn_id: id,
+                    remote_uid: 8,
+                    mute: true
+                },
+                Call::MuteLocalVideo {
+                    conn_id: id,
+                    mute: true
+                },
+            ]
+        );
+    }
+}
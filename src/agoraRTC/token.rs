@@ -0,0 +1,338 @@
+//! Keeping the token of a [`Connection`](super::Connection) fresh.
+//!
+//! On `on_token_privilege_will_expire`, or an `on_error` with `TOKEN_EXPIRED` or
+//! `INVALID_TOKEN`, a new token is fetched from the [`TokenProvider`] and applied
+//! with `agora_rtc_renew_token`. This happens on a thread of its own, never on the
+//! SDK's, so the provider may block (e.g. on an HTTP request).
+use super::connection::ConnShared;
+use super::handler::Observer;
+use super::service::RtcService;
+use super::{AgoraError, SdkError};
+use log::{error, info, warn};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Where fresh tokens come from, usually your token server.
+///
+/// Any `Fn(&str, u32) -> Result<String, AgoraError>` is one.
+/// With the `async` feature [`AsyncTokenProvider`] wraps an async fn.
+pub trait TokenProvider: Send + Sync {
+    /// A new token for `uid` in `channel_name`.
+    fn token(&self, channel_name: &str, uid: u32) -> Result<String, AgoraError>;
+}
+
+impl<F> TokenProvider for F
+where
+    F: Fn(&str, u32) -> Result<String, AgoraError> + Send + Sync,
+{
+    fn token(&self, channel_name: &str, uid: u32) -> Result<String, AgoraError> {
+        self(channel_name, uid)
+    }
+}
+
+/// A [`TokenProvider`] running an async fn on a tokio runtime.
+#[cfg(feature = "async")]
+pub struct AsyncTokenProvider<F> {
+    handle: tokio::runtime::Handle,
+    fetch: F,
+}
+
+#[cfg(feature = "async")]
+impl<F, Fut> AsyncTokenProvider<F>
+where
+    F: Fn(String, u32) -> Fut + Send + Sync,
+    Fut: std::future::Future<Output = Result<String, AgoraError>>,
+{
+    /// `fetch(channel_name, uid)` runs on `handle`, e.g. `Handle::current()`.
+    pub fn new(handle: tokio::runtime::Handle, fetch: F) -> Self {
+        AsyncTokenProvider { handle, fetch }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<F, Fut> TokenProvider for AsyncTokenProvider<F>
+where
+    F: Fn(String, u32) -> Fut + Send + Sync,
+    Fut: std::future::Future<Output = Result<String, AgoraError>>,
+{
+    fn token(&self, channel_name: &str, uid: u32) -> Result<String, AgoraError> {
+        // we are on the token thread, not in the runtime
+        self.handle
+            .block_on((self.fetch)(channel_name.to_owned(), uid))
+    }
+}
+
+/// How often to try fetching and applying a token before giving up.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TokenRetry {
+    /// tries per renewal, at least 1
+    pub max_attempts: u32,
+    /// wait after the first failure, doubled after each one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for TokenRetry {
+    fn default() -> Self {
+        TokenRetry {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl TokenRetry {
+    /// Wait after the failed attempt number `attempt` (from 0).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+/// Errors from `on_error` which a new token fixes.
+pub(crate) fn needs_new_token(code: i32) -> bool {
+    matches!(
+        AgoraError::from_code(code),
+        AgoraError::Sdk(SdkError::TOKEN_EXPIRED | SdkError::INVALID_TOKEN)
+    )
+}
+
+#[derive(Default)]
+struct State {
+    requested: bool,
+    closed: bool,
+}
+
+/// Observer of the connection waking up the token thread.
+#[derive(Default)]
+struct Trigger {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+impl Trigger {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn request(&self) {
+        self.state().requested = true;
+        self.cond.notify_all();
+    }
+
+    fn close(&self) {
+        self.state().closed = true;
+        self.cond.notify_all();
+    }
+
+    /// Wait for a request, `false` once closed.
+    fn wait_request(&self) -> bool {
+        let mut st = self
+            .cond
+            .wait_while(self.state(), |s| !s.requested && !s.closed)
+            .unwrap_or_else(|e| e.into_inner());
+        st.requested = false;
+        !st.closed
+    }
+
+    /// Sleep for `d`, `false` if closed meanwhile.
+    fn sleep(&self, d: Duration) -> bool {
+        let (st, _) = self
+            .cond
+            .wait_timeout_while(self.state(), d, |s| !s.closed)
+            .unwrap_or_else(|e| e.into_inner());
+        !st.closed
+    }
+}
+
+impl Observer for Trigger {
+    fn on_error(&self, _conn_id: u32, code: i32, _msg: &str) {
+        if needs_new_token(code) {
+            self.request()
+        }
+    }
+    fn on_token_privilege_will_expire(&self, _conn_id: u32, _token: &str) {
+        self.request()
+    }
+}
+
+fn run(
+    trigger: &Trigger,
+    conn: &ConnShared,
+    service: &RtcService,
+    provider: &dyn TokenProvider,
+    retry: TokenRetry,
+) {
+    let attempts = retry.max_attempts.max(1);
+    while trigger.wait_request() {
+        for attempt in 0..attempts {
+            let res = provider
+                .token(&conn.channel_name(), conn.uid())
+                .and_then(|t| conn.renew_token(&**service.backend(), &t));
+            match res {
+                Ok(()) => {
+                    info!("token renewed, conn_id: {}", conn.conn_id());
+                    break;
+                }
+                Err(e) if attempt + 1 == attempts => {
+                    error!(
+                        "giving up renewing the token after {} attempts, conn_id: {}, {}",
+                        attempts,
+                        conn.conn_id(),
+                        e
+                    );
+                }
+                Err(e) => {
+                    warn!(
+                        "renewing the token failed, conn_id: {}, {}",
+                        conn.conn_id(),
+                        e
+                    );
+                    if !trigger.sleep(retry.backoff(attempt)) {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The token thread of one connection. Stops when dropped.
+pub(crate) struct TokenRefresher {
+    trigger: Arc<Trigger>,
+    conn: Arc<ConnShared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TokenRefresher {
+    pub(crate) fn start(
+        conn: Arc<ConnShared>,
+        service: RtcService,
+        provider: Arc<dyn TokenProvider>,
+        retry: TokenRetry,
+    ) -> Self {
+        let trigger = Arc::new(Trigger::default());
+        let (t2, c2) = (trigger.clone(), conn.clone());
+        let spawned = std::thread::Builder::new()
+            .name("agora-token".to_owned())
+            .spawn(move || run(&t2, &c2, &service, provider.as_ref(), retry));
+        let thread = match spawned {
+            Ok(t) => {
+                conn.add_observer(trigger.clone());
+                Some(t)
+            }
+            Err(e) => {
+                error!(
+                    "failed to spawn token thread, tokens won't be renewed: {}",
+                    e
+                );
+                None
+            }
+        };
+        TokenRefresher {
+            trigger,
+            conn,
+            thread,
+        }
+    }
+}
+
+impl Drop for TokenRefresher {
+    fn drop(&mut self) {
+        let observer: Arc<dyn Observer> = self.trigger.clone();
+        self.conn.remove_observer(&observer);
+        self.trigger.close();
+        if let Some(t) = self.thread.take() {
+            if t.join().is_err() {
+                warn!("token thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::ffi::*;
+    use super::super::fake::{fake_service, Call, FakeBackend};
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Instant;
+
+    fn renewals(fake: &FakeBackend) -> Vec<String> {
+        fake.calls()
+            .into_iter()
+            .filter_map(|c| match c {
+                Call::RenewToken { token, .. } => Some(token),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn wait_for_renewals(fake: &FakeBackend, n: usize) -> Vec<String> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while renewals(fake).len() < n && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        renewals(fake)
+    }
+
+    #[test]
+    fn renews_on_will_expire_and_expired_errors() {
+        let (fake, service) = fake_service();
+        let conn = service.create_connection().unwrap();
+        let id = conn.conn_id();
+        conn.join_channel("ch", Some(5), "old", rtc_channel_options_t::new())
+            .unwrap();
+        let fetched = Arc::new(AtomicU32::new(0));
+        let f2 = fetched.clone();
+        let provider = move |channel: &str, uid: u32| {
+            // the first fetch fails and is retried
+            match f2.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(AgoraError::Timeout),
+                n => Ok(format!("{}-{}-{}", channel, uid, n)),
+            }
+        };
+        let retry = TokenRetry {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+        conn.set_token_provider(Arc::new(provider), retry);
+
+        fake.token_privilege_will_expire(id, "old");
+        assert_eq!(wait_for_renewals(&fake, 1), ["ch-5-1"]);
+        fake.error(id, 17, "join rejected");
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(fetched.load(Ordering::SeqCst), 2);
+        fake.error(id, 109, "token expired");
+        assert_eq!(wait_for_renewals(&fake, 2), ["ch-5-1", "ch-5-2"]);
+
+        // stops with the connection, nothing is called after
+        drop(conn);
+        fake.token_privilege_will_expire(id, "old");
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(fetched.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let r = TokenRetry {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+        assert_eq!(r.backoff(0), Duration::from_millis(100));
+        assert_eq!(r.backoff(1), Duration::from_millis(200));
+        assert_eq!(r.backoff(3), Duration::from_millis(800));
+        assert_eq!(r.backoff(4), Duration::from_secs(1));
+        assert_eq!(r.backoff(40), Duration::from_secs(1));
+        assert!(needs_new_token(109));
+        assert!(needs_new_token(-110));
+        assert!(!needs_new_token(17));
+    }
+}
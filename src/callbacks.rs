@@ -1,138 +1,247 @@
-use std::ffi::{c_void, CStr};
-use super::utils::{err_2_reason};
+//! Trampolines handed to the SDK in `agora_rtc_event_handler_t`.
+//!
+//! They convert the raw arguments and route the event by `conn_id` to the
+//! [`EventHandler`] registered for that connection (see `agoraRTC::handler`).
+//! Panics are caught here since unwinding into C is undefined behavior.
+use super::agoraRTC::handler::{handler_for, EventHandler};
+use super::agoraRTC::rtm::{rtm_shared, RtmShared};
 use super::ffi::*;
-use log::{error, info, warn};
+use log::error;
+use std::cell::Cell;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+thread_local! {
+    static CALLBACK_DEPTH: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Whether the current thread is inside a callback invoked by the SDK.
+/// `agora_rtc_init`/`agora_rtc_fini` must not be called from there.
+pub(crate) fn in_sdk_callback() -> bool {
+    CALLBACK_DEPTH.with(|d| d.get() > 0)
+}
+
+/// Marks the current thread as running an SDK callback while alive.
+pub(crate) struct CallbackScope;
+
+impl CallbackScope {
+    pub(crate) fn enter() -> Self {
+        CALLBACK_DEPTH.with(|d| d.set(d.get() + 1));
+        CallbackScope
+    }
+}
+
+impl Drop for CallbackScope {
+    fn drop(&mut self) {
+        CALLBACK_DEPTH.with(|d| d.set(d.get() - 1));
+    }
+}
+
+fn dispatch<F>(name: &str, conn_id: u32, f: F)
+where
+    F: FnOnce(&dyn EventHandler),
+{
+    let _scope = CallbackScope::enter();
+    let handler = handler_for(conn_id);
+    if catch_unwind(AssertUnwindSafe(|| f(handler.as_ref()))).is_err() {
+        error!("panic in event handler {}, conn_id: {}", name, conn_id);
+    }
+}
+
+/// the SDK may hand us NULL or non UTF-8 strings
+unsafe fn str_or_empty<'a>(ptr: *const c_char) -> &'a str {
+    if ptr.is_null() {
+        return "";
+    }
+    CStr::from_ptr(ptr).to_str().unwrap_or("")
+}
+
+unsafe fn slice_or_empty<'a>(ptr: *const c_void, len: size_t) -> &'a [u8] {
+    if ptr.is_null() || len == 0 {
+        return &[];
+    }
+    std::slice::from_raw_parts(ptr as *const u8, len as usize)
+}
+
 /// Occurs when local user joins channel successfully.
 /// * `conn_id` -  Connection identification
 /// * `uid`     -   local uid
 /// * `elapsed_ms` - Time elapsed (ms) since channel is established
-pub extern "C" fn on_join_channel_success(conn_id: u32, uid: u32, elapsed_ms: i32) {
-    info!(
-        "join_channel_success, conn_id: {}, uid: {}, elapsed_ms: {}",
-        conn_id, uid, elapsed_ms
-    );
+pub unsafe extern "C" fn on_join_channel_success(conn_id: u32, uid: u32, elapsed_ms: c_int) {
+    dispatch("on_join_channel_success", conn_id, |h| {
+        h.on_join_channel_success(conn_id, uid, elapsed_ms)
+    });
 }
-pub extern "C" fn on_connection_lost(conn_id: u32) {
-    error!("connection_lost, conn_id: {}", conn_id);
+
+pub unsafe extern "C" fn on_connection_lost(conn_id: u32) {
+    dispatch("on_connection_lost", conn_id, |h| {
+        h.on_connection_lost(conn_id)
+    });
 }
-pub extern "C" fn on_rejoin_channel_success(conn_id: u32, uid: u32, elapsed_ms: i32) {
-    info!(
-        "rejoin_channel_success, conn_id: {}, uid: {}, elapsed_ms: {}",
-        conn_id, uid, elapsed_ms
-    );
+
+pub unsafe extern "C" fn on_rejoin_channel_success(conn_id: u32, uid: u32, elapsed_ms: c_int) {
+    dispatch("on_rejoin_channel_success", conn_id, |h| {
+        h.on_rejoin_channel_success(conn_id, uid, elapsed_ms)
+    });
 }
 
 /// Report error message during runtime.
-/// In most cases, it means SDK can't fix the issue and application should take action.
 /// * `conn_id` Connection identification
 /// * `code`    Error code, see #agora_err_code_e
 /// * `msg`     Error message
-pub extern "C" fn on_error(conn_id: u32, code: i32, msg: *const u8) {
-    let m = unsafe { CStr::from_ptr(msg) }.to_str();
-    let message = match code
-        .try_into()
-        .expect("Error converting on_error error code")
-    {
-        agora_err_code_e_ERR_INVALID_APP_ID => "Invalid App ID. Please double check.",
-        agora_err_code_e_ERR_INVALID_CHANNEL_NAME => "Invalid channel name",
-        agora_err_code_e_ERR_INVALID_TOKEN => "Invalid token",
-        agora_err_code_e_ERR_DYNAMIC_TOKEN_BUT_USE_STATIC_KEY => {
-            "Dynamic token is enabled but is not provided."
-        }
-        _ => "Other Error",
-    };
-    error!(
-        "ERROR!, conn_id: {}, {:?}, {:?}, Code: {}",
-        conn_id, message, m, code
-    );
+pub unsafe extern "C" fn on_error(conn_id: u32, code: c_int, msg: *const c_char) {
+    dispatch("on_error", conn_id, |h| {
+        h.on_error(conn_id, code, str_or_empty(msg))
+    });
 }
 
-pub extern "C" fn on_user_joined(conn_id: u32, uid: u32, elapsed_ms: i32) {
-    info!(
-        "user_join, conn_id: {}, uid: {}, elapsed_ms: {}",
-        conn_id, uid, elapsed_ms
-    );
+pub unsafe extern "C" fn on_user_joined(conn_id: u32, uid: u32, elapsed_ms: c_int) {
+    dispatch("on_user_joined", conn_id, |h| {
+        h.on_user_joined(conn_id, uid, elapsed_ms)
+    });
 }
 
-pub extern "C" fn on_user_offline(conn_id: u32, uid: u32, reason: i32) {
-    warn!(
-        "user_offline, conn_id: {}, uid: {}, user_offline_reason_e: {}",
-        conn_id, uid, reason
-    );
+pub unsafe extern "C" fn on_user_offline(conn_id: u32, uid: u32, reason: c_int) {
+    dispatch("on_user_offline", conn_id, |h| {
+        h.on_user_offline(conn_id, uid, reason)
+    });
 }
 
-pub extern "C" fn on_user_mute_audio(conn_id: u32, uid: u32, muted: bool) {
-    info!(
-        "user_mute_audio, conn_id: {}, uid: {}, is_muted: {}",
-        conn_id, uid, muted
-    );
+pub unsafe extern "C" fn on_user_mute_audio(conn_id: u32, uid: u32, muted: bool) {
+    dispatch("on_user_mute_audio", conn_id, |h| {
+        h.on_user_mute_audio(conn_id, uid, muted)
+    });
 }
 
-pub extern "C" fn on_user_mute_video(conn_id: u32, uid: u32, muted: bool) {
-    info!(
-        "user_mute_video, conn_id: {}, uid: {}, is_muted: {}",
-        conn_id, uid, muted
-    );
+pub unsafe extern "C" fn on_user_mute_video(conn_id: u32, uid: u32, muted: bool) {
+    dispatch("on_user_mute_video", conn_id, |h| {
+        h.on_user_mute_video(conn_id, uid, muted)
+    });
 }
 
-pub extern "C" fn on_audio_data(
-    _conn_id: u32,
-    _uid: u32,
-    _sent_ts: u16,
-    _data_ptr: *const c_void,
-    _data_length: u64,
-    _info_ptr: *const audio_frame_info_t,
+pub unsafe extern "C" fn on_audio_data(
+    conn_id: u32,
+    uid: u32,
+    sent_ts: u16,
+    data_ptr: *const c_void,
+    data_length: size_t,
+    info_ptr: *const audio_frame_info_t,
 ) {
-    // won't do anything default
+    if info_ptr.is_null() {
+        return;
+    }
+    dispatch("on_audio_data", conn_id, |h| {
+        h.on_audio_data(
+            conn_id,
+            uid,
+            sent_ts,
+            slice_or_empty(data_ptr, data_length),
+            &*info_ptr,
+        )
+    });
 }
 
 /// Occurs every 20ms.
-pub extern "C" fn on_mixed_audio_data(
-    _conn_id: u32,
-    _data_ptr: *const c_void,
-    _data_length: u64,
-    _info_ptr: *const audio_frame_info_t,
+pub unsafe extern "C" fn on_mixed_audio_data(
+    conn_id: u32,
+    data_ptr: *const c_void,
+    data_length: size_t,
+    info_ptr: *const audio_frame_info_t,
 ) {
-    // won't do anything default
+    if info_ptr.is_null() {
+        return;
+    }
+    dispatch("on_mixed_audio_data", conn_id, |h| {
+        h.on_mixed_audio_data(conn_id, slice_or_empty(data_ptr, data_length), &*info_ptr)
+    });
+}
+
+pub unsafe extern "C" fn on_video_data(
+    conn_id: u32,
+    uid: u32,
+    sent_ts: u16,
+    data_ptr: *const c_void,
+    data_length: size_t,
+    info_ptr: *const video_frame_info_t,
+) {
+    if info_ptr.is_null() {
+        return;
+    }
+    dispatch("on_video_data", conn_id, |h| {
+        h.on_video_data(
+            conn_id,
+            uid,
+            sent_ts,
+            slice_or_empty(data_ptr, data_length),
+            &*info_ptr,
+        )
+    });
+}
+
+pub unsafe extern "C" fn on_target_bitrate_changed(conn_id: u32, target_bps: u32) {
+    dispatch("on_target_bitrate_changed", conn_id, |h| {
+        h.on_target_bitrate_changed(conn_id, target_bps)
+    });
 }
 
-pub extern "C" fn on_video_data(
-    _conn_id: u32,
-    _uid: u32,
-    _sent_ts: u16,
-    _data_ptr: *const c_void,
-    _data_length: u64,
-    _info_ptr: *const video_frame_info_t,
+pub unsafe extern "C" fn on_key_frame_gen_req(
+    conn_id: u32,
+    uid: u32,
+    stream_type: video_stream_type_e,
 ) {
-    // won't do anything default
+    dispatch("on_key_frame_gen_req", conn_id, |h| {
+        h.on_key_frame_gen_req(conn_id, uid, stream_type)
+    });
+}
+
+pub unsafe extern "C" fn on_token_privilege_will_expire(conn_id: u32, token: *const c_char) {
+    dispatch("on_token_privilege_will_expire", conn_id, |h| {
+        h.on_token_privilege_will_expire(conn_id, str_or_empty(token))
+    });
+}
+
+pub unsafe extern "C" fn on_license_validation_failure(conn_id: connection_id_t, error: c_int) {
+    dispatch("on_license_validation_failure", conn_id, |h| {
+        h.on_license_validation_failure(conn_id, error)
+    });
 }
 
-pub extern "C" fn on_target_bitrate_changed(conn_id: u32, target_bps: u32) {
-    info!(
-        "target_bitrate_changed, conn_id: {}, target_bps: {}",
-        conn_id, target_bps
-    );
+fn dispatch_rtm<F>(name: &str, f: F)
+where
+    F: FnOnce(&RtmShared),
+{
+    let _scope = CallbackScope::enter();
+    let shared = match rtm_shared() {
+        Some(s) => s,
+        None => {
+            error!("{} without RTM client", name);
+            return;
+        }
+    };
+    if catch_unwind(AssertUnwindSafe(|| f(&shared))).is_err() {
+        error!("panic in rtm handler {}", name);
+    }
 }
 
-pub extern "C" fn on_key_frame_gen_req(conn_id: u32, uid: u32, stream_type: u32) {
-    info!(
-        "key_frame_gen_req, conn_id: {}, uid: {}, video_stream_type_e: {}",
-        conn_id, uid, stream_type
-    );
+pub unsafe extern "C" fn on_rtm_data(rtm_uid: *const c_char, msg: *const c_void, msg_len: size_t) {
+    dispatch_rtm("on_rtm_data", |s| {
+        s.on_data(str_or_empty(rtm_uid), slice_or_empty(msg, msg_len))
+    });
 }
 
-pub extern "C" fn on_token_privilege_will_expire(conn_id: u32, token: *const u8) {
-    let t = unsafe { CStr::from_ptr(token) }.to_str();
-    info!(
-        "token_privilege_will_expire, conn_id: {}, The token will expire: {:?}",
-        conn_id, t
-    );
+pub unsafe extern "C" fn on_rtm_event(
+    rtm_uid: *const c_char,
+    event_type: rtm_event_type_e,
+    err_code: rtm_err_code_e,
+) {
+    dispatch_rtm("on_rtm_event", |s| {
+        s.on_event(str_or_empty(rtm_uid), event_type, err_code)
+    });
 }
 
-pub extern "C" fn on_license_validation_failure(conn_id: connection_id_t, error: i32) {
-    let reason = err_2_reason(error);
-    error!(
-        "license_validation_failure, conn_id: {}, error: {}, reason: {:?}",
-        conn_id, error, reason
-    );
+pub unsafe extern "C" fn on_send_rtm_data_result(msg_id: u32, error_code: rtm_err_code_e) {
+    dispatch_rtm("on_send_rtm_data_result", |s| {
+        s.on_send_result(msg_id, error_code)
+    });
 }
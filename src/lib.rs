@@ -2,9 +2,8 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
-/// This module contains the generated bindings from `bindgen`.
-/// Just copy and paste from `bindings.rs` to here.
-pub mod ffi;
+/// The raw bindings, from `agora-rtsa-sys`.
+pub use agora_rtsa_sys as ffi;
 
 // https://stackoverflow.com/questions/24145823/how-do-i-convert-a-c-string-into-a-rust-string-and-back-via-ffi
 // https://doc.rust-lang.org/reference/items/extern-crates.htm
@@ -14,7 +13,8 @@ mod callbacks;
 mod utils;
 pub mod agoraRTC;
 
-#[cfg(test)]
+// against the real SDK
+#[cfg(all(test, feature = "ffi"))]
 mod tests {
     use super::*;
     #[test]
@@ -1,6 +1,6 @@
-use super::ffi::*;
-use std::ffi::{CString, CStr};
-pub type ErrorCode = i32;
+use super::agoraRTC::backend::default_backend;
+use super::agoraRTC::error::AgoraError;
+use std::ffi::CString;
 
 pub trait ToCString {
     fn to_c_string(&self) -> Result<CString, std::ffi::NulError>;
@@ -18,16 +18,29 @@ impl ToCString for String {
 }
 
 
-pub fn err_2_result(code: ErrorCode) -> Result<(), ErrorCode> {
+pub fn err_2_result(code: i32) -> Result<(), AgoraError> {
     match code {
         0 => Result::Ok(()),
-        _ => Result::Err(code),
+        _ => Result::Err(AgoraError::from_code(code)),
     }
 }
 
-pub fn err_2_reason(code: ErrorCode) -> String {
-    unsafe {
-        let pReason = agora_rtc_err_2_str(code);
-        CStr::from_ptr(pReason).to_str().unwrap().to_owned()
+pub fn err_2_reason(code: i32) -> String {
+    default_backend().err_2_str(code)
+}
+
+/// Copy `s` with its NUL into a fixed size C `char` array like `product_id[64]`.
+pub fn copy_to_c_array<const N: usize>(s: &CString) -> Result<[std::os::raw::c_char; N], AgoraError> {
+    let bytes = s.as_bytes_with_nul();
+    if bytes.len() > N {
+        return Err(AgoraError::BufferTooLarge {
+            len: bytes.len() - 1,
+            max: N - 1,
+        });
+    }
+    let mut arr = [0; N];
+    for (dst, src) in arr.iter_mut().zip(bytes) {
+        *dst = *src as std::os::raw::c_char;
     }
+    Ok(arr)
 }